@@ -0,0 +1,277 @@
+use std::{rc::Rc, cell::RefCell};
+use crate::{state::{State, msr}, memory::{MmioDevice, PAGE_SIZE}};
+
+pub const APIC_BASE: u64 = 0xFEE0_0000;
+pub const IOAPIC_BASE: u64 = 0xFEC0_0000;
+
+const TIMER: usize = 0; // LVT entries: timer, thermal, performance counter, LINT0, LINT1, error
+const LVT_MASKED: u32 = 1 << 16;
+const TIMER_PERIODIC: u32 = 0b01 << 17;
+const TIMER_TSC_DEADLINE: u32 = 0b10 << 17;
+const SVR_ENABLE: u32 = 1 << 8;
+
+fn highest(bits: &[u32; 8]) -> Option<u8> {
+	bits.iter().enumerate().rev().find(|(_, &word)| word != 0).map(|(index, word)| (index * 32 + 31 - word.leading_zeros() as usize) as u8)
+}
+fn set(bits: &mut [u32; 8], vector: u8, value: bool) {
+	let (index, bit) = ((vector / 32) as usize, 1 << (vector % 32));
+	if value { bits[index] |= bit; } else { bits[index] &= !bit; }
+}
+fn get(bits: &[u32; 8], vector: u8) -> bool { bits[(vector / 32) as usize] & (1 << (vector % 32)) != 0 }
+
+// xAPIC. The timer counts bus clocks at the virtual cycle rate
+pub struct LocalApic {
+	id: u8,
+	pub tpr: u8,
+	ldr: u32,
+	dfr: u32,
+	svr: u32,
+	isr: [u32; 8],
+	tmr: [u32; 8],
+	irr: [u32; 8],
+	esr: u32,
+	icr: u64,
+	lvt: [u32; 6],
+	initial_count: u32,
+	divide: u32,
+	timer_deadline: Option<u64>,
+	pub tsc_deadline: u64,
+	now: u64,
+	tsc_offset: u64,
+	eoi: Option<u8>, // Level triggered EOI to broadcast to the IOAPIC
+}
+
+impl LocalApic {
+	pub fn new(id: u8) -> Self {
+		Self{id, tpr: 0, ldr: 0, dfr: !0, svr: 0xFF, isr: [0; 8], tmr: [0; 8], irr: [0; 8], esr: 0, icr: 0, lvt: [LVT_MASKED; 6],
+			initial_count: 0, divide: 0, timer_deadline: None, tsc_deadline: 0, now: 0, tsc_offset: 0, eoi: None}
+	}
+
+	fn divisor(&self) -> u64 { 1 << ((((self.divide & 0b11) | (self.divide & 0b1000) >> 1) + 1) & 0b111) }
+	fn timer_period(&self) -> u64 { self.initial_count as u64 * self.divisor() }
+
+	fn ppr(&self) -> u8 {
+		let isrv = highest(&self.isr).unwrap_or(0);
+		if self.tpr >> 4 >= isrv >> 4 { self.tpr } else { isrv & 0xF0 }
+	}
+
+	// Fixed interrupt from the timer, an IPI or the IOAPIC
+	pub fn request(&mut self, vector: u8, level_triggered: bool) {
+		set(&mut self.irr, vector, true);
+		set(&mut self.tmr, vector, level_triggered);
+	}
+
+	// Advances the timer to now (cycles)
+	pub fn update(&mut self, now: u64, tsc_offset: u64) {
+		(self.now, self.tsc_offset) = (now, tsc_offset);
+		let timer = self.lvt[TIMER];
+		if timer & TIMER_TSC_DEADLINE != 0 {
+			if self.tsc_deadline != 0 && now.wrapping_add(tsc_offset) >= self.tsc_deadline {
+				self.tsc_deadline = 0;
+				if timer & LVT_MASKED == 0 { self.request(timer as u8, false); }
+			}
+		} else if let Some(deadline) = self.timer_deadline {
+			if now >= deadline {
+				if timer & LVT_MASKED == 0 { self.request(timer as u8, false); }
+				self.timer_deadline = if timer & TIMER_PERIODIC != 0 && self.timer_period() > 0 {
+					let period = self.timer_period();
+					Some(now + period - (now - deadline) % period)
+				} else { None };
+			}
+		}
+	}
+
+	pub fn next_timer_event(&self) -> Option<u64> {
+		if self.lvt[TIMER] & TIMER_TSC_DEADLINE != 0 { if self.tsc_deadline != 0 { Some(self.tsc_deadline.wrapping_sub(self.tsc_offset)) } else { None } }
+		else { self.timer_deadline }
+	}
+
+	// INTA: highest pending vector above the processor priority moves from IRR to ISR
+	pub fn acknowledge(&mut self) -> Option<u8> {
+		if self.svr & SVR_ENABLE == 0 { return None; }
+		let vector = highest(&self.irr)?;
+		if vector & 0xF0 <= self.ppr() & 0xF0 { return None; }
+		set(&mut self.irr, vector, false);
+		set(&mut self.isr, vector, true);
+		Some(vector)
+	}
+
+	pub fn take_eoi(&mut self) -> Option<u8> { self.eoi.take() }
+
+	fn end_of_interrupt(&mut self) {
+		if let Some(vector) = highest(&self.isr) {
+			set(&mut self.isr, vector, false);
+			if get(&self.tmr, vector) { self.eoi = Some(vector); }
+		}
+	}
+
+	fn send_ipi(&mut self) {
+		let (vector, delivery_mode, shorthand) = (self.icr as u8, (self.icr >> 8) & 0b111, (self.icr >> 18) & 0b11);
+		let destination = (self.icr >> 56) as u8;
+		// Single processor: fixed IPIs to self, others are dropped
+		let to_self = match shorthand { 0 => destination == self.id || destination == 0xFF, 1 | 2 => true, _ => false };
+		if delivery_mode == 0 && to_self { self.request(vector, false); }
+	}
+
+	fn read_register(&self, offset: u64) -> u32 {
+		match offset {
+			0x020 => (self.id as u32) << 24,
+			0x030 => 0x14 | (5 << 16), // Version, 6 LVT entries
+			0x080 => self.tpr as u32,
+			0x090 => 0,
+			0x0A0 => self.ppr() as u32,
+			0x0D0 => self.ldr,
+			0x0E0 => self.dfr,
+			0x0F0 => self.svr,
+			0x100..=0x170 => self.isr[((offset - 0x100) / 0x10) as usize],
+			0x180..=0x1F0 => self.tmr[((offset - 0x180) / 0x10) as usize],
+			0x200..=0x270 => self.irr[((offset - 0x200) / 0x10) as usize],
+			0x280 => self.esr,
+			0x300 => (self.icr as u32) & !(1 << 12), // Delivery status: idle
+			0x310 => (self.icr >> 32) as u32,
+			0x320..=0x370 => self.lvt[((offset - 0x320) / 0x10) as usize],
+			0x380 => self.initial_count,
+			0x390 => match self.timer_deadline {
+				Some(deadline) if self.lvt[TIMER] & TIMER_TSC_DEADLINE == 0 => (deadline.saturating_sub(self.now) / self.divisor()) as u32,
+				_ => 0,
+			},
+			0x3E0 => self.divide,
+			_ => 0,
+		}
+	}
+
+	fn write_register(&mut self, offset: u64, value: u32) {
+		match offset {
+			0x020 => self.id = (value >> 24) as u8,
+			0x080 => self.tpr = value as u8,
+			0x0B0 => self.end_of_interrupt(),
+			0x0D0 => self.ldr = value & 0xFF00_0000,
+			0x0E0 => self.dfr = value | 0x0FFF_FFFF,
+			0x0F0 => {
+				self.svr = value & 0x3FF;
+				if value & SVR_ENABLE == 0 { for lvt in self.lvt.iter_mut() { *lvt |= LVT_MASKED; } }
+			}
+			0x280 => self.esr = 0,
+			0x300 => { self.icr = (self.icr & !0xFFFF_FFFF) | value as u64; self.send_ipi(); }
+			0x310 => self.icr = (self.icr & 0xFFFF_FFFF) | (value as u64) << 32,
+			0x320..=0x370 => {
+				let value = if self.svr & SVR_ENABLE == 0 { value | LVT_MASKED } else { value };
+				self.lvt[((offset - 0x320) / 0x10) as usize] = value;
+			}
+			0x380 => {
+				self.initial_count = value;
+				self.timer_deadline = if value != 0 { Some(self.now + self.timer_period()) } else { None };
+			}
+			0x3E0 => self.divide = value & 0b1011,
+			_ => {}
+		}
+	}
+}
+
+impl MmioDevice for LocalApic {
+	fn read(&mut self, offset: u64, _size: usize) -> u64 { self.read_register(offset & !0xF) as u64 }
+	fn write(&mut self, offset: u64, _size: usize, value: u64) { self.write_register(offset & !0xF, value as u32) }
+}
+
+const REDIRECTION_MASKED: u64 = 1 << 16;
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+const REDIRECTION_REMOTE_IRR: u64 = 1 << 14;
+
+// IOAPIC: routes the 24 IRQ lines to the local APIC
+pub struct IoApic {
+	id: u8,
+	select: u8,
+	redirection: [u64; 24],
+	levels: u32,
+}
+
+impl Default for IoApic {
+	fn default() -> Self { Self{id: 0, select: 0, redirection: [REDIRECTION_MASKED; 24], levels: 0} }
+}
+
+impl IoApic {
+	// Edge triggered pins deliver on the rising edge, level triggered pins while asserted and not in service
+	pub fn update(&mut self, levels: u32, apic: &mut LocalApic) {
+		let rising = levels & !self.levels;
+		self.levels = levels;
+		for (pin, entry) in self.redirection.iter_mut().enumerate() {
+			if *entry & REDIRECTION_MASKED != 0 || levels & (1 << pin) == 0 { continue; }
+			if *entry & REDIRECTION_LEVEL != 0 {
+				if *entry & REDIRECTION_REMOTE_IRR == 0 {
+					*entry |= REDIRECTION_REMOTE_IRR;
+					apic.request(*entry as u8, true);
+				}
+			} else if rising & (1 << pin) != 0 {
+				apic.request(*entry as u8, false);
+			}
+		}
+	}
+
+	pub fn end_of_interrupt(&mut self, vector: u8) {
+		for entry in self.redirection.iter_mut() {
+			if *entry as u8 == vector { *entry &= !REDIRECTION_REMOTE_IRR; }
+		}
+	}
+}
+
+impl MmioDevice for IoApic {
+	fn read(&mut self, offset: u64, _size: usize) -> u64 {
+		match offset {
+			0x00 => self.select as u64,
+			0x10 => match self.select {
+				0x00 => (self.id as u64) << 24,
+				0x01 => 0x11 | (23 << 16), // Version, 24 entries
+				0x02 => 0,
+				index @ 0x10..=0x3F => {
+					let entry = self.redirection[(index as usize - 0x10) / 2];
+					if index % 2 == 0 { entry & 0xFFFF_FFFF } else { entry >> 32 }
+				}
+				_ => 0,
+			},
+			_ => 0,
+		}
+	}
+
+	fn write(&mut self, offset: u64, _size: usize, value: u64) {
+		match offset {
+			0x00 => self.select = value as u8,
+			0x10 => match self.select {
+				0x00 => self.id = (value >> 24) as u8 & 0xF,
+				index @ 0x10..=0x3F => {
+					let entry = &mut self.redirection[(index as usize - 0x10) / 2];
+					*entry = if index % 2 == 0 {
+						(*entry & !0xFFFF_FFFF) | (value & 0xFFFF_FFFF & !(REDIRECTION_REMOTE_IRR | 1 << 12)) | (*entry & REDIRECTION_REMOTE_IRR)
+					} else {
+						(*entry & 0xFFFF_FFFF) | (value & 0xFFFF_FFFF) << 32
+					};
+				}
+				_ => {}
+			},
+			_ => {}
+		}
+	}
+}
+
+impl State {
+	pub fn enable_apic(&mut self) {
+		let apic = Rc::new(RefCell::new(LocalApic::new(0)));
+		let ioapic = Rc::new(RefCell::new(IoApic::default()));
+		self.memory.register_mmio(APIC_BASE..APIC_BASE+PAGE_SIZE, Box::new(apic.clone()));
+		self.memory.register_mmio(IOAPIC_BASE..IOAPIC_BASE+0x20, Box::new(ioapic.clone()));
+		self.msr.insert(msr::APIC_BASE, APIC_BASE | 1 << 11 | 1 << 8); // Enabled, bootstrap processor
+		self.apic = Some(apic);
+		self.ioapic = Some(ioapic);
+	}
+
+	// Samples the IRQ lines and the timer. Returns the vector accepted by the CPU if interrupts are enabled
+	pub fn update_apic(&mut self, interrupts_enabled: bool) -> Option<u8> {
+		let mut apic = self.apic.as_ref()?.borrow_mut();
+		if let Some(ioapic) = &self.ioapic {
+			let mut ioapic = ioapic.borrow_mut();
+			if let Some(vector) = apic.take_eoi() { ioapic.end_of_interrupt(vector); }
+			ioapic.update(self.irq.levels(), &mut apic);
+		}
+		apic.update(self.cycles, self.tsc_offset);
+		if interrupts_enabled { apic.acknowledge() } else { None }
+	}
+}
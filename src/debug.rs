@@ -0,0 +1,85 @@
+use std::{fmt, ops::Range};
+use crate::{state::State, memory::{Access, WatchHit}, instruction::Flags, interrupt::vector::DEBUG};
+
+// Why execute returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+	Exited, // Returned to !0 or exit syscall
+	Halted, // hlt without any timer event to wake up
+	InstructionLimit,
+	TripleFault, // A fault while delivering a double fault
+	Breakpoint{address: u64}, // Before executing the instruction at address
+	Watchpoint{address: u64, access: Access, value: u64}, // After the accessing instruction
+}
+
+impl From<WatchHit> for StopReason {
+	fn from(WatchHit{address, access, value}: WatchHit) -> Self { StopReason::Watchpoint{address, access, value} }
+}
+
+impl fmt::Display for StopReason {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			StopReason::Exited => write!(f, "Exited"),
+			StopReason::Halted => write!(f, "Halted"),
+			StopReason::InstructionLimit => write!(f, "Instruction limit reached"),
+			StopReason::TripleFault => write!(f, "Triple fault"),
+			StopReason::Breakpoint{address} => write!(f, "Breakpoint at {:x}", address),
+			StopReason::Watchpoint{address, access, value} => write!(f, "Watchpoint {:?} of {:x} at {:x}", access, value, address),
+		}
+	}
+}
+
+impl State {
+	pub fn add_breakpoint(&mut self, address: u64) { self.breakpoints.insert(address); }
+	pub fn remove_breakpoint(&mut self, address: u64) -> bool { self.breakpoints.remove(&address) }
+	// Returns the address of the function, None without symbols for it
+	pub fn add_symbol_breakpoint(&mut self, name: &str) -> Option<u64> {
+		let address = self.symbols.as_ref()?.symbol(name)?;
+		self.add_breakpoint(address);
+		Some(address)
+	}
+
+	pub fn add_watchpoint(&mut self, range: Range<u64>, access: Access) { self.memory.add_watchpoint(range, access); }
+	pub fn remove_watchpoint(&mut self, range: Range<u64>, access: Access) -> bool { self.memory.remove_watchpoint(range, access) }
+
+	// DR7 enables DRn with bits 2n and 2n+1 and sets its R/W and LEN at bit 16+4n. None access is an instruction breakpoint
+	fn debug_breakpoint(&self, index: usize) -> Option<(Range<u64>, Option<Access>)> {
+		if (self.dr7 >> (2 * index)) & 0b11 == 0 { return None; }
+		let control = self.dr7 >> (16 + 4 * index);
+		let length = match (control >> 2) & 0b11 { 0 => 1, 1 => 2, 2 => 8, _ => 4 };
+		let access = match control & 0b11 {
+			0 => None,
+			1 => Some(Access::Write),
+			3 => Some(Access::ReadWrite),
+			_ => return None, // I/O breakpoints
+		};
+		let address = self.dr[index] & !(length - 1);
+		Some((address..address+length, access))
+	}
+
+	pub(crate) fn update_debug_registers(&mut self) {
+		let mut watchpoints = [None, None, None, None];
+		for (index, watchpoint) in watchpoints.iter_mut().enumerate() {
+			if let Some((range, Some(access))) = self.debug_breakpoint(index) { *watchpoint = Some((range, access)); }
+		}
+		self.memory.set_debug_watchpoints(watchpoints);
+	}
+
+	// Instruction breakpoints fault before the instruction unless RFLAGS.RF is set. Returns whether #DB was raised
+	pub(crate) fn debug_instruction_breakpoint(&mut self, address: u64) -> bool {
+		if self.dr7 & 0xFF == 0 || self.get_flag(Flags::Resume) { return false; }
+		let hits = (0..4).filter(|&index| matches!(self.debug_breakpoint(index), Some((range, None)) if range.start == address)).fold(0, |hits, index| hits | 1 << index);
+		if hits == 0 { return false; }
+		self.dr6 |= hits;
+		self.fault(DEBUG, None);
+		true
+	}
+
+	// Data breakpoints trap after the instruction
+	pub(crate) fn debug_data_breakpoints(&mut self) {
+		let hits = self.memory.take_debug_hits();
+		if hits == 0 { return; }
+		self.dr6 |= hits as u64;
+		self.raise_exception(DEBUG, None);
+	}
+}
@@ -1,5 +1,5 @@
 use bitflags::bitflags;
-use crate::{memory::Memory, instruction::{Register, RegisterSize, OperandSize, Opcode, Repeat, Operand, Operands}};
+use crate::{memory::Memory, segment::Mode, instruction::{Register, RegisterSize, OperandSize, Opcode, Repeat, Operand, Operands}};
 
 #[derive(PartialEq)] enum RegOrOpcode { Register, Opcode, }
 #[derive(PartialEq)] enum ImmediateSize { None, Bit8, Bit32, }
@@ -14,7 +14,7 @@ bitflags! {
 bitflags! {
 	struct Flags: u64 {
 		const REVERSED_REGISTER_DIRECTION = 1 /*<< 0*/;
-		const ADDRESS_SIZE_OVERRIDE = 1 << 2;
+		const ADDRESS_SIZE_OVERRIDE = 1 << 2; // 32bit addressing
 		const NEW_64BIT_REGISTER = 1 << 5;
 		const NEW_8BIT_REGISTER = 1 << 6;
 		const MOD_R_M_EXTENSION = 1 << 7;
@@ -24,10 +24,18 @@ bitflags! {
 		const SIB_DISPLACEMENT_ONLY = 1 << 11;
 		const OP1_XMM = 1 << 12;
 		const OP2_XMM = 1 << 13;
+		const SEGMENT_FS = 1 << 14;
+		const SEGMENT_GS = 1 << 15;
+		const SEGMENT_ES = 1 << 16;
+		const SEGMENT_CS = 1 << 17;
+		const SEGMENT_SS = 1 << 18;
+		const SEGMENT_DS = 1 << 19;
+		const ADDRESS_16_BIT = 1 << 20;
+		const LEGACY = 1 << 21; // 16/32bit code: no REX, no RIP relative addressing
 	}
 }
 
-pub fn decode(rip : &mut i64, memory : &Memory) -> (Opcode, Operands) {
+pub fn decode(rip : &mut i64, memory : &Memory, mode: Mode) -> (Opcode, Operands) {
 	let mut flags = Flags { bits: 0 };
 	let mut repeat = Repeat::None;
 	loop {
@@ -35,10 +43,16 @@ pub fn decode(rip : &mut i64, memory : &Memory) -> (Opcode, Operands) {
 			0xF0 => { /* todo: do not ignore lock/bound prefix */ }
 			0xF2 => { repeat = Repeat::NotEqual }
 			0xF3 => { repeat = Repeat::Equal; }
-			0x2E | 0x3E | 0x36 | 0x26 | 0x64 | 0x65 => { /* TODO: do not ignore segment prefix (or probably we should?) */ }
+			0x2E | 0x3E | 0x36 | 0x26 if mode == Mode::Long64 => { /* Null segment prefixes in 64bit mode */ }
+			0x26 => { flags |= Flags::SEGMENT_ES; }
+			0x2E => { flags |= Flags::SEGMENT_CS; }
+			0x36 => { flags |= Flags::SEGMENT_SS; }
+			0x3E => { flags |= Flags::SEGMENT_DS; }
+			0x64 => { flags |= Flags::SEGMENT_FS; }
+			0x65 => { flags |= Flags::SEGMENT_GS; }
 			0x66 => { flags |= Flags::OPERAND_16_BIT; }
 			0x67 => { flags |= Flags::ADDRESS_SIZE_OVERRIDE; }
-			bits @ 0x40..=0x4F => { // 64bit REX prefix
+			bits @ 0x40..=0x4F if mode == Mode::Long64 => { // 64bit REX prefix
 				let rex = REX{bits};
 				if rex.contains(REX::B) { flags |= Flags::NEW_64BIT_REGISTER; }
 				if rex.contains(REX::R) { flags |= Flags::MOD_R_M_EXTENSION; }
@@ -50,6 +64,17 @@ pub fn decode(rip : &mut i64, memory : &Memory) -> (Opcode, Operands) {
 		}
 		*rip += 1;
 	}
+	if mode != Mode::Long64 {
+		flags |= Flags::LEGACY;
+		// 66/67 prefixes select the other size: 16bit in 32bit code, 32bit in 16bit code
+		if mode.default_16bit() { flags.toggle(Flags::OPERAND_16_BIT); }
+		if mode.default_16bit() != flags.contains(Flags::ADDRESS_SIZE_OVERRIDE) {
+			flags.remove(Flags::ADDRESS_SIZE_OVERRIDE);
+			flags |= Flags::ADDRESS_16_BIT;
+		} else {
+			flags |= Flags::ADDRESS_SIZE_OVERRIDE;
+		}
+	}
 
 	let register_size = if flags.contains(Flags::OPERAND_64_BIT) {
 			RegisterSize::Bit64
@@ -59,6 +84,10 @@ pub fn decode(rip : &mut i64, memory : &Memory) -> (Opcode, Operands) {
 			RegisterSize::Bit32
 	};
 
+	// push, pop, call, ret: 64bit in 64bit mode
+	let stack_size = if mode == Mode::Long64 { None } else { Some(if flags.contains(Flags::OPERAND_16_BIT) { OperandSize::Bit16 } else { OperandSize::Bit32 }) };
+	let stack_register_size = if mode == Mode::Long64 { RegisterSize::Bit64 } else { register_size };
+
 	macro_rules! Opcode { ($($op:ident)+) => ( [$(Opcode::$op),+] ) }
 	let jcc = Opcode!(Jo Jno Jb Jae Je Jne Jbe Ja Js Jns Jp Jnp Jl Jge Jle Jg);
 	let scc = Opcode!(Seto Setno Setb Setae Sete Setne Setbe Seta Sets Setns Setp Setnp Setl Setge Setle Setg);
@@ -255,16 +284,74 @@ pub fn decode(rip : &mut i64, memory : &Memory) -> (Opcode, Operands) {
 					let op = decode_ax_immediate(memory, rip, register_size, flags);
 					(Opcode::Cmp, op)
 			}
+			0x06 | 0x07 | 0x0E | 0x16 | 0x17 | 0x1E | 0x1F | 0x27 | 0x2F | 0x37 | 0x3F | 0x60 | 0x61 | 0x62 | 0x9A | 0xCE | 0xD4 | 0xD5 | 0xEA if mode == Mode::Long64 => {
+					// Invalid in 64bit mode
+					*rip += 1;
+					(Opcode::Ud2, Operands::default())
+			}
+			opcode @ 0x27 | opcode @ 0x2F | opcode @ 0x37 | opcode @ 0x3F => {
+					*rip += 1;
+					(match opcode { 0x27 => Opcode::Daa, 0x2F => Opcode::Das, 0x37 => Opcode::Aaa, _ => Opcode::Aas }, Operands::default())
+			}
+			opcode @ 0xD4 | opcode @ 0xD5 => {
+					let base = memory.get_u8(*rip, 1);
+					*rip += 2;
+					(if opcode == 0xD4 { Opcode::Aam } else { Opcode::Aad }, Operands{ operands: [Some(Operand::Immediate(base as i64)), None, None], explicit_size: Some(OperandSize::Bit8), ..Default::default() })
+			}
+			0xCE => {
+					*rip += 1;
+					(Opcode::Into, Operands::default())
+			}
+			0x62 => {
+					let op = decode_reg_reg(memory, rip, register_size, flags | Flags::REVERSED_REGISTER_DIRECTION);
+					(Opcode::Bound, op)
+			}
+			opcode @ 0x06 | opcode @ 0x0E | opcode @ 0x16 | opcode @ 0x1E => {
+					*rip += 1;
+					(Opcode::Push, Operands{ operands: [Some(Operand::Register(get_register(opcode >> 3, RegisterSize::Segment, false, false))), None, None],
+																																						explicit_size: stack_size, ..Default::default() })
+			}
+			opcode @ 0x07 | opcode @ 0x17 | opcode @ 0x1F => {
+					*rip += 1;
+					(Opcode::Pop, Operands{ operands: [Some(Operand::Register(get_register(opcode >> 3, RegisterSize::Segment, false, false))), None, None],
+																																						explicit_size: stack_size, ..Default::default() })
+			}
+			opcode @ 0x40..=0x4F => {
+					// inc/dec r16/r32 (REX prefix in 64bit mode)
+					*rip += 1;
+					(Opcode::RegisterOperation, Operands{ operands: [Some(Operand::Register(get_register(opcode & 0b111, register_size, false, false))), None, None],
+																																						opcode: Some((opcode >> 3) & 1), ..Default::default() })
+			}
+			0x60 => {
+					*rip += 1;
+					(Opcode::Pusha, Operands{ explicit_size: stack_size, ..Default::default() })
+			}
+			0x61 => {
+					*rip += 1;
+					(Opcode::Popa, Operands{ explicit_size: stack_size, ..Default::default() })
+			}
+			opcode @ 0x9A | opcode @ 0xEA => {
+					// call/jmp far ptr16:16, ptr16:32
+					let (offset, length) = if flags.contains(Flags::OPERAND_16_BIT) {
+							(memory.get_i16(*rip, 1) as u16 as i64, 2)
+					} else {
+							(memory.get_i32(*rip, 1) as u32 as i64, 4)
+					};
+					let selector = memory.get_i16(*rip, 1 + length) as u16 as i64;
+					*rip += 1 + length + 2;
+					(Opcode::RegisterOperation, Operands{ operands: [Some(Operand::Immediate(selector)), Some(Operand::Immediate(offset)), None],
+																																						opcode: Some(if opcode == 0x9A { 3 } else { 5 }), explicit_size: stack_size, ..Default::default() })
+			}
 			opcode @ 0x50..=0x57 => {
 					*rip += 1;
-					(Opcode::Push, Operands{ operands: [Some(Operand::Register(get_register(opcode - 0x50, RegisterSize::Bit64,
+					(Opcode::Push, Operands{ operands: [Some(Operand::Register(get_register(opcode - 0x50, stack_register_size,
 																																																																													flags.contains(Flags::NEW_64BIT_REGISTER),
 																																																																													flags.contains(Flags::NEW_8BIT_REGISTER)) )),
 																																						None, None], ..Default::default() })
 			}
 			opcode @ 0x58..=0x5F => {
 					*rip += 1;
-					(Opcode::Pop, Operands{ operands: [Some(Operand::Register(get_register(opcode - 0x58, RegisterSize::Bit64,
+					(Opcode::Pop, Operands{ operands: [Some(Operand::Register(get_register(opcode - 0x58, stack_register_size,
 																																																																													flags.contains(Flags::NEW_64BIT_REGISTER),
 																																																																													flags.contains(Flags::NEW_8BIT_REGISTER)))),
 																																						None, None], ..Default::default() })
@@ -288,7 +375,7 @@ pub fn decode(rip : &mut i64, memory : &Memory) -> (Opcode, Operands) {
 							*rip += 5;
 							immediate
 					};
-					(Opcode::Push, Operands{ operands: [Some(Operand::Immediate(immediate)), None, None], ..Default::default() })
+					(Opcode::Push, Operands{ operands: [Some(Operand::Immediate(immediate)), None, None], explicit_size: stack_size, ..Default::default() })
 			}
 			0x69 => {
 					let (mut op, ip_offset) = get_operands(&memory, *rip, register_size, RegOrOpcode::Register, ImmediateSize::None,
@@ -307,7 +394,18 @@ pub fn decode(rip : &mut i64, memory : &Memory) -> (Opcode, Operands) {
 					op.operands = [Some(Operand::Immediate(immediate)), op0, op1];
 					(Opcode::Imul, op)
 			}
-			0x6A => (Opcode::Push, read_immediate_8bit(memory, rip)),
+			opcode @ 0x6C..=0x6F => {
+					// ins (%dx),%es:(%rdi) / outs %ds:(%rsi),(%dx)
+					*rip += 1;
+					let operand_size = if opcode & 1 == 0 { OperandSize::Bit8 } else if flags.contains(Flags::OPERAND_16_BIT) { OperandSize::Bit16 } else { OperandSize::Bit32 };
+					let port = Some(Operand::Register(Register::DX));
+					if opcode < 0x6E {
+							(Opcode::Ins, Operands{ operands: [port, Some(string_destination(flags)), None], explicit_size: Some(operand_size), repeat, ..Default::default() })
+					} else {
+							(Opcode::Outs, Operands{ operands: [Some(string_source(flags)), port, None], explicit_size: Some(operand_size), repeat, ..Default::default() })
+					}
+			}
+			0x6A => (Opcode::Push, Operands{ explicit_size: stack_size, ..read_immediate_8bit(memory, rip) }),
 			0x6B => {
 					let (mut op, ip_offset) = get_operands(memory, *rip, register_size,
 																															RegOrOpcode::Register,
@@ -428,14 +526,15 @@ pub fn decode(rip : &mut i64, memory : &Memory) -> (Opcode, Operands) {
 					*rip += ip_offset;
 					(Opcode::Lea, op)
 			}
-			0x8E => {
-					// mov 16bit segment registers
-					let (op, ip_offset) =
-							get_operands(&memory, *rip, RegisterSize::Segment,
+			opcode @ 0x8C | opcode @ 0x8E => {
+					// mov 16bit segment registers (8C: mov Sreg,r/m, 8E: mov r/m16,Sreg)
+					let (mut op, ip_offset) =
+							get_operands(&memory, *rip, if opcode == 0x8E { RegisterSize::Bit16 } else { register_size },
 																	RegOrOpcode::Register,
 																	ImmediateSize::None,
-																	// TODO: REVERSED_REGISTER_DIRECTION correct?
-																	flags | Flags::REVERSED_REGISTER_DIRECTION);
+																	if opcode == 0x8E { flags | Flags::REVERSED_REGISTER_DIRECTION } else { flags });
+					let segment = Some(Operand::Register(get_register((memory.get_u8(*rip, 1) & 0b00111000) >> 3, RegisterSize::Segment, false, false)));
+					if opcode == 0x8E { op.operands[1] = segment; } else { op.operands[0] = segment; }
 					*rip += ip_offset;
 					(Opcode::Mov, op)
 			}
@@ -446,6 +545,7 @@ pub fn decode(rip : &mut i64, memory : &Memory) -> (Opcode, Operands) {
 																															flags |
 																															Flags::REVERSED_REGISTER_DIRECTION);
 					op.operands[1] = None;
+					if stack_size.is_some() { op.explicit_size = stack_size; }
 					*rip += ip_offset;
 					(Opcode::Pop, op)
 			}
@@ -484,15 +584,15 @@ pub fn decode(rip : &mut i64, memory : &Memory) -> (Opcode, Operands) {
 			}
 			0x9C => {
 					*rip += 1;
-					(Opcode::Pushf, Operands::default())
+					(Opcode::Pushf, Operands{ explicit_size: stack_size, ..Default::default() })
 			}
 			0x9D => {
 					*rip += 1;
-					(Opcode::Popf, Operands::default())
+					(Opcode::Popf, Operands{ explicit_size: stack_size, ..Default::default() })
 			}
 			0xA4 => {
 					*rip += 1;
-					(Opcode::Movs, Operands{ repeat, explicit_size: Some(OperandSize::Bit8), ..Default::default()})
+					(Opcode::Movs, Operands{ operands: [Some(string_source(flags)), Some(string_destination(flags)), None], repeat, explicit_size: Some(OperandSize::Bit8), ..Default::default()})
 			}
 			0xA5 => {
 					let operand_size = if flags.contains(Flags::OPERAND_16_BIT) {
@@ -503,7 +603,7 @@ pub fn decode(rip : &mut i64, memory : &Memory) -> (Opcode, Operands) {
 							OperandSize::Bit32
 					};
 					*rip += 1;
-					(Opcode::Movs, Operands{ repeat, explicit_size: Some(operand_size), ..Default::default() })
+					(Opcode::Movs, Operands{ operands: [Some(string_source(flags)), Some(string_destination(flags)), None], repeat, explicit_size: Some(operand_size), ..Default::default() })
 			}
 			0xA8 => {
 					let op = decode_al_immediate(memory, rip);
@@ -515,7 +615,7 @@ pub fn decode(rip : &mut i64, memory : &Memory) -> (Opcode, Operands) {
 			}
 			0xAA => {
 					*rip += 1;
-					(Opcode::Stos, Operands{ repeat, explicit_size: Some(OperandSize::Bit8), ..Default::default() })
+					(Opcode::Stos, Operands{ operands: [Some(Operand::Register(Register::AL)), Some(string_destination(flags)), None], repeat, explicit_size: Some(OperandSize::Bit8), ..Default::default() })
 			}
 			0xAB => {
 					*rip += 1;
@@ -526,13 +626,16 @@ pub fn decode(rip : &mut i64, memory : &Memory) -> (Opcode, Operands) {
 							RegisterSize::Bit64 => OperandSize::Bit64,
 							_ => panic!("Unsupported register size"),
 					};
-					(Opcode::Stos, Operands{ repeat, explicit_size: Some(operand_size), ..Default::default() })
+					(Opcode::Stos, Operands{ operands: [Some(Operand::Register(get_register(0, register_size, false, false))), Some(string_destination(flags)), None],
+																																						repeat, explicit_size: Some(operand_size), ..Default::default() })
 			}
 			0xAE => {
 					*rip += 1;
-					(Opcode::Scas, Operands{ operands: [Some(Operand::EffectiveAddress{ base: Some(Register::RDI), index: None, scale: None, displacement: 0 }),
-																																						Some(Operand::Register(Register::AL)),
-																																						None], repeat, ..Default::default() })
+					(Opcode::Scas, Operands{ operands: [Some(string_destination(flags)), Some(Operand::Register(Register::AL)), None], repeat, ..Default::default() })
+			}
+			0xAF => {
+					*rip += 1;
+					(Opcode::Scas, Operands{ operands: [Some(string_destination(flags)), Some(Operand::Register(get_register(0, register_size, false, false))), None], repeat, ..Default::default() })
 			}
 			opcode @ 0xB0..=0xB7 => {
 					let immediate = memory.get_u8(*rip, 1) as i64;
@@ -591,14 +694,15 @@ pub fn decode(rip : &mut i64, memory : &Memory) -> (Opcode, Operands) {
 					(Opcode::ShiftRotate, op)
 			}
 			0xC3 => {
-					(Opcode::Ret, Operands::default())
+					(Opcode::Ret, Operands{ explicit_size: stack_size, ..Default::default() })
 			}
 			0xC9 => {
 					*rip += 1;
-					(Opcode::Leave, Operands::default())
+					(Opcode::Leave, Operands{ explicit_size: stack_size, ..Default::default() })
 			}
 			0xCB => {
-					(Opcode::Lret, Operands::default())
+					let operand_size = if flags.contains(Flags::OPERAND_64_BIT) { OperandSize::Bit64 } else if flags.contains(Flags::OPERAND_16_BIT) { OperandSize::Bit16 } else { OperandSize::Bit32 };
+					(Opcode::Lret, Operands{ explicit_size: Some(operand_size), ..Default::default() })
 			}
 			0xD1 => {
 					let (mut op, ip_offset) = get_operands(&memory, *rip, register_size,
@@ -633,19 +737,25 @@ pub fn decode(rip : &mut i64, memory : &Memory) -> (Opcode, Operands) {
 					(Opcode::ShiftRotate, op)
 			}
 			0xEB => { (Opcode::Jmp, read_immediate_8bit(memory, rip)) }
-			0xE8 => {
-					let immediate = memory.get_i32(*rip, 1);
-					*rip += 5;
-					(Opcode::Call, Operands{ operands: [Some(Operand::Immediate(immediate as i64)), None, None], ..Default::default() } )
-			}
-			0xE9 => {
-					let immediate = memory.get_i32(*rip, 1);
-					*rip += 5;
-					(Opcode::Jmp, Operands{ operands: [Some(Operand::Immediate(immediate as i64)), None, None], ..Default::default() } )
-			}
-			0xEE => {
-					*rip += 1;
-					(Opcode::Out, Operands::default())
+			0xE8 => (Opcode::Call, Operands{ explicit_size: stack_size, ..read_relative(memory, rip, flags) }),
+			0xE9 => (Opcode::Jmp, read_relative(memory, rip, flags)),
+			opcode @ 0xE4..=0xE7 | opcode @ 0xEC..=0xEF => {
+					// in/out al/ax/eax, imm8 or dx
+					let register_size = if opcode & 1 == 0 { RegisterSize::Bit8 } else if flags.contains(Flags::OPERAND_16_BIT) { RegisterSize::Bit16 } else { RegisterSize::Bit32 };
+					let register = Some(Operand::Register(get_register(0, register_size, false, false)));
+					let port = if opcode < 0xE8 {
+							*rip += 2;
+							Some(Operand::Immediate(memory.get_u8(*rip, -1) as i64))
+					} else {
+							*rip += 1;
+							Some(Operand::Register(Register::DX))
+					};
+					let explicit_size = Some(match register_size { RegisterSize::Bit8 => OperandSize::Bit8, RegisterSize::Bit16 => OperandSize::Bit16, _ => OperandSize::Bit32 });
+					if opcode & 0b10 == 0 {
+							(Opcode::In, Operands{ operands: [port, register, None], explicit_size, ..Default::default() })
+					} else {
+							(Opcode::Out, Operands{ operands: [register, port, None], explicit_size, ..Default::default() })
+					}
 			}
 			0xF6 => {
 					let modrm = memory.get_u8(*rip, 1);
@@ -709,15 +819,17 @@ pub fn decode(rip : &mut i64, memory : &Memory) -> (Opcode, Operands) {
 					*rip += ip_offset;
 					(Opcode::CompareMulOperation, op)
 			}
+			0xF4 => {
+					*rip += 1;
+					(Opcode::Hlt, Operands::default())
+			}
 			0xFA => {
-					// todo: implement cli instruction
 					*rip += 1;
-					(Opcode::Nop, Operands::default())
+					(Opcode::Cli, Operands::default())
 			}
 			0xFB => {
-					// todo: implement sti instruction
 					*rip += 1;
-					(Opcode::Nop, Operands::default())
+					(Opcode::Sti, Operands::default())
 			}
 			0xFC => {
 					*rip += 1;
@@ -740,11 +852,15 @@ pub fn decode(rip : &mut i64, memory : &Memory) -> (Opcode, Operands) {
 					// todo: cleanup code
 					let modrm = memory.get_u8(*rip, 1);
 					let opcode = (modrm & 0b00111000) >> 3;
-					let register_size = if opcode == 2 || opcode == 4 {RegisterSize::Bit64} else {register_size}; // FF /2, 4 (Call/jmp near absolute indirect) implies REX.W
+					let register_size = if opcode == 2 || opcode == 4 {stack_register_size} else {register_size}; // FF /2, 4 (Call/jmp near absolute indirect) implies REX.W
 					let (mut op, ip_offset) =
 							get_operands(&memory, *rip, register_size, RegOrOpcode::Register, ImmediateSize::None, flags | Flags::REVERSED_REGISTER_DIRECTION);
 					op.operands[1] = None;
 					op.opcode = Some(opcode);
+					if (opcode == 2 || opcode == 4 || opcode == 6) && stack_size.is_some() { op.explicit_size = stack_size; }
+					if opcode == 3 || opcode == 5 { // FF /3, 5 (Call/jmp far absolute indirect) m16:16/32/64
+							op.explicit_size = Some(if flags.contains(Flags::OPERAND_64_BIT) { OperandSize::Bit64 } else if flags.contains(Flags::OPERAND_16_BIT) { OperandSize::Bit16 } else { OperandSize::Bit32 });
+					}
 					*rip += ip_offset;
 					(Opcode::RegisterOperation, op)
 			}
@@ -752,24 +868,49 @@ pub fn decode(rip : &mut i64, memory : &Memory) -> (Opcode, Operands) {
 					// two byte instructions
 					*rip += 1;
 					match memory.get_u8(*rip, 0) {
+							0x00 => {
+									let modrm = memory.get_u8(*rip, 1);
+									let opcode = (modrm & 0b00111000) >> 3;
+									match opcode {
+											0..=5 => {
+													let (mut op, ip_offset) = get_operands(&memory, *rip, RegisterSize::Bit16,
+																																			RegOrOpcode::Opcode,
+																																			ImmediateSize::None,
+																																			flags);
+													op.explicit_size = Some(OperandSize::Bit16);
+													*rip += ip_offset;
+													(Opcode!(Sldt Str Lldt Ltr Verr Verw)[opcode as usize], op)
+											},
+											_ => {
+													*rip += 1;
+													(Opcode::Ud2, Operands::default())
+											}
+									}
+							}
 							0x01 => {
 									let modrm = memory.get_u8(*rip, 1);
 									let opcode = (modrm & 0b00111000) >> 3;
 									match opcode {
 											2  | 3 => {
-													let (mut op, ip_offset) = get_operands(&memory, *rip, register_size,
+													let (op, ip_offset) = get_operands(&memory, *rip, register_size,
 																																							RegOrOpcode::Opcode,
-																																							ImmediateSize::Bit32,
-																																							flags | Flags::REVERSED_REGISTER_DIRECTION);
-													let [_, op1, _] = op.operands;
-													op.operands = [op1, None, None];
-													*rip += ip_offset - 4;
+																																							ImmediateSize::None,
+																																							flags);
+													*rip += ip_offset;
 													if opcode == 2 {
 															(Opcode::Lgdt, op)
 													} else {
 															(Opcode::Lidt, op)
 													}
 											},
+											7 if modrm == 0xF8 => {
+													*rip += 2;
+													(Opcode::Swapgs, Operands::default())
+											},
+											7 if modrm == 0xF9 => {
+													*rip += 2;
+													(Opcode::Rdtscp, Operands::default())
+											},
 											_ => panic!("0F 01 unsupported opcode: {:x}", opcode)
 									}
 							}
@@ -777,6 +918,11 @@ pub fn decode(rip : &mut i64, memory : &Memory) -> (Opcode, Operands) {
 									*rip += 1;
 									(Opcode::Syscall, Operands::default())
 							}
+							0x07 => {
+									*rip += 1;
+									let operand_size = if flags.contains(Flags::OPERAND_64_BIT) { OperandSize::Bit64 } else { OperandSize::Bit32 };
+									(Opcode::Sysret, Operands{ explicit_size: Some(operand_size), ..Default::default() })
+							}
 							0x0B => {
 									*rip += 1;
 									(Opcode::Ud2, Operands::default())
@@ -808,50 +954,77 @@ pub fn decode(rip : &mut i64, memory : &Memory) -> (Opcode, Operands) {
 									*rip += ip_offset;
 									(Opcode::Nop, Operands::default())
 							}
-							/*0x20 => {
+							0x20 => {
 									let (mut op, ip_offset) = get_operands(&memory, *rip, RegisterSize::Bit64,
 																																	RegOrOpcode::Register,
 																																	ImmediateSize::None,
 																																	flags);
-									let register = match op.operands[0].unwrap() {
-											Operand::Register(register) => {
+									let register = match op.operands[0] {
+											Some(Operand::Register(register)) => {
 													match register {
 															Register::R8 => Register::CR8,
 															Register::RAX => Register::CR0,
 															Register::RDX => Register::CR2,
 															Register::RBX => Register::CR3,
 															Register::RSP => Register::CR4,
-															_ => panic!("Invalid operand for mov r64, CRn instruciton"),
+															_ => panic!("Invalid operand for mov r64, CRn instruction"),
 													}
 											},
-											_ => panic!("Invalid operand for mov r64, CRn instruciton"),
+											_ => panic!("Invalid operand for mov r64, CRn instruction"),
 									};
 									op.operands[0] = Some(Operand::Register(register));
 									*rip += ip_offset;
-									(Opcode::Mov, op)
+									(Opcode::MovCr, op)
 							},
 							0x22 => {
 									let (mut op, ip_offset) = get_operands(&memory, *rip, RegisterSize::Bit64,
 																																	RegOrOpcode::Register,
 																																	ImmediateSize::None,
 																																	flags | Flags::REVERSED_REGISTER_DIRECTION);
-									let register = match op.operands[1].unwrap() {
-											Operand::Register(register) => {
+									let register = match op.operands[1] {
+											Some(Operand::Register(register)) => {
 													match register {
 															Register::R8 => Register::CR8,
 															Register::RAX => Register::CR0,
 															Register::RDX => Register::CR2,
 															Register::RBX => Register::CR3,
 															Register::RSP => Register::CR4,
-															_ => panic!("Invalid operand for mov r64, CRn instruciton"),
+															_ => panic!("Invalid operand for mov r64, CRn instruction"),
 													}
 											},
-											_ => panic!("Invalid operand for mov r64, CRn instruciton"),
+											_ => panic!("Invalid operand for mov r64, CRn instruction"),
 									};
 									op.operands[1] = Some(Operand::Register(register));
 									*rip += ip_offset;
-									(Opcode::Mov, op)
-							},*/
+									(Opcode::MovCr, op)
+							},
+							// DR4 and DR5 alias DR6 and DR7
+							0x21 => {
+									let (mut op, ip_offset) = get_operands(&memory, *rip, RegisterSize::Bit64,
+																																	RegOrOpcode::Register,
+																																	ImmediateSize::None,
+																																	flags);
+									let register = match op.operands[0] {
+											Some(Operand::Register(register)) => debug_register(register),
+											_ => panic!("Invalid operand for mov r64, DRn instruction"),
+									};
+									op.operands[0] = Some(Operand::Register(register));
+									*rip += ip_offset;
+									(Opcode::MovCr, op)
+							},
+							0x23 => {
+									let (mut op, ip_offset) = get_operands(&memory, *rip, RegisterSize::Bit64,
+																																	RegOrOpcode::Register,
+																																	ImmediateSize::None,
+																																	flags | Flags::REVERSED_REGISTER_DIRECTION);
+									let register = match op.operands[1] {
+											Some(Operand::Register(register)) => debug_register(register),
+											_ => panic!("Invalid operand for mov DRn, r64 instruction"),
+									};
+									op.operands[1] = Some(Operand::Register(register));
+									*rip += ip_offset;
+									(Opcode::MovCr, op)
+							},
 							0x2A => {
 								let (op, ip_offset) = get_operands(&memory, *rip, register_size,
 																																RegOrOpcode::Register,
@@ -872,10 +1045,23 @@ pub fn decode(rip : &mut i64, memory : &Memory) -> (Opcode, Operands) {
 									*rip += 1;
 									(Opcode::Wrmsr, Operands::default())
 							}
+							0x31 => {
+									*rip += 1;
+									(Opcode::Rdtsc, Operands::default())
+							}
 							0x32 => {
 									*rip += 1;
 									(Opcode::Rdmsr, Operands::default())
 							}
+							0x34 => {
+									*rip += 1;
+									(Opcode::Sysenter, Operands::default())
+							}
+							0x35 => {
+									*rip += 1;
+									let operand_size = if flags.contains(Flags::OPERAND_64_BIT) { OperandSize::Bit64 } else { OperandSize::Bit32 };
+									(Opcode::Sysexit, Operands{ explicit_size: Some(operand_size), ..Default::default() })
+							}
 							0x40 => {
 									let (op, ip_offset) = get_operands(&memory, *rip, register_size,
 																															RegOrOpcode::Register,
@@ -1080,12 +1266,7 @@ pub fn decode(rip : &mut i64, memory : &Memory) -> (Opcode, Operands) {
 								*rip += ip_offset;
 								(Opcode::Movd, op)
 							}
-							opcode @ 0x80..=0x8F => {
-									// TODO: could also be 16bit value
-									let immediate = memory.get_i32(*rip, 1) as i64;
-									*rip += 5;
-									(jcc[(opcode-0x80) as usize], Operands{ operands: [Some(Operand::Immediate(immediate)), None, None], ..Default::default() })
-							},
+							opcode @ 0x80..=0x8F => (jcc[(opcode-0x80) as usize], read_relative(memory, rip, flags)),
 							opcode @ 0x90..=0x9F => {
 									let (mut op, ip_offset) = get_operands(&memory, *rip, RegisterSize::Bit8,
 																																	RegOrOpcode::Register,
@@ -1109,6 +1290,21 @@ pub fn decode(rip : &mut i64, memory : &Memory) -> (Opcode, Operands) {
 									let op = decode_reg_reg(memory, rip, register_size, flags);
 									(Opcode::Bts, op)
 							}
+							0xAE => {
+									let modrm = memory.get_u8(*rip, 1);
+									let opcode = (modrm & 0b00111000) >> 3;
+									if let (Repeat::Equal, 0b11, 0..=3) = (&repeat, modrm >> 6, opcode) {
+											// F3 0F AE /0-3: rdfsbase, rdgsbase, wrfsbase, wrgsbase
+											let (op, ip_offset) = get_operands(&memory, *rip, register_size,
+																																	RegOrOpcode::Opcode,
+																																	ImmediateSize::None,
+																																	flags);
+											*rip += ip_offset;
+											([Opcode::Rdfsbase, Opcode::Rdgsbase, Opcode::Wrfsbase, Opcode::Wrgsbase][opcode as usize], op)
+									} else {
+											panic!("0F AE unsupported opcode: {:x}", opcode)
+									}
+							}
 							0xAF => {
 									let (op, ip_offset) = get_operands(&memory, *rip, register_size,
 																															RegOrOpcode::Register,
@@ -1191,76 +1387,53 @@ pub fn decode(rip : &mut i64, memory : &Memory) -> (Opcode, Operands) {
 					}
 			}
 			0xCC => {
-					// abuse int 3 instruction to signal failed test program
-					panic!("int3 instruction");
+					*rip += 1;
+					(Opcode::Int, Operands{ operands: [Some(Operand::Immediate(3)), None, None], explicit_size: Some(OperandSize::Bit8), ..Default::default() })
 			}
 			0xCD => {
-					// abuse int X instruction to signal passed test program
-					(Opcode::Int, Operands::default())
+					let immediate = memory.get_u8(*rip, 1);
+					*rip += 2;
+					(Opcode::Int, Operands{ operands: [Some(Operand::Immediate(immediate as i64)), None, None], explicit_size: Some(OperandSize::Bit8), ..Default::default() })
+			}
+			0xCF => {
+					let operand_size = if flags.contains(Flags::OPERAND_64_BIT) { OperandSize::Bit64 } else if flags.contains(Flags::OPERAND_16_BIT) { OperandSize::Bit16 } else { OperandSize::Bit32 };
+					(Opcode::Iret, Operands{ explicit_size: Some(operand_size), ..Default::default() })
 			}
 			unknown => panic!("Unknown instruction: {:x}", unknown),
 	}
 }
 
+// rel16 in 16bit code, rel32 otherwise
+fn read_relative(memory: &Memory, rip: &mut i64, flags: Flags) -> Operands {
+	let immediate = if flags.contains(Flags::LEGACY) && flags.contains(Flags::OPERAND_16_BIT) {
+			let immediate = memory.get_i16(*rip, 1) as i64;
+			*rip += 3;
+			immediate
+	} else {
+			let immediate = memory.get_i32(*rip, 1) as i64;
+			*rip += 5;
+			immediate
+	};
+	Operands{ operands: [Some(Operand::Immediate(immediate)), None, None], ..Default::default()}
+}
+
 fn read_immediate_8bit(memory: &Memory, rip: &mut i64) -> Operands {
 	let immediate = memory.get_i8(*rip, 1) as i64;
 	*rip += 2;
 	Operands{ operands: [Some(Operand::Immediate(immediate)), None, None], ..Default::default()}
 }
 
-fn get_operands(memory : &Memory, rip: i64, register_size: RegisterSize, reg_or_opcode: RegOrOpcode, immediate_size: ImmediateSize, mut flags: Flags) -> (Operands, i64) {
+fn get_operands(memory : &Memory, rip: i64, register_size: RegisterSize, reg_or_opcode: RegOrOpcode, immediate_size: ImmediateSize, flags: Flags) -> (Operands, i64) {
 	let modrm = memory.get_u8(rip, 1);
-	let mut address_mod = modrm >> 6;
+	let address_mod = modrm >> 6;
 	match address_mod {
 			0b00 | 0b01 | 0b10 => {
-					// effective address / effecive address + 8 bit deplacement /
-					// effecive address + 32 bit deplacement
-					let rm = modrm & 0b00000111;
-
-					// special case: RIP relative adressing. We fake a 32bit displacement instruction.
-					if address_mod == 0b00 && rm == 0x5 {
-							address_mod = 0b100;
-					}
-
-					// sib byte
-					let (sib, offset) = if rm == 0b100 {
-							(Some(memory.get_u8(rip, 2)), 3)
+					let (address, mut ip_offset) = if flags.contains(Flags::ADDRESS_16_BIT) {
+							modrm_address_16bit(memory, rip, modrm, flags)
 					} else {
-							(None, 2)
+							modrm_address(memory, rip, modrm, flags)
 					};
 
-					let (displacement, mut ip_offset) = match address_mod {
-							0b00 => {
-									match sib {
-											Some(sib) => {
-													let base = sib & 0b00000111;
-													if base == 0x5 {
-															let displacement = memory.get_i32(rip, offset);
-															flags |= Flags::SIB_DISPLACEMENT_ONLY;
-															(displacement, 4)
-													} else {
-															(0, 0)
-													}
-											},
-											None => (0, 0)
-									}
-							}
-							0b01 => {
-									(memory.get_i8(rip, offset) as i8 as i32, 1)
-							}
-							0b10 | 0b100 => {
-									let displacement = memory.get_i32(rip, offset);
-									// change RIP relative addressing mode back to 0b00
-									if address_mod == 0b100 {
-											address_mod = 0b00;
-									}
-
-									(displacement, 4)
-							}
-							_ => unreachable!(),
-					};
-					ip_offset += offset; // skip instruction + modrm byte
-
 					let register_or_opcode = (modrm & 0b00111000) >> 3;
 					// TODO: based on REX, this could be a 64bit value
 					match immediate_size {
@@ -1275,20 +1448,8 @@ fn get_operands(memory : &Memory, rip: i64, register_size: RegisterSize, reg_or_
 											RegisterSize::Bit64 => OperandSize::Bit64,
 											_ => panic!("Unsupported register size"),
 									};
-									let register = if address_mod == 0b00 && rm == 0x5 {
-											Register::RIP
-									} else {
-											let register_size = if flags.contains(Flags::ADDRESS_SIZE_OVERRIDE) {
-													RegisterSize::Bit32
-											} else {
-													RegisterSize::Bit64
-											};
-											get_register(rm, register_size, flags.contains(Flags::NEW_64BIT_REGISTER),
-																		flags.contains(Flags::NEW_8BIT_REGISTER))
-									};
 
-									(Operands{ operands: [Some(Operand::Immediate(immediate as i64)),
-																													Some(effective_address(sib, register, displacement, flags)), None],
+									(Operands{ operands: [Some(Operand::Immediate(immediate as i64)), Some(address), None],
 																			opcode: Some(register_or_opcode), explicit_size: Some(operand_size), ..Default::default() },
 										ip_offset + 1)
 							}
@@ -1312,39 +1473,11 @@ fn get_operands(memory : &Memory, rip: i64, register_size: RegisterSize, reg_or_
 											_ => panic!("Unsupported register size"),
 									};
 
-									let register = if address_mod == 0b00 && rm == 0x5 {
-											Register::RIP
-									} else {
-											let register_size = if flags.contains(Flags::ADDRESS_SIZE_OVERRIDE) {
-													RegisterSize::Bit32
-											} else {
-													RegisterSize::Bit64
-											};
-											get_register(rm, register_size, flags.contains(Flags::NEW_64BIT_REGISTER),
-																		flags.contains(Flags::NEW_8BIT_REGISTER))
-									};
-
-									(Operands{ operands: [Some(Operand::Immediate(immediate)), Some(effective_address(sib, register, displacement, flags)), None],
+									(Operands{ operands: [Some(Operand::Immediate(immediate)), Some(address), None],
 																			opcode: Some(register_or_opcode), explicit_size: Some(operand_size), ..Default::default() },
 										ip_offset)
 							}
 							ImmediateSize::None => {
-									let first_reg_size = if flags.contains(Flags::ADDRESS_SIZE_OVERRIDE) {
-											RegisterSize::Bit32
-									} else {
-											RegisterSize::Bit64
-									};
-
-									// special case: RIP relative adressing.
-									let register1 = if address_mod == 0b00 && rm == 0x5 {
-											Register::RIP
-									} else {
-											get_register(rm,
-																		first_reg_size,
-																		flags.contains(Flags::NEW_64BIT_REGISTER),
-																		flags.contains(Flags::NEW_8BIT_REGISTER))
-									};
-
 									(match reg_or_opcode {
 											RegOrOpcode::Register => {
 													let register2 = get_register_or_xmm(register_or_opcode,
@@ -1354,15 +1487,15 @@ fn get_operands(memory : &Memory, rip: i64, register_size: RegisterSize, reg_or_
 																											flags.contains(Flags::OP2_XMM));
 
 													if flags.contains(Flags::REVERSED_REGISTER_DIRECTION) {
-															Operands{ operands: [Some(effective_address(sib, register1, displacement, flags)), Some(Operand::Register(register2)), None],
+															Operands{ operands: [Some(address), Some(Operand::Register(register2)), None],
 																									..Default::default()}
 													} else {
 															Operands{ operands: [Some(Operand::Register(register2)),
-																																			Some(effective_address(sib, register1, displacement, flags)), None], ..Default::default() }
+																																			Some(address), None], ..Default::default() }
 													}
 											},
 											RegOrOpcode::Opcode => {
-													Operands{ operands: [Some(effective_address(sib, register1, displacement, flags)), None, None],
+													Operands{ operands: [Some(address), None, None],
 																						opcode: Some(register_or_opcode), explicit_size: Some(OperandSize::Bit64), ..Default::default() }
 											}
 									}, ip_offset)
@@ -1404,9 +1537,13 @@ fn get_operands(memory : &Memory, rip: i64, register_size: RegisterSize, reg_or_
 																							opcode: Some(value2), ..Default::default()}, 3)
 											}
 											ImmediateSize::Bit32 => {
-													let immediate = memory.get_i32(rip, 2);
-													(Operands{ operands: [Some(Operand::Immediate(immediate as i64)), Some(Operand::Register(register)), None],
-																							opcode: Some(value2), ..Default::default()}, 6)
+													let (immediate, ip_offset) = if flags.contains(Flags::OPERAND_16_BIT) {
+															(memory.get_i16(rip, 2) as i64, 4)
+													} else {
+															(memory.get_i32(rip, 2) as i64, 6)
+													};
+													(Operands{ operands: [Some(Operand::Immediate(immediate)), Some(Operand::Register(register)), None],
+																							opcode: Some(value2), ..Default::default()}, ip_offset)
 											}
 											ImmediateSize::None => { (Operands{ operands: [Some(Operand::Register(register)), None, None], opcode: Some(value2), ..Default::default()}, 2) }
 									}
@@ -1417,7 +1554,138 @@ fn get_operands(memory : &Memory, rip: i64, register_size: RegisterSize, reg_or_
 	}
 }
 
+// ModRM (and SIB) memory operand with 32/64bit addressing. Returns the operand and the offset past the displacement
+fn modrm_address(memory: &Memory, rip: i64, modrm: u8, mut flags: Flags) -> (Operand, i64) {
+	let mut address_mod = modrm >> 6;
+	// effective address / effecive address + 8 bit deplacement /
+	// effecive address + 32 bit deplacement
+	let rm = modrm & 0b00000111;
+
+	// special case: RIP relative adressing. We fake a 32bit displacement instruction.
+	if address_mod == 0b00 && rm == 0x5 {
+			address_mod = 0b100;
+	}
+
+	// sib byte
+	let (sib, offset) = if rm == 0b100 {
+			(Some(memory.get_u8(rip, 2)), 3)
+	} else {
+			(None, 2)
+	};
+
+	let (displacement, mut ip_offset) = match address_mod {
+			0b00 => {
+					match sib {
+							Some(sib) => {
+									let base = sib & 0b00000111;
+									if base == 0x5 {
+											let displacement = memory.get_i32(rip, offset);
+											flags |= Flags::SIB_DISPLACEMENT_ONLY;
+											(displacement, 4)
+									} else {
+											(0, 0)
+									}
+							},
+							None => (0, 0)
+					}
+			}
+			0b01 => {
+					(memory.get_i8(rip, offset) as i8 as i32, 1)
+			}
+			0b10 | 0b100 => {
+					let displacement = memory.get_i32(rip, offset);
+					// change RIP relative addressing mode back to 0b00
+					if address_mod == 0b100 {
+							address_mod = 0b00;
+					}
+
+					(displacement, 4)
+			}
+			_ => unreachable!(),
+	};
+	ip_offset += offset; // skip instruction + modrm byte
+
+	if address_mod == 0b00 && rm == 0x5 {
+			if flags.contains(Flags::LEGACY) { // No RIP relative addressing outside 64bit mode: disp32
+					return (Operand::EffectiveAddress{ base: None, index: None, scale: None, displacement, segment: segment_override(flags), address_size: address_size(flags) }, ip_offset);
+			}
+			return (effective_address(sib, Register::RIP, displacement, flags), ip_offset);
+	}
+	let register = get_register(rm, address_register_size(flags), flags.contains(Flags::NEW_64BIT_REGISTER), flags.contains(Flags::NEW_8BIT_REGISTER));
+	(effective_address(sib, register, displacement, flags), ip_offset)
+}
+
+// 16bit addressing: [BX+SI], [BX+DI], [BP+SI], [BP+DI], [SI], [DI], [BP] (disp16 if mod=00), [BX]
+fn modrm_address_16bit(memory: &Memory, rip: i64, modrm: u8, flags: Flags) -> (Operand, i64) {
+	let (address_mod, rm) = (modrm >> 6, modrm & 0b111);
+	let (base, index) = match rm {
+			0 => (Register::BX, Some(Register::SI)),
+			1 => (Register::BX, Some(Register::DI)),
+			2 => (Register::BP, Some(Register::SI)),
+			3 => (Register::BP, Some(Register::DI)),
+			4 => (Register::SI, None),
+			5 => (Register::DI, None),
+			6 => (Register::BP, None),
+			7 => (Register::BX, None),
+			_ => unreachable!(),
+	};
+	let (base, displacement, ip_offset) = match address_mod {
+			0b00 if rm == 6 => (None, memory.get_i16(rip, 2) as u16 as i32, 4),
+			0b00 => (Some(base), 0, 2),
+			0b01 => (Some(base), memory.get_i8(rip, 2) as i32, 3),
+			0b10 => (Some(base), memory.get_i16(rip, 2) as i32, 4),
+			_ => unreachable!(),
+	};
+	(Operand::EffectiveAddress{ base, index, scale: index.map(|_| 1), displacement, segment: segment_override(flags), address_size: OperandSize::Bit16 }, ip_offset)
+}
+
+fn address_size(flags: Flags) -> OperandSize {
+	if flags.contains(Flags::ADDRESS_16_BIT) { OperandSize::Bit16 }
+	else if flags.contains(Flags::ADDRESS_SIZE_OVERRIDE) { OperandSize::Bit32 }
+	else { OperandSize::Bit64 }
+}
+
+fn address_register_size(flags: Flags) -> RegisterSize {
+	if flags.contains(Flags::ADDRESS_16_BIT) { RegisterSize::Bit16 }
+	else if flags.contains(Flags::ADDRESS_SIZE_OVERRIDE) { RegisterSize::Bit32 }
+	else { RegisterSize::Bit64 }
+}
+
+// String instruction operands: (r/e)si in DS or the override segment, (r/e)di always in ES
+fn string_source(flags: Flags) -> Operand {
+	Operand::EffectiveAddress{ base: Some(get_register(6, address_register_size(flags), false, false)), index: None, scale: None, displacement: 0, segment: segment_override(flags), address_size: address_size(flags) }
+}
+
+fn string_destination(flags: Flags) -> Operand {
+	Operand::EffectiveAddress{ base: Some(get_register(7, address_register_size(flags), false, false)), index: None, scale: None, displacement: 0, segment: Some(Register::ES), address_size: address_size(flags) }
+}
+
+// ModRM.reg of mov to or from a debug register
+fn debug_register(register: Register) -> Register {
+	match register {
+		Register::RAX => Register::DR0,
+		Register::RCX => Register::DR1,
+		Register::RDX => Register::DR2,
+		Register::RBX => Register::DR3,
+		Register::RSP | Register::RSI => Register::DR6,
+		Register::RBP | Register::RDI => Register::DR7,
+		_ => panic!("Invalid operand for mov DRn instruction"),
+	}
+}
+
+fn segment_override(flags: Flags) -> Option<Register> {
+	if flags.contains(Flags::SEGMENT_FS) { Some(Register::FS) }
+	else if flags.contains(Flags::SEGMENT_GS) { Some(Register::GS) }
+	else if flags.contains(Flags::SEGMENT_ES) { Some(Register::ES) }
+	else if flags.contains(Flags::SEGMENT_CS) { Some(Register::CS) }
+	else if flags.contains(Flags::SEGMENT_SS) { Some(Register::SS) }
+	else if flags.contains(Flags::SEGMENT_DS) { Some(Register::DS) }
+	else { None }
+}
+
 fn effective_address(sib: Option<u8>, register: Register, displacement: i32, flags: Flags) -> Operand {
+	let segment = segment_override(flags);
+	let address_size = address_size(flags);
 	match sib {
 		None => {
 			Operand::EffectiveAddress {
@@ -1425,6 +1693,8 @@ fn effective_address(sib: Option<u8>, register: Register, displacement: i32, fla
 					index: None,
 					scale: None,
 					displacement,
+					segment,
+					address_size,
 			}
 		}
 		Some(sib) => {
@@ -1447,6 +1717,8 @@ fn effective_address(sib: Option<u8>, register: Register, displacement: i32, fla
 					Operand::EffectiveAddress {
 							base: None,
 							displacement,
+							segment,
+							address_size,
 							scale: None,
 							index: None,
 					}
@@ -1454,6 +1726,8 @@ fn effective_address(sib: Option<u8>, register: Register, displacement: i32, fla
 					Operand::EffectiveAddress {
 							base: Some(base),
 							displacement,
+							segment,
+							address_size,
 							scale: None,
 							index: None,
 					}
@@ -1462,6 +1736,8 @@ fn effective_address(sib: Option<u8>, register: Register, displacement: i32, fla
 				Operand::EffectiveAddress {
 						base: None,
 						displacement,
+						segment,
+						address_size,
 						scale: Some(scale),
 						index: Some(get_register(index, register_size,
 																		flags.contains(Flags::SIB_EXTENSION), false))
@@ -1470,6 +1746,8 @@ fn effective_address(sib: Option<u8>, register: Register, displacement: i32, fla
 				Operand::EffectiveAddress {
 						base: Some(base),
 						displacement,
+						segment,
+						address_size,
 						scale: Some(scale),
 						index: Some(get_register(index, register_size,
 																		flags.contains(Flags::SIB_EXTENSION), false))
@@ -13,6 +13,7 @@ pub fn dispatch(state: &mut State, (opcode, operand, _): &(Opcode, Operands, usi
         Opcode::Btc => btc(state, operand),
         Opcode::Call => call(state, operand),
         Opcode::Cld => cld(state),
+        Opcode::Cli => cli(state),
         Opcode::Cmova => cmova(state, operand),
         Opcode::Cmovae => cmovae(state, operand),
         Opcode::Cmovb => cmovb(state, operand),
@@ -38,8 +39,12 @@ pub fn dispatch(state: &mut State, (opcode, operand, _): &(Opcode, Operands, usi
         Opcode::Fsub => fsub(state, operand),
         Opcode::Fmul => fmul(state, operand),
         Opcode::Fdiv => fdiv(state, operand),
+        Opcode::Hlt => hlt(state),
         Opcode::Imul => imul(state, operand),
+        Opcode::In => in_(state, operand),
+        Opcode::Ins => ins(state, operand),
         Opcode::Int => int(state, operand),
+        Opcode::Iret => iret(state, operand),
         Opcode::Ja => ja(state, operand),
         Opcode::Jae => jae(state, operand),
         Opcode::Jb => jb(state, operand),
@@ -58,10 +63,17 @@ pub fn dispatch(state: &mut State, (opcode, operand, _): &(Opcode, Operands, usi
         Opcode::Jp => jp(state, operand),
         Opcode::Js => js(state, operand),
         Opcode::Lea => lea(state, operand),
-        Opcode::Leave => leave(state),
+        Opcode::Leave => leave(state, operand),
         Opcode::Lidt => lidt(state, operand),
         Opcode::Lgdt => lgdt(state, operand),
+        Opcode::Ltr => ltr(state, operand),
+        Opcode::Lldt => lldt(state, operand),
+        Opcode::Sldt => sldt(state, operand),
+        Opcode::Str => str_(state, operand),
+        Opcode::Verr => verr(state, operand),
+        Opcode::Verw => verw(state, operand),
         Opcode::Mov => mov(state, operand),
+        Opcode::MovCr => mov_cr(state, operand),
         Opcode::Movd => movd(state, operand),
         Opcode::Movss => movss(state, operand),
         Opcode::Movs => movs(state, operand),
@@ -69,28 +81,42 @@ pub fn dispatch(state: &mut State, (opcode, operand, _): &(Opcode, Operands, usi
         Opcode::Movzx => movzx(state, operand),
         Opcode::Nop => (),
         Opcode::Or => or(state, operand),
-        Opcode::Out => out(state),
+        Opcode::Out => out(state, operand),
+        Opcode::Outs => outs(state, operand),
         Opcode::Pop => pop(state, operand),
-        Opcode::Popf => popf(state),
+        Opcode::Popf => popf(state, operand),
+        Opcode::Popa => popa(state, operand),
         Opcode::Push => push(state, operand),
-        Opcode::Pushf => pushf(state),
+        Opcode::Pushf => pushf(state, operand),
+        Opcode::Pusha => pusha(state, operand),
         Opcode::RegisterOperation => register_operation(state, operand),
-        Opcode::Ret => ret(state),
-        Opcode::Lret => lret(state),
+        Opcode::Ret => ret(state, operand),
+        Opcode::Lret => lret(state, operand),
+        Opcode::Rdfsbase => rdfsbase(state, operand),
+        Opcode::Rdgsbase => rdgsbase(state, operand),
         Opcode::Rdmsr => rdmsr(state),
+        Opcode::Rdtsc => rdtsc(state),
+        Opcode::Rdtscp => rdtscp(state),
         Opcode::Sbb => sbb(state, operand),
         Opcode::ShiftRotate => shift_rotate(state, operand),
         Opcode::Std => std(state),
+        Opcode::Sti => sti(state),
         Opcode::Stos => stos(state, operand),
         Opcode::Sub => sub(state, operand),
+        Opcode::Swapgs => swapgs(state),
         Opcode::Test => test(state, operand),
         Opcode::Ud2 => ud2(state),
+        Opcode::Wrfsbase => wrfsbase(state, operand),
+        Opcode::Wrgsbase => wrgsbase(state, operand),
         Opcode::Wrmsr => wrmsr(state),
         Opcode::Xor => xor(state, operand),
         Opcode::Scas => scas(state, operand),
         Opcode::Cmpxchg => cmpxchg(state, operand),
         Opcode::Xchg => xchg(state, operand),
         Opcode::Syscall => syscall(state),
+        Opcode::Sysenter => sysenter(state),
+        Opcode::Sysexit => sysexit(state, operand),
+        Opcode::Sysret => sysret(state, operand),
         Opcode::Seto => seto(state, operand),
         Opcode::Setno => setno(state, operand),
         Opcode::Setb => setb(state, operand),
@@ -107,5 +133,13 @@ pub fn dispatch(state: &mut State, (opcode, operand, _): &(Opcode, Operands, usi
         Opcode::Setge => setge(state, operand),
         Opcode::Setle => setle(state, operand),
         Opcode::Setg => setg(state, operand),
+        Opcode::Daa => daa(state),
+        Opcode::Das => das(state),
+        Opcode::Aaa => aaa(state),
+        Opcode::Aas => aas(state),
+        Opcode::Aam => aam(state, operand),
+        Opcode::Aad => aad(state, operand),
+        Opcode::Into => into(state),
+        Opcode::Bound => bound(state, operand),
     }
 }
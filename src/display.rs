@@ -0,0 +1,198 @@
+use std::{rc::Rc, cell::RefCell, io::Write};
+use crate::{state::State, io::PortDevice, pci::{PciConfig, PciDevice, BarKind}};
+
+pub const VBE_DISPI_INDEX: u16 = 0x1CE;
+pub const VBE_DISPI_DATA: u16 = 0x1CF;
+const FRAMEBUFFER_SIZE: usize = 16 << 20;
+
+// Bochs VBE DISPI registers
+const INDEX_ID: usize = 0;
+const INDEX_XRES: usize = 1;
+const INDEX_YRES: usize = 2;
+const INDEX_BPP: usize = 3;
+const INDEX_ENABLE: usize = 4;
+const INDEX_BANK: usize = 5;
+const INDEX_VIRT_WIDTH: usize = 6;
+const INDEX_VIRT_HEIGHT: usize = 7;
+const INDEX_X_OFFSET: usize = 8;
+const INDEX_Y_OFFSET: usize = 9;
+const INDEX_VIDEO_MEMORY_64K: usize = 10;
+const DISPI_ID5: u16 = 0xB0C5;
+const ENABLED: u16 = 0x01;
+const GETCAPS: u16 = 0x02;
+const NOCLEARMEM: u16 = 0x80;
+const MAX_XRES: u16 = 2560;
+const MAX_YRES: u16 = 1600;
+
+// Linear framebuffer with bochs-display / VBE mode setting
+pub struct Display {
+	pub memory: Vec<u8>,
+	registers: [u16; 10],
+	index: u16,
+}
+
+impl Default for Display {
+	fn default() -> Self { Self{memory: vec![0; FRAMEBUFFER_SIZE], registers: [DISPI_ID5, 640, 480, 32, 0, 0, 640, 480, 0, 0], index: 0} }
+}
+
+impl Display {
+	// width, height, bits per pixel while enabled
+	pub fn mode(&self) -> Option<(usize, usize, usize)> {
+		if self.registers[INDEX_ENABLE] & ENABLED == 0 { return None; }
+		Some((self.registers[INDEX_XRES] as usize, self.registers[INDEX_YRES] as usize, self.registers[INDEX_BPP] as usize))
+	}
+
+	fn read_register(&self, index: usize) -> u16 {
+		let caps = self.registers[INDEX_ENABLE] & GETCAPS != 0;
+		match index {
+			INDEX_XRES if caps => MAX_XRES,
+			INDEX_YRES if caps => MAX_YRES,
+			INDEX_BPP if caps => 32,
+			INDEX_VIDEO_MEMORY_64K => (FRAMEBUFFER_SIZE >> 16) as u16,
+			index if index < self.registers.len() => self.registers[index],
+			_ => 0,
+		}
+	}
+
+	fn write_register(&mut self, index: usize, value: u16) {
+		match index {
+			INDEX_ID | INDEX_VIDEO_MEMORY_64K => {}
+			INDEX_ENABLE => {
+				if value & ENABLED != 0 && self.registers[INDEX_ENABLE] & ENABLED == 0 {
+					self.registers[INDEX_VIRT_WIDTH] = self.registers[INDEX_XRES];
+					self.registers[INDEX_VIRT_HEIGHT] = self.registers[INDEX_YRES];
+					self.registers[INDEX_X_OFFSET] = 0;
+					self.registers[INDEX_Y_OFFSET] = 0;
+					if value & NOCLEARMEM == 0 { self.memory.iter_mut().for_each(|byte| *byte = 0); }
+				}
+				self.registers[INDEX_ENABLE] = value;
+			}
+			INDEX_XRES => self.registers[index] = value.clamp(1, MAX_XRES),
+			INDEX_YRES => self.registers[index] = value.clamp(1, MAX_YRES),
+			INDEX_BPP => self.registers[index] = if matches!(value, 8 | 15 | 16 | 24 | 32) { value } else { 32 },
+			INDEX_BANK | INDEX_VIRT_WIDTH | INDEX_VIRT_HEIGHT | INDEX_X_OFFSET | INDEX_Y_OFFSET => self.registers[index] = value,
+			_ => {}
+		}
+	}
+
+	// Visible frame as 8bit RGB
+	pub fn snapshot(&self) -> Option<(usize, usize, Vec<u8>)> {
+		let (width, height, bpp) = self.mode()?;
+		let bytes_per_pixel = (bpp + 7) / 8;
+		let stride = self.registers[INDEX_VIRT_WIDTH] as usize * bytes_per_pixel;
+		let (x_offset, y_offset) = (self.registers[INDEX_X_OFFSET] as usize, self.registers[INDEX_Y_OFFSET] as usize);
+		let mut rgb = Vec::with_capacity(width * height * 3);
+		for y in 0..height {
+			for x in 0..width {
+				let offset = (y + y_offset) * stride + (x + x_offset) * bytes_per_pixel;
+				let pixel = self.memory.get(offset..offset+bytes_per_pixel).unwrap_or(&[0; 4][..bytes_per_pixel]);
+				rgb.extend_from_slice(&match bpp {
+					32 | 24 => [pixel[2], pixel[1], pixel[0]], // BGR(X)
+					16 => { let p = u16::from_le_bytes([pixel[0], pixel[1]]); [((p >> 11) << 3) as u8, ((p >> 5 & 0x3F) << 2) as u8, (p << 3) as u8] }
+					15 => { let p = u16::from_le_bytes([pixel[0], pixel[1]]); [((p >> 10 & 0x1F) << 3) as u8, ((p >> 5 & 0x1F) << 3) as u8, (p << 3) as u8] }
+					_ => [pixel[0]; 3], // Palette index as gray
+				});
+			}
+		}
+		Some((width, height, rgb))
+	}
+
+	pub fn write_ppm(&self, output: &mut dyn Write) -> std::io::Result<()> {
+		let (width, height, rgb) = self.snapshot().ok_or_else(disabled)?;
+		write!(output, "P6\n{} {}\n255\n", width, height)?;
+		output.write_all(&rgb)
+	}
+
+	// Uncompressed (stored deflate blocks) truecolor PNG
+	pub fn write_png(&self, output: &mut dyn Write) -> std::io::Result<()> {
+		let (width, height, rgb) = self.snapshot().ok_or_else(disabled)?;
+		let mut raw = Vec::with_capacity(height * (1 + width * 3));
+		for row in rgb.chunks(width * 3) { raw.push(0); raw.extend_from_slice(row); } // Filter: none
+		let mut zlib = vec![0x78, 0x01];
+		let blocks = raw.chunks(0xFFFF).collect::<Vec<_>>();
+		for (index, block) in blocks.iter().enumerate() {
+			zlib.push((index == blocks.len() - 1) as u8);
+			zlib.extend_from_slice(&(block.len() as u16).to_le_bytes());
+			zlib.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+			zlib.extend_from_slice(block);
+		}
+		let (a, b) = raw.iter().fold((1u32, 0u32), |(a, b), &byte| { let a = (a + byte as u32) % 65521; (a, (b + a) % 65521) });
+		zlib.extend_from_slice(&(b << 16 | a).to_be_bytes());
+		let mut header = Vec::new();
+		header.extend_from_slice(&(width as u32).to_be_bytes());
+		header.extend_from_slice(&(height as u32).to_be_bytes());
+		header.extend_from_slice(&[8, 2, 0, 0, 0]); // 8bit RGB
+		output.write_all(b"\x89PNG\r\n\x1a\n")?;
+		for (kind, data) in [(b"IHDR", header), (b"IDAT", zlib), (b"IEND", Vec::new())].iter() {
+			output.write_all(&(data.len() as u32).to_be_bytes())?;
+			output.write_all(*kind)?;
+			output.write_all(data)?;
+			output.write_all(&crc32(kind.iter().chain(data.iter())).to_be_bytes())?;
+		}
+		Ok(())
+	}
+}
+
+fn disabled() -> std::io::Error { std::io::Error::new(std::io::ErrorKind::Other, "display is disabled") }
+
+fn crc32<'t>(bytes: impl Iterator<Item=&'t u8>) -> u32 {
+	!bytes.fold(!0u32, |crc, &byte| (0..8).fold(crc ^ byte as u32, |crc, _| if crc & 1 != 0 { 0xEDB8_8320 ^ (crc >> 1) } else { crc >> 1 }))
+}
+
+impl PortDevice for Display {
+	fn read(&mut self, port: u16, _size: usize) -> u32 {
+		match port {
+			VBE_DISPI_INDEX => self.index as u32,
+			_ => self.read_register(self.index as usize) as u32,
+		}
+	}
+	fn write(&mut self, port: u16, _size: usize, value: u32) {
+		match port {
+			VBE_DISPI_INDEX => self.index = value as u16,
+			_ => self.write_register(self.index as usize, value as u16),
+		}
+	}
+}
+
+// bochs-display: BAR 0 framebuffer, BAR 2 registers
+struct BochsDisplay {
+	config: PciConfig,
+	display: Rc<RefCell<Display>>,
+}
+
+impl PciDevice for BochsDisplay {
+	fn config(&mut self) -> &mut PciConfig { &mut self.config }
+
+	fn read_bar(&mut self, bar: usize, offset: u64, size: usize) -> u64 {
+		let display = self.display.borrow();
+		match (bar, offset) {
+			(0, _) => display.memory[offset as usize..][..size].iter().rev().fold(0, |value, &byte| value << 8 | byte as u64),
+			(2, 0x500..=0x515) => display.read_register((offset as usize - 0x500) / 2) as u64,
+			(2, 0x600) => 8, // Size of the QEMU extended registers
+			(2, 0x604) => 0x1e1e_1e1e, // Little endian framebuffer
+			_ => 0,
+		}
+	}
+
+	fn write_bar(&mut self, bar: usize, offset: u64, size: usize, value: u64) {
+		let mut display = self.display.borrow_mut();
+		match (bar, offset) {
+			(0, _) => display.memory[offset as usize..][..size].copy_from_slice(&value.to_le_bytes()[..size]),
+			(2, 0x500..=0x515) => display.write_register((offset as usize - 0x500) / 2, value as u16),
+			_ => {}
+		}
+	}
+}
+
+impl State {
+	// Requires PCI. The returned handle takes snapshots
+	pub fn enable_display(&mut self) -> Rc<RefCell<Display>> {
+		let display = Rc::new(RefCell::new(Display::default()));
+		let mut config = PciConfig::new(0x1234, 0x1111, 0x03_80_00, 2);
+		config.add_bar(0, FRAMEBUFFER_SIZE as u64, BarKind::Memory32);
+		config.add_bar(2, 0x1000, BarKind::Memory32);
+		self.add_pci_device(Box::new(BochsDisplay{config, display: display.clone()}));
+		self.io.register(VBE_DISPI_INDEX..=VBE_DISPI_DATA, Box::new(display.clone()));
+		display
+	}
+}
@@ -0,0 +1,32 @@
+use object::{elf::{FileHeader32, FileHeader64, PT_LOAD}, read::elf::{FileHeader, ProgramHeader}, Endianness, FileKind};
+use crate::{state::State, memory::PAGE_SIZE};
+
+// Loads the PT_LOAD segments at their physical (boot loaders) or virtual (programs) address
+// Returns the entry point and the end of the loaded image
+pub fn load_elf(state: &mut State, image: &[u8], physical: bool) -> (u64, u64) {
+	match FileKind::parse(image) {
+		Ok(FileKind::Elf32) => load::<FileHeader32<Endianness>>(state, image, physical),
+		Ok(FileKind::Elf64) => load::<FileHeader64<Endianness>>(state, image, physical),
+		_ => panic!("Not an ELF image"),
+	}
+}
+
+fn load<Elf: FileHeader<Endian=Endianness>>(state: &mut State, image: &[u8], physical: bool) -> (u64, u64) {
+	let header = Elf::parse(image).unwrap_or_else(|error| panic!("ELF: {}", error));
+	let endian = header.endian().unwrap();
+	let mut end = 0;
+	for segment in header.program_headers(endian, image).unwrap_or_else(|error| panic!("ELF: {}", error)) {
+		if segment.p_type(endian) != PT_LOAD { continue; }
+		let address: u64 = if physical { segment.p_paddr(endian).into() } else { segment.p_vaddr(endian).into() };
+		let size: u64 = segment.p_memsz(endian).into();
+		let data = segment.data(endian, image).unwrap_or_else(|_| panic!("ELF: segment {:x} outside the image", address));
+		// Keeps pages already backed by RAM
+		for page in address/PAGE_SIZE..(address+size+PAGE_SIZE-1)/PAGE_SIZE {
+			state.memory.physical_to_host.entry(page).or_insert_with(|| vec![0; PAGE_SIZE as usize]);
+		}
+		state.memory.write_unaligned_bytes(address, data);
+		state.memory.write_unaligned_bytes(address+data.len() as u64, &vec![0; size as usize-data.len()]); // .bss
+		end = end.max(address+size);
+	}
+	(header.e_entry(endian).into(), end)
+}
@@ -0,0 +1,334 @@
+use std::{collections::BTreeSet, io::{self, Read, Write}, net::{TcpListener, TcpStream}, os::unix::net::{UnixListener, UnixStream}};
+use crate::{state::State, memory::Access, debug::StopReason};
+
+// x86-64 register set in the order of g packets: general purpose, x87 (not emulated), SSE and segment bases
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+<architecture>i386:x86-64</architecture>
+<feature name="org.gnu.gdb.i386.core">
+<reg name="rax" bitsize="64" type="int64"/><reg name="rbx" bitsize="64" type="int64"/>
+<reg name="rcx" bitsize="64" type="int64"/><reg name="rdx" bitsize="64" type="int64"/>
+<reg name="rsi" bitsize="64" type="int64"/><reg name="rdi" bitsize="64" type="int64"/>
+<reg name="rbp" bitsize="64" type="data_ptr"/><reg name="rsp" bitsize="64" type="data_ptr"/>
+<reg name="r8" bitsize="64" type="int64"/><reg name="r9" bitsize="64" type="int64"/>
+<reg name="r10" bitsize="64" type="int64"/><reg name="r11" bitsize="64" type="int64"/>
+<reg name="r12" bitsize="64" type="int64"/><reg name="r13" bitsize="64" type="int64"/>
+<reg name="r14" bitsize="64" type="int64"/><reg name="r15" bitsize="64" type="int64"/>
+<reg name="rip" bitsize="64" type="code_ptr"/><reg name="eflags" bitsize="32" type="int32"/>
+<reg name="cs" bitsize="32" type="int32"/><reg name="ss" bitsize="32" type="int32"/>
+<reg name="ds" bitsize="32" type="int32"/><reg name="es" bitsize="32" type="int32"/>
+<reg name="fs" bitsize="32" type="int32"/><reg name="gs" bitsize="32" type="int32"/>
+<reg name="st0" bitsize="80" type="i387_ext"/><reg name="st1" bitsize="80" type="i387_ext"/>
+<reg name="st2" bitsize="80" type="i387_ext"/><reg name="st3" bitsize="80" type="i387_ext"/>
+<reg name="st4" bitsize="80" type="i387_ext"/><reg name="st5" bitsize="80" type="i387_ext"/>
+<reg name="st6" bitsize="80" type="i387_ext"/><reg name="st7" bitsize="80" type="i387_ext"/>
+<reg name="fctrl" bitsize="32" type="int" group="float"/><reg name="fstat" bitsize="32" type="int" group="float"/>
+<reg name="ftag" bitsize="32" type="int" group="float"/><reg name="fiseg" bitsize="32" type="int" group="float"/>
+<reg name="fioff" bitsize="32" type="int" group="float"/><reg name="foseg" bitsize="32" type="int" group="float"/>
+<reg name="fooff" bitsize="32" type="int" group="float"/><reg name="fop" bitsize="32" type="int" group="float"/>
+</feature>
+<feature name="org.gnu.gdb.i386.sse">
+<vector id="v4f" type="ieee_single" count="4"/><vector id="v2d" type="ieee_double" count="2"/>
+<vector id="v16i8" type="int8" count="16"/><vector id="v8i16" type="int16" count="8"/>
+<vector id="v4i32" type="int32" count="4"/><vector id="v2i64" type="int64" count="2"/>
+<union id="vec128"><field name="v4_float" type="v4f"/><field name="v2_double" type="v2d"/>
+<field name="v16_int8" type="v16i8"/><field name="v8_int16" type="v8i16"/><field name="v4_int32" type="v4i32"/>
+<field name="v2_int64" type="v2i64"/><field name="uint128" type="uint128"/></union>
+<reg name="xmm0" bitsize="128" type="vec128"/><reg name="xmm1" bitsize="128" type="vec128"/>
+<reg name="xmm2" bitsize="128" type="vec128"/><reg name="xmm3" bitsize="128" type="vec128"/>
+<reg name="xmm4" bitsize="128" type="vec128"/><reg name="xmm5" bitsize="128" type="vec128"/>
+<reg name="xmm6" bitsize="128" type="vec128"/><reg name="xmm7" bitsize="128" type="vec128"/>
+<reg name="xmm8" bitsize="128" type="vec128"/><reg name="xmm9" bitsize="128" type="vec128"/>
+<reg name="xmm10" bitsize="128" type="vec128"/><reg name="xmm11" bitsize="128" type="vec128"/>
+<reg name="xmm12" bitsize="128" type="vec128"/><reg name="xmm13" bitsize="128" type="vec128"/>
+<reg name="xmm14" bitsize="128" type="vec128"/><reg name="xmm15" bitsize="128" type="vec128"/>
+<reg name="mxcsr" bitsize="32" type="int" group="vector"/>
+</feature>
+<feature name="org.gnu.gdb.i386.segments">
+<reg name="fs_base" bitsize="64" type="int"/><reg name="gs_base" bitsize="64" type="int"/>
+</feature>
+</target>"#;
+
+const REGISTERS: usize = 59;
+fn register_size(number: usize) -> usize {
+	match number {
+		0..=16 | 57 | 58 => 8,
+		24..=31 => 10,
+		40..=55 => 16,
+		_ => 4,
+	}
+}
+
+fn general_register(state: &mut State, number: usize) -> Option<&mut i64> {
+	Some(match number {
+		0 => &mut state.rax, 1 => &mut state.rbx, 2 => &mut state.rcx, 3 => &mut state.rdx,
+		4 => &mut state.rsi, 5 => &mut state.rdi, 6 => &mut state.rbp, 7 => &mut state.rsp,
+		8 => &mut state.r8, 9 => &mut state.r9, 10 => &mut state.r10, 11 => &mut state.r11,
+		12 => &mut state.r12, 13 => &mut state.r13, 14 => &mut state.r14, 15 => &mut state.r15,
+		16 => &mut state.rip, 17 => &mut state.rflags,
+		_ => return None,
+	})
+}
+
+fn read_register(state: &mut State, number: usize) -> Vec<u8> {
+	let segments = [state.cs, state.ss, state.ds, state.es, state.fs, state.gs];
+	let (fs_base, gs_base) = (state.fs.base, state.gs.base);
+	let mut value = match number {
+		0..=17 => general_register(state, number).map_or(0, |register| *register as u128),
+		18..=23 => segments[number-18].selector as u128,
+		32 => 0x37F, // fctrl
+		40..=55 => state.xmm[number-40],
+		56 => 0x1F80, // mxcsr
+		57 => fs_base as u128,
+		58 => gs_base as u128,
+		_ => 0,
+	}.to_le_bytes().to_vec();
+	value.truncate(register_size(number));
+	value
+}
+
+// Selectors, x87 and mxcsr are read only
+fn write_register(state: &mut State, number: usize, value: &[u8]) {
+	let mut bytes = [0; 16];
+	bytes[..value.len()].copy_from_slice(value);
+	let value = u128::from_le_bytes(bytes);
+	match number {
+		0..=17 => if let Some(register) = general_register(state, number) { *register = value as i64; },
+		40..=55 => state.xmm[number-40] = value,
+		57 => state.fs.base = value as u64,
+		58 => state.gs.base = value as u64,
+		_ => {}
+	}
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String { bytes.iter().map(|byte| format!("{:02x}", byte)).collect() }
+fn unhex(text: &str) -> Option<Vec<u8>> {
+	(0..text.len()).step_by(2).map(|index| u8::from_str_radix(text.get(index..index+2)?, 16).ok()).collect()
+}
+fn number(text: &str) -> Option<u64> { u64::from_str_radix(text, 16).ok() }
+// addr,length
+fn range(text: &str) -> Option<(u64, u64)> {
+	let (address, length) = text.split_once(',')?;
+	Some((number(address)?, number(length)?))
+}
+
+trait Connection: Read + Write { fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>; }
+impl Connection for TcpStream { fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> { TcpStream::set_nonblocking(self, nonblocking) } }
+impl Connection for UnixStream { fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> { UnixStream::set_nonblocking(self, nonblocking) } }
+
+const INTERRUPT: u8 = 0x03;
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+const INTERRUPT_POLL: u64 = 0x10000; // Instructions between checks for ^C while running
+
+// GDB remote serial protocol stub for a single threaded guest
+pub struct GdbStub {
+	connection: Box<dyn Connection>,
+	no_ack: bool,
+	hardware_breakpoints: BTreeSet<u64>, // Z1 breakpoints are reported as hwbreak
+}
+
+impl GdbStub {
+	// Waits for gdb to connect on a Unix socket path, a TCP host:port or a localhost port
+	pub fn listen(address: &str) -> io::Result<Self> {
+		let connection: Box<dyn Connection> = if address.contains('/') {
+			Box::new(UnixListener::bind(address)?.accept()?.0)
+		} else {
+			let address = if address.contains(':') { address.to_string() } else { format!("127.0.0.1:{}", address) };
+			let stream = TcpListener::bind(address)?.accept()?.0;
+			stream.set_nodelay(true)?;
+			Box::new(stream)
+		};
+		Ok(Self{connection, no_ack: false, hardware_breakpoints: BTreeSet::new()})
+	}
+
+	fn read_byte(&mut self) -> io::Result<u8> {
+		let mut byte = [0];
+		self.connection.read_exact(&mut byte)?;
+		Ok(byte[0])
+	}
+
+	// Returns the payload of the next valid packet, or ^C
+	fn receive(&mut self) -> io::Result<String> {
+		loop {
+			match self.read_byte()? {
+				b'$' => {}
+				INTERRUPT => return Ok((INTERRUPT as char).to_string()),
+				_ => continue, // Acknowledgments
+			}
+			let mut packet = Vec::new();
+			loop {
+				match self.read_byte()? {
+					b'#' => break,
+					byte => packet.push(byte),
+				}
+			}
+			let checksum = [self.read_byte()?, self.read_byte()?];
+			let valid = std::str::from_utf8(&checksum).ok().and_then(number) == Some(packet.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) as u64);
+			if !self.no_ack { self.connection.write_all(if valid { b"+" } else { b"-" })?; }
+			if valid { return Ok(String::from_utf8_lossy(&packet).into_owned()); }
+		}
+	}
+
+	fn send(&mut self, packet: &str) -> io::Result<()> {
+		let checksum = packet.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+		loop {
+			write!(self.connection, "${}#{:02x}", packet, checksum)?;
+			self.connection.flush()?;
+			if self.no_ack || self.read_byte()? != b'-' { return Ok(()); }
+		}
+	}
+
+	fn interrupted(&mut self) -> io::Result<bool> {
+		self.connection.set_nonblocking(true)?;
+		let mut byte = [0];
+		let interrupted = match self.connection.read(&mut byte) {
+			Ok(1) => byte[0] == INTERRUPT,
+			Ok(_) => false,
+			Err(error) if error.kind() == io::ErrorKind::WouldBlock => false,
+			Err(error) => return Err(error),
+		};
+		self.connection.set_nonblocking(false)?;
+		Ok(interrupted)
+	}
+
+	fn stop_reply(&self, state: &State, reason: StopReason) -> String {
+		match reason {
+			StopReason::Exited => format!("W{:02x}", state.exit_status() as u8),
+			// The guest can still be inspected and resumed
+			StopReason::Halted | StopReason::InstructionLimit => format!("S{:02x}", SIGTRAP),
+			StopReason::Breakpoint{address} => format!("T{:02x}{}:;", SIGTRAP, if self.hardware_breakpoints.contains(&address) { "hwbreak" } else { "swbreak" }),
+			StopReason::Watchpoint{address, access, ..} => format!("T{:02x}{}:{:x};", SIGTRAP, if access == Access::Read { "rwatch" } else { "watch" }, address),
+			StopReason::TripleFault => format!("S{:02x}", SIGSEGV),
+		}
+	}
+
+	// Runs until a breakpoint, a watchpoint, ^C or the end of execution. Returns the stop reply
+	fn resume(&mut self, state: &mut State, single_step: bool) -> io::Result<String> {
+		if single_step {
+			return Ok(match state.step() {
+				Some(reason) => self.stop_reply(state, reason),
+				None => format!("S{:02x}", SIGTRAP),
+			});
+		}
+		// Polls for ^C between slices of the instruction limit
+		let limit = state.instruction_limit;
+		loop {
+			state.instruction_limit = limit.min(state.instructions + INTERRUPT_POLL);
+			let reason = state.execute();
+			state.instruction_limit = limit;
+			if reason != StopReason::InstructionLimit || state.instructions >= limit { return Ok(self.stop_reply(state, reason)); }
+			if self.interrupted()? { return Ok(format!("S{:02x}", SIGINT)); }
+		}
+	}
+
+	// Z and z packets: type,addr,kind
+	fn breakpoint(&mut self, state: &mut State, insert: bool, arguments: &str) -> Option<&'static str> {
+		let (kind, arguments) = arguments.split_once(',')?;
+		let (address, length) = range(arguments)?;
+		let access = match kind {
+			"0" | "1" => {
+				if insert { state.add_breakpoint(address); } else { state.remove_breakpoint(address); }
+				if kind == "1" && insert { self.hardware_breakpoints.insert(address); } else { self.hardware_breakpoints.remove(&address); }
+				return Some("OK");
+			}
+			"2" => Access::Write,
+			"3" => Access::Read,
+			"4" => Access::ReadWrite,
+			_ => return Some(""),
+		};
+		if insert { state.add_watchpoint(address..address+length, access); } else { state.remove_watchpoint(address..address+length, access); }
+		Some("OK")
+	}
+
+	// Serves gdb until it detaches (returns true: execution continues without the debugger), kills the guest or disconnects
+	pub fn run(&mut self, state: &mut State) -> io::Result<bool> {
+		loop {
+			let packet = match self.receive() {
+				Ok(packet) => packet,
+				Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+				Err(error) => return Err(error),
+			};
+			let reply = match packet.as_str() {
+				"\x03" => format!("S{:02x}", SIGINT),
+				"?" => format!("S{:02x}", SIGTRAP),
+				"QStartNoAckMode" => {
+					self.send("OK")?;
+					self.no_ack = true;
+					continue;
+				}
+				"qAttached" => "1".to_string(),
+				"qC" => "QC1".to_string(),
+				"qfThreadInfo" => "m1".to_string(),
+				"qsThreadInfo" => "l".to_string(),
+				"g" => (0..REGISTERS).map(|number| hex(&read_register(state, number))).collect(),
+				"vCont?" => "vCont;c;C;s;S".to_string(),
+				"k" => return Ok(false),
+				"D" => {
+					self.send("OK")?;
+					return Ok(true);
+				}
+				packet if packet.starts_with("qSupported") => "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+;vContSupported+;swbreak+;hwbreak+".to_string(),
+				packet if packet.starts_with("qXfer:features:read:target.xml:") => {
+					let (offset, length) = range(&packet["qXfer:features:read:target.xml:".len()..]).unwrap_or((0, 0));
+					let xml = TARGET_XML.get(offset as usize..).unwrap_or("");
+					if xml.len() as u64 > length { format!("m{}", &xml[..length as usize]) } else { format!("l{}", xml) }
+				}
+				packet if packet.starts_with('G') => {
+					let mut values = unhex(&packet[1..]).unwrap_or_default();
+					if values.len() == (0..REGISTERS).map(register_size).sum::<usize>() {
+						for number in 0..REGISTERS {
+							let value = values.drain(..register_size(number)).collect::<Vec<_>>();
+							write_register(state, number, &value);
+						}
+						"OK".to_string()
+					} else { "E22".to_string() }
+				}
+				packet if packet.starts_with('p') => match number(&packet[1..]) {
+					Some(number) if (number as usize) < REGISTERS => hex(&read_register(state, number as usize)),
+					_ => "E22".to_string(),
+				},
+				packet if packet.starts_with('P') => match packet[1..].split_once('=').and_then(|(number_, value)| Some((number(number_)? as usize, unhex(value)?))) {
+					Some((number, value)) if number < REGISTERS && value.len() == register_size(number) => {
+						write_register(state, number, &value);
+						"OK".to_string()
+					}
+					_ => "E22".to_string(),
+				},
+				packet if packet.starts_with('m') => match range(&packet[1..]).and_then(|(address, length)| state.memory.peek(address, length as usize)) {
+					Some(bytes) => hex(&bytes),
+					None => "E14".to_string(),
+				},
+				packet if packet.starts_with('M') => {
+					let write = packet[1..].split_once(':').and_then(|(range_, data)| Some((range(range_)?.0, unhex(data)?)));
+					match write {
+						Some((address, data)) if state.memory.poke(address, &data) => {
+							state.flush_instruction_cache();
+							"OK".to_string()
+						}
+						_ => "E14".to_string(),
+					}
+				}
+				// Optional resume address, signals are not delivered
+				packet if packet.starts_with(&['c', 's', 'C', 'S'][..]) => {
+					let single_step = packet.starts_with(&['s', 'S'][..]);
+					let address = if packet.starts_with(&['c', 's'][..]) { Some(&packet[1..]) } else { packet.split_once(';').map(|(_, address)| address) };
+					if let Some(address) = address.and_then(number) { state.rip = address as i64; }
+					self.resume(state, single_step)?
+				}
+				// The first action applies to the only thread
+				packet if packet.starts_with("vCont;") => match packet["vCont;".len()..].chars().next() {
+					Some('s') | Some('S') => self.resume(state, true)?,
+					Some('c') | Some('C') => self.resume(state, false)?,
+					_ => String::new(),
+				},
+				packet if packet.starts_with('Z') || packet.starts_with('z') => self.breakpoint(state, packet.starts_with('Z'), &packet[1..]).unwrap_or("E22").to_string(),
+				packet if packet.starts_with('H') || packet.starts_with('T') => "OK".to_string(),
+				_ => String::new(), // Unsupported
+			};
+			self.send(&reply)?;
+		}
+	}
+}
@@ -10,6 +10,7 @@ pub enum Register {
     R8, R9, R10, R11, R12, R13, R14, R15,
     RIP,
     CR0, CR2, CR3, CR4, CR8,
+    DR0, DR1, DR2, DR3, DR6, DR7,
     // 32 Bit
     EAX, EBX, ECX, EDX, ESP, EBP, ESI, EDI,
     R8D, R9D, R10D, R11D, R12D, R13D, R14D, R15D,
@@ -26,10 +27,16 @@ pub enum Register {
 pub enum Flags {
     Carry = 1 /*<< 0*/,
     Parity = 1 << 2,
+    Adjust = 1 << 4, // Carry out of the low nibble for BCD arithmetic
     Zero = 1 << 6,
     Sign = 1 << 7,
+    Trap = 1 << 8,
+    Interrupt = 1 << 9,
     Direction = 1 << 10,
     Overflow = 1 << 11,
+    NestedTask = 1 << 14,
+    Resume = 1 << 16,
+    Virtual8086 = 1 << 17,
 }
 
 #[derive(Debug)] pub enum Repeat { None, Equal, NotEqual }
@@ -37,6 +44,11 @@ impl Default for Repeat { fn default() -> Repeat { Repeat::None } }
 
 #[derive(Clone, Copy, Debug)] pub enum RegisterSize { Bit8, Bit16, Bit32, Bit64, Bit128, Segment }
 #[derive(Debug, Copy, Clone)] pub enum OperandSize { Bit128, Bit64, Bit32, Bit16, Bit8 }
+impl OperandSize {
+	pub fn bytes(&self) -> usize {
+		match self { OperandSize::Bit128 => 16, OperandSize::Bit64 => 8, OperandSize::Bit32 => 4, OperandSize::Bit16 => 2, OperandSize::Bit8 => 1 }
+	}
+}
 
 pub fn get_register_size(reg: Register) -> OperandSize {
 	match reg {
@@ -49,7 +61,8 @@ pub fn get_register_size(reg: Register) -> OperandSize {
 		Register::RBP | Register::RSI | Register::RDI | Register::RIP | Register::R8 |
 		Register::R9 | Register::R10 | Register::R11 | Register::R12 | Register::R13 |
 		Register::R14 | Register::R15 | Register::CR0 | Register::CR2 | Register::CR3 |
-		Register::CR4 | Register::CR8 => OperandSize::Bit64,
+		Register::CR4 | Register::CR8 | Register::DR0 | Register::DR1 | Register::DR2 |
+		Register::DR3 | Register::DR6 | Register::DR7 => OperandSize::Bit64,
 
 		Register::EAX | Register::EBX | Register::ECX | Register::EDX | Register::ESP |
 		Register::EBP | Register::ESI | Register::EDI | Register::R8D | Register::R9D |
@@ -85,6 +98,8 @@ pub enum Operand {
         index: Option<Register>,
         scale: Option<u8>,
         displacement: i32,
+        segment: Option<Register>, // Segment override prefix
+        address_size: OperandSize, // 16bit and 32bit addresses wrap around
     },
 }
 
@@ -110,10 +125,13 @@ impl fmt::Display for Operand {
         match *self {
             Operand::Register(ref register) => write!(f, "{}", register),
             Operand::Immediate(immediate) => write!(f, "$0x{:x}", immediate),
-            Operand::EffectiveAddress { displacement, .. } => match displacement.cmp(&0) {
-                std::cmp::Ordering::Less => write!(f, "-{:#x}{}", displacement.abs(), format_effective_address(self)),
-                std::cmp::Ordering::Greater => write!(f, "{:#x}{}", displacement, format_effective_address(self)),
-                std::cmp::Ordering::Equal => write!(f, "0x0{}", format_effective_address(self)),
+            Operand::EffectiveAddress { displacement, segment, .. } => {
+                if let Some(segment) = segment { write!(f, "{}:", segment)?; }
+                match displacement.cmp(&0) {
+                    std::cmp::Ordering::Less => write!(f, "-{:#x}{}", displacement.abs(), format_effective_address(self)),
+                    std::cmp::Ordering::Greater => write!(f, "{:#x}{}", displacement, format_effective_address(self)),
+                    std::cmp::Ordering::Equal => write!(f, "0x0{}", format_effective_address(self)),
+                }
             }
         }
     }
@@ -188,7 +206,7 @@ fn format_effective_address(op: &Operand) -> String {
     }
 }
 
-#[derive(Clone,Copy)]
+#[derive(Clone,Copy,Debug)]
 pub enum Opcode {
     Adc,
     Add,
@@ -201,6 +219,7 @@ pub enum Opcode {
     Btc,
     Call,
     Cld,
+    Cli,
     Cmova,
     Cmovae,
     Cmovb,
@@ -226,8 +245,12 @@ pub enum Opcode {
     Fsub,
     Fmul,
     Fdiv,
+    Hlt,
     Imul,
+    In,
+    Ins,
     Int,
+    Iret,
     Ja,
     Jae,
     Jb,
@@ -249,7 +272,14 @@ pub enum Opcode {
     Leave,
     Lidt,
     Lgdt,
+    Ltr,
+    Lldt,
+    Sldt,
+    Str,
+    Verr,
+    Verw,
     Mov,
+    MovCr,
     Movs,
     Movd,
     Movss,
@@ -258,26 +288,40 @@ pub enum Opcode {
     Nop,
     Or,
     Out,
+    Outs,
     Pop,
     Popf,
+    Popa,
     Push,
     Pushf,
+    Pusha,
+    Rdfsbase,
+    Rdgsbase,
     Rdmsr,
+    Rdtsc,
+    Rdtscp,
     RegisterOperation,
     Ret,
     Lret,
     Sbb,
     ShiftRotate,
     Std,
+    Sti,
     Stos,
     Sub,
+    Swapgs,
     Test,
+    Wrfsbase,
+    Wrgsbase,
     Wrmsr,
     Xor,
     Scas,
     Cmpxchg,
     Xchg,
     Syscall,
+    Sysenter,
+    Sysexit,
+    Sysret,
     Seto,
     Setno,
     Setb,
@@ -295,4 +339,12 @@ pub enum Opcode {
     Setle,
     Setg,
     Ud2,
+    Daa,
+    Das,
+    Aaa,
+    Aas,
+    Aam,
+    Aad,
+    Into,
+    Bound,
 }
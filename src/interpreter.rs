@@ -1,9 +1,20 @@
 use crate::instruction::{Operand, Operands, Register, Flags, Repeat, OperandSize, get_register_size};
 use crate::state::{State, Value::*};
+use crate::segment::{DescriptorTable, Mode};
+use crate::interrupt::vector::{DIVIDE_ERROR, OVERFLOW, BOUND_RANGE, INVALID_OPCODE, GENERAL_PROTECTION};
+use crate::state::msr;
+use crate::segment::Segment;
 
 impl State {
-	pub fn print(&self, instruction: &str) { if self.print_instructions { println!("{:<6}", instruction); } }
+	pub fn print(&self, instruction: &str) {
+		self.trace_disassembly(|| instruction.to_string());
+		if self.print_instructions { println!("{:<6}", instruction); }
+	}
 	pub fn print_size(&self, explicit_size: Option<OperandSize>, instruction: &str, operands: &Operands) {
+		self.trace_disassembly(|| {
+			let suffix = match explicit_size { Some(OperandSize::Bit8) => "b", Some(OperandSize::Bit16) => "w", Some(OperandSize::Bit32) => "l", Some(OperandSize::Bit64) => "q", _ => "" };
+			format!("{}{} {}", instruction, suffix, operands)
+		});
 		if !self.print_instructions { return; }
 		match explicit_size {
 			Some(size) => {
@@ -22,7 +33,24 @@ impl State {
 	}
 	pub fn print_no_size(&self, instruction: &str, op: &Operands) { self.print_size(None, instruction, op) }
 	pub fn print_(&self, instruction: &str, op: &Operands) { self.print_size(op.explicit_size, instruction, op) }
-	pub fn print_disp(&self, instruction: &str, op: &Operands) { if self.print_instructions { println!("{:<6} {}", instruction, op.fmt(self.rip)); } }
+	pub fn print_disp(&self, instruction: &str, op: &Operands) {
+		self.trace_disassembly(|| format!("{} {}", instruction, op.fmt(self.rip)));
+		if self.print_instructions { println!("{:<6} {}", instruction, op.fmt(self.rip)); }
+	}
+}
+
+// Privileged instructions #GP(0) outside ring 0
+fn privileged(state: &mut State) -> bool {
+    if state.cpl() == 0 { return true; }
+    state.fault(GENERAL_PROTECTION, Some(0));
+    false
+}
+
+// I/O sensitive instructions (cli, sti, in, out) #GP(0) when CPL > IOPL
+fn io_privileged(state: &mut State) -> bool {
+    if state.cpl() as i64 <= (state.rflags >> 12) & 0b11 { return true; }
+    state.fault(GENERAL_PROTECTION, Some(0));
+    false
 }
 
 fn jmp_iml(state: &mut State, op: &Operands) {
@@ -70,9 +98,9 @@ pub fn register_operation(state: &mut State, op: &Operands) {
         0 => inc(state, op),
         1 => dec(state, op),
         2 => call(state, op),
-        3 => call(state, op), // far call
+        3 => lcall(state, op),
         4 => jmp(state, op),
-        5 => jmp(state, op), // far jmp
+        5 => ljmp(state, op),
         6 => push(state, op),
         _ => unreachable!(),
     }
@@ -115,21 +143,40 @@ pub fn shift_rotate(state: &mut State, op: &Operands) {
 }
 
 pub fn stack_push<T>(state: &mut State, value: &T) {
-    state.rsp -= std::mem::size_of::<T>() as i64;
-    state.memory.write(state.rsp as u64, value);
+    state.stack_adjust(-(std::mem::size_of::<T>() as i64));
+    state.memory.write(state.stack_address(), value);
+}
+
+fn stack_push_size(state: &mut State, value: i64, operand_size: OperandSize) {
+    match operand_size {
+        OperandSize::Bit64 => stack_push(state, &value),
+        OperandSize::Bit32 => stack_push(state, &(value as u32)),
+        OperandSize::Bit16 => stack_push(state, &(value as u16)),
+        _ => unreachable!(),
+    }
 }
 
-pub fn stack_pop(state: &mut State) -> i64 {
-    let value = state.memory.read(state.rsp as u64);
-    state.rsp += 8;
+fn stack_pop_size(state: &mut State, operand_size: OperandSize) -> i64 {
+    let address = state.stack_address();
+    let (value, size) = match operand_size {
+        OperandSize::Bit64 => (state.memory.read::<i64>(address), 8),
+        OperandSize::Bit32 => (state.memory.read::<u32>(address) as i64, 4),
+        OperandSize::Bit16 => (state.memory.read::<u16>(address) as i64, 2),
+        _ => unreachable!(),
+    };
+    state.stack_adjust(size);
     value
 }
 
+// push, pop, call, ret without operands: 64bit unless decoded in 16/32bit code
+fn stack_size(op: &Operands) -> OperandSize { op.explicit_size.unwrap_or(OperandSize::Bit64) }
+
 // all other instructions
 pub fn push(state: &mut State, op: &Operands) {
     state.print_("push", &op);
     let value = state.get_value(&op.op(), op.size());
     match op.size() {
+        OperandSize::Bit16 => { stack_push(state, &(value as i16)) }
         OperandSize::Bit32 => { stack_push(state, &(value as i32)) }
         OperandSize::Bit64 => { stack_push(state, &value) }
         _ => panic!("Unsupported push value size"),
@@ -139,7 +186,7 @@ pub fn push(state: &mut State, op: &Operands) {
 pub fn pop(state: &mut State, op: &Operands) {
     state.print_("pop", &op);
     let first_operand = op.op();
-    let value = stack_pop(state);
+    let value = stack_pop_size(state, op.size());
     state.set_value(value, &first_operand, op.size());
 }
 
@@ -244,6 +291,7 @@ fn add_(state: &mut State, value0: i64, value1: i64, operand_size: OperandSize)
     };
     state.set_flag(Flags::Carry, carry);
     state.set_flag(Flags::Overflow, overflow);
+    state.set_flag(Flags::Adjust, (value0 ^ value1 ^ result) & 0x10 != 0);
 
     state.compute_flags(result, operand_size);
     result
@@ -259,15 +307,30 @@ pub fn add(state: &mut State, op: &Operands) {
     state.set_value(result, &second_operand, operand_size);
 }
 
-pub fn or(state: &mut State, op: &Operands) {
-    state.print_("or", &op);
+// and, or, xor: general purpose operands at the operand size, xmm operands as 128bit values
+fn bitwise(state: &mut State, op: &Operands, f: fn(u128, u128) -> u128) {
     let operand_size = op.size();
     let (first_operand, second_operand) = op.operands();
-    let value0 = state.get(&first_operand, operand_size);
-    let value1 = state.get(&second_operand, operand_size);
-    let result = value0.into():u128 | value1.into():u128;
-    state.compute_flags(result as i64, operand_size);
-    state.set(XMM(result), &second_operand, operand_size); // fixme
+    if let OperandSize::Bit128 = operand_size {
+        let value0 = state.get(&first_operand, operand_size);
+        let value1 = state.get(&second_operand, operand_size);
+        let result = f(value0.into():u128, value1.into():u128);
+        state.compute_flags(result as i64, operand_size);
+        state.set(XMM(result), &second_operand, operand_size);
+    } else {
+        let value0 = state.get_value(&first_operand, operand_size);
+        let value1 = state.get_value(&second_operand, operand_size);
+        let result = f(value0 as u128, value1 as u128) as i64;
+        state.compute_flags(result, operand_size);
+        state.set_value(result, &second_operand, operand_size);
+    }
+    state.set_flag(Flags::Carry, false);
+    state.set_flag(Flags::Overflow, false);
+}
+
+pub fn or(state: &mut State, op: &Operands) {
+    state.print_("or", &op);
+    bitwise(state, op, |a, b| a | b);
 }
 
 pub fn adc(state: &mut State, op: &Operands) {
@@ -301,6 +364,7 @@ fn sub__(state: &mut State, value0: i64, value1: i64, operand_size: OperandSize)
     };
     state.set_flag(Flags::Carry, carry);
     state.set_flag(Flags::Overflow, overflow);
+    state.set_flag(Flags::Adjust, (value0 ^ value1 ^ result) & 0x10 != 0);
     state.compute_flags(result, operand_size);
     result
 }
@@ -338,15 +402,7 @@ fn and_(state: &mut State, op: &Operands, set: bool) {
 
 pub fn and(state: &mut State, op: &Operands) {
     state.print_("and", &op);
-    let operand_size = op.size();
-    let (first_operand, second_operand) = op.operands();
-    let value0 = state.get(&first_operand, operand_size);
-    let value1 = state.get(&second_operand, operand_size);
-    let result = value0.into():u128 & value1.into():u128;
-    state.compute_flags(result as i64, operand_size);
-    state.set_flag(Flags::Carry, false);
-    state.set_flag(Flags::Overflow, false);
-    state.set(XMM(result), &second_operand, operand_size); // fixme
+    bitwise(state, op, |a, b| a & b);
 }
 
 pub fn sub(state: &mut State, op: &Operands) {
@@ -356,13 +412,7 @@ pub fn sub(state: &mut State, op: &Operands) {
 
 pub fn xor(state: &mut State, op: &Operands) {
     state.print_("xor", &op);
-    let operand_size = op.size();
-    let (first_operand, second_operand) = op.operands();
-    let value0 = state.get(&first_operand, operand_size);
-    let value1 = state.get(&second_operand, operand_size);
-    let result = value0.into():u128 ^ value1.into():u128;
-    state.compute_flags(result as i64, operand_size);
-    state.set(XMM(result), &second_operand, operand_size); // fixme
+    bitwise(state, op, |a, b| a ^ b);
 }
 
 pub fn cmp(state: &mut State, op: &Operands) {
@@ -373,7 +423,7 @@ pub fn cmp(state: &mut State, op: &Operands) {
 pub fn call(state: &mut State, op: &Operands) {
     state.print_("call", &op);
     let value = state.rip;
-    stack_push(state, &value);
+    stack_push_size(state, value, op.size());
     jmp_iml(state, op);
 }
 
@@ -383,7 +433,7 @@ pub fn lea(state: &mut State, op: &Operands) {
     let operand_size = op.size();
     match *first_operand {
         Operand::EffectiveAddress { .. } => {
-            let value = state.calculate_effective_address(&first_operand) as i64;
+            let value = state.calculate_offset(&first_operand) as i64;
             match *second_operand {
                 Operand::Register { .. } => {
                     state.set_value(value, &second_operand, operand_size)
@@ -818,7 +868,95 @@ pub fn idiv(state: &mut State, op: &Operands) {
 
 pub fn ud2(state: &mut State) {
     state.print("ud2");
-    panic!("");
+    state.fault(INVALID_OPCODE, None);
+}
+
+// Decimal adjust of packed BCD in AL after add (daa) or sub (das)
+fn decimal_adjust(state: &mut State, subtract: bool) {
+    let (al, carry) = (state.get_register_value(Register::AL) as u8, state.get_flag(Flags::Carry));
+    let adjust = al & 0xF > 9 || state.get_flag(Flags::Adjust);
+    let mut result = al;
+    let mut borrow = false;
+    if adjust {
+        if subtract { borrow = al < 6; result = result.wrapping_sub(6); } else { result = result.wrapping_add(6); }
+    }
+    let carry = al > 0x99 || carry;
+    if carry { result = if subtract { result.wrapping_sub(0x60) } else { result.wrapping_add(0x60) }; }
+    state.set_register_value(Register::AL, result as i64);
+    state.compute_flags(result as i64, OperandSize::Bit8);
+    state.set_flag(Flags::Adjust, adjust);
+    state.set_flag(Flags::Carry, carry || borrow);
+}
+
+pub fn daa(state: &mut State) {
+    state.print("daa");
+    decimal_adjust(state, false);
+}
+
+pub fn das(state: &mut State) {
+    state.print("das");
+    decimal_adjust(state, true);
+}
+
+// ASCII adjust of unpacked BCD in AX after add (aaa) or sub (aas)
+fn ascii_adjust(state: &mut State, subtract: bool) {
+    let ax = state.get_register_value(Register::AX) as u16;
+    let adjust = ax & 0xF > 9 || state.get_flag(Flags::Adjust);
+    let ax = match (adjust, subtract) {
+        (false, _) => ax,
+        (true, false) => ax.wrapping_add(0x106),
+        (true, true) => ax.wrapping_sub(6).wrapping_sub(0x100),
+    };
+    state.set_register_value(Register::AX, (ax & 0xFF0F) as i64);
+    state.set_flag(Flags::Adjust, adjust);
+    state.set_flag(Flags::Carry, adjust);
+}
+
+pub fn aaa(state: &mut State) {
+    state.print("aaa");
+    ascii_adjust(state, false);
+}
+
+pub fn aas(state: &mut State) {
+    state.print("aas");
+    ascii_adjust(state, true);
+}
+
+pub fn aam(state: &mut State, op: &Operands) {
+    state.print_("aam", &op);
+    let base = state.get_value(op.op(), OperandSize::Bit8) as u8;
+    if base == 0 { return state.fault(DIVIDE_ERROR, None); }
+    let al = state.get_register_value(Register::AL) as u8;
+    state.set_register_value(Register::AH, (al / base) as i64);
+    state.set_register_value(Register::AL, (al % base) as i64);
+    state.compute_flags((al % base) as i64, OperandSize::Bit8);
+}
+
+pub fn aad(state: &mut State, op: &Operands) {
+    state.print_("aad", &op);
+    let base = state.get_value(op.op(), OperandSize::Bit8) as u8;
+    let (al, ah) = (state.get_register_value(Register::AL) as u8, state.get_register_value(Register::AH) as u8);
+    let result = al.wrapping_add(ah.wrapping_mul(base));
+    state.set_register_value(Register::AX, result as i64);
+    state.compute_flags(result as i64, OperandSize::Bit8);
+}
+
+pub fn into(state: &mut State) {
+    state.print("into");
+    if state.get_flag(Flags::Overflow) { state.software_interrupt(OVERFLOW); }
+}
+
+// #BR when the signed index register is outside the lower and upper bounds in memory
+pub fn bound(state: &mut State, op: &Operands) {
+    state.print_("bound", &op);
+    let (bounds, index) = op.operands();
+    if !matches!(bounds, Operand::EffectiveAddress{..}) { return state.fault(INVALID_OPCODE, None); }
+    let address = state.calculate_effective_address(bounds);
+    let (index, lower, upper) = match op.size() {
+        OperandSize::Bit16 => (state.get_value(index, OperandSize::Bit16) as i16 as i64, state.memory.read_unaligned::<i16>(address) as i64, state.memory.read_unaligned::<i16>(address + 2) as i64),
+        _ => (state.get_value(index, OperandSize::Bit32) as i32 as i64, state.memory.read_unaligned::<i32>(address) as i64, state.memory.read_unaligned::<i32>(address + 4) as i64),
+    };
+    if index < lower || index > upper { state.fault(BOUND_RANGE, None); }
 }
 
 pub fn mul(state: &mut State, op: &Operands) {
@@ -925,30 +1063,108 @@ pub fn neg(state: &mut State, op: &Operands) {
     state.set_value(result, &first_operand, operand_size);
 }
 
-pub fn ret(state: &mut State) {
+pub fn ret(state: &mut State, op: &Operands) {
     state.print("ret");
-    state.rip = stack_pop(state);
+    state.rip = stack_pop_size(state, stack_size(op));
+}
+
+// m16:16, m16:32 or m16:64 memory operand
+fn far_pointer(state: &State, op: &Operands) -> (u16, i64) {
+    if let [Some(Operand::Immediate(selector)), Some(Operand::Immediate(offset)), _] = op.operands { return (selector as u16, offset); } // ptr16:16, ptr16:32
+    let address = state.calculate_effective_address(op.op());
+    match op.size() {
+        OperandSize::Bit64 => (state.memory.read_unaligned(address+8), state.memory.read_unaligned(address)),
+        OperandSize::Bit32 => (state.memory.read_unaligned(address+4), state.memory.read_unaligned::<u32>(address) as i64),
+        OperandSize::Bit16 => (state.memory.read_unaligned(address+2), state.memory.read_unaligned::<u16>(address) as i64),
+        _ => unreachable!(),
+    }
 }
 
-pub fn lret(state: &mut State) {
-    state.print("lret");
-    let value = stack_pop(state);
-    stack_pop(state); // Code segment
-    state.rip = value;
+// Loading CS switches the current privilege level (and mode). Returns false after a fault
+fn far_jump(state: &mut State, selector: u16, rip: i64) -> bool {
+    if !state.load_segment(Register::CS, selector) { return false; }
+    state.rip = rip;
+    true
 }
 
-pub fn leave(state: &mut State) {
+pub fn ljmp(state: &mut State, op: &Operands) {
+    state.print_("ljmp", &op);
+    let (selector, rip) = far_pointer(state, op);
+    far_jump(state, selector, rip);
+}
+
+pub fn lcall(state: &mut State, op: &Operands) {
+    state.print_("lcall", &op);
+    let operand_size = op.size();
+    let (selector, rip) = far_pointer(state, op);
+    let (cs, return_address, rsp) = (state.cs.selector, state.rip, state.rsp);
+    stack_push_size(state, cs as i64, operand_size);
+    stack_push_size(state, return_address, operand_size);
+    if !far_jump(state, selector, rip) { state.rsp = rsp; }
+}
+
+pub fn lret(state: &mut State, op: &Operands) {
+    state.print_("lret", &op);
+    let operand_size = op.size();
+    let original_rsp = state.rsp;
+    let rip = stack_pop_size(state, operand_size);
+    let selector = stack_pop_size(state, operand_size) as u16;
+    let cpl = state.cpl();
+    if !far_jump(state, selector, rip) { state.rsp = original_rsp; return; }
+    if state.cpl() > cpl { // Return to outer privilege level
+        let rsp = stack_pop_size(state, operand_size);
+        let ss = stack_pop_size(state, operand_size) as u16;
+        state.rsp = rsp;
+        state.load_segment(Register::SS, ss);
+    }
+}
+
+pub fn leave(state: &mut State, op: &Operands) {
     state.print("leave");
-    state.rsp = state.rbp;
-    state.rbp = stack_pop(state);
+    let operand_size = stack_size(op);
+    state.rsp = (state.rsp & !state.stack_mask()) | (state.rbp & state.stack_mask());
+    let value = stack_pop_size(state, operand_size);
+    let rbp = match operand_size { OperandSize::Bit16 => Register::BP, OperandSize::Bit32 => Register::EBP, _ => Register::RBP };
+    state.set_register_value(rbp, value);
 }
 
-pub fn pushf(state: &mut State) { let value = state.rflags; stack_push(state, &value); }
+pub fn pushf(state: &mut State, op: &Operands) { let value = state.rflags; stack_push_size(state, value, stack_size(op)); }
+
+// Flags loaded by popf and iret: IOPL is only restored at CPL 0, IF only when CPL <= IOPL
+fn writable_flags(state: &State) -> i64 {
+    let (cpl, iopl) = (state.cpl() as i64, (state.rflags >> 12) & 0b11);
+    if cpl == 0 { !0 } else if cpl <= iopl { !(0b11 << 12) } else { !(0b11 << 12 | Flags::Interrupt as i64) }
+}
 
-pub fn popf(state: &mut State) {
+pub fn popf(state: &mut State, op: &Operands) {
     state.print("popf");
-    let value = stack_pop(state);
-    state.rflags = value;
+    let operand_size = stack_size(op);
+    let value = stack_pop_size(state, operand_size);
+    // VM, VIF and VIP are unchanged and RF is cleared
+    let mask = writable_flags(state) & match operand_size { OperandSize::Bit16 => 0xFFFF, _ => !0x1B_0000 }; // RF, VM, VIF, VIP
+    state.rflags = (value & mask) | (state.rflags & !mask & !(Flags::Resume as i64));
+}
+
+pub fn pusha(state: &mut State, op: &Operands) {
+    state.print_("pusha", &op);
+    let operand_size = op.size();
+    let sp = state.rsp;
+    for &value in [state.rax, state.rcx, state.rdx, state.rbx, sp, state.rbp, state.rsi, state.rdi].iter() {
+        stack_push_size(state, value, operand_size);
+    }
+}
+
+pub fn popa(state: &mut State, op: &Operands) {
+    state.print_("popa", &op);
+    let operand_size = op.size();
+    let registers = match operand_size {
+        OperandSize::Bit16 => [Register::DI, Register::SI, Register::BP, Register::SP, Register::BX, Register::DX, Register::CX, Register::AX],
+        _ => [Register::EDI, Register::ESI, Register::EBP, Register::ESP, Register::EBX, Register::EDX, Register::ECX, Register::EAX],
+    };
+    for &register in registers.iter() {
+        let value = stack_pop_size(state, operand_size);
+        if !matches!(register, Register::SP | Register::ESP) { state.set_register_value(register, value); } // (E)SP is skipped
+    }
 }
 
 pub fn std(state: &mut State) {
@@ -961,67 +1177,73 @@ pub fn cld(state: &mut State) {
     state.set_flag(Flags::Direction, false);
 }
 
-fn repeat<F:Fn(&mut State)>(state: &mut State, op: &Operands, f: F) {
+// Count register of rep: CX, ECX or RCX as the address size of the string operands
+fn count_register(op: &Operands) -> Register {
+    let address_size = op.operands.iter().flatten().find_map(|operand| match *operand {
+        Operand::EffectiveAddress{ base: Some(register), .. } => Some(get_register_size(register)),
+        _ => None,
+    });
+    match address_size { Some(OperandSize::Bit16) => Register::CX, Some(OperandSize::Bit32) => Register::ECX, _ => Register::RCX }
+}
+
+// rep: repeats (r/e)cx times. repe/repne (compare: cmps, scas) also stop on the first mismatch/match
+fn repeat<F:Fn(&mut State)>(state: &mut State, op: &Operands, compare: bool, f: F) {
     match op.repeat {
         Repeat::None => f(state),
-        Repeat::Equal => loop {
-            let rcx = state.get_value(&Operand::Register(Register::RCX), OperandSize::Bit64);
-            if rcx == 0 { break; }
-            f(state);
-            if state.get_flag(Flags::Zero) { break; }
-            state.set_register_value(Register::RCX, rcx - 1);
-        },
-        _ => unimplemented!(),
+        Repeat::Equal | Repeat::NotEqual => {
+            let count = count_register(op);
+            loop {
+                let value = state.get_register_value(count);
+                if value == 0 { break; }
+                f(state);
+                state.set_register_value(count, value - 1);
+                if compare && state.get_flag(Flags::Zero) != matches!(op.repeat, Repeat::Equal) { break; }
+            }
+        }
     }
 }
 
-pub fn stos(_state: &mut State, _op: &Operands) {
-    unimplemented!();
-    /*repeat(state, op, |state| {
-        let size = match op.explicit_size.unwrap() {
-            OperandSize::Bit8 => { state.print("rep stos %al,%es:(%rdi)"); 1 },
-            OperandSize::Bit16 => { state.print("rep stos %ax,%es:(%rdi)"); 2 },
-            OperandSize::Bit32 => { state.print("rep stos %eax,%es:(%rdi)"); 4 },
-            OperandSize::Bit64 => { state.print("rep stos %rax,%es:(%rdi)"); 8 },
-        };
-        let to = state.get_value(&Operand::Register { register: Register::RDI }, OperandSize::Bit64);
-        let update = if state.get_flag(Flags::Direction) { -size } else { +size };
-        state.set_register_value(Register::RDI, (to + update) as i64);
-    })*/
+// Steps the (r/e)si or (r/e)di operand of a string instruction, wrapping at the address size
+fn string_advance(state: &mut State, operand: &Operand, operand_size: OperandSize) {
+    if let Operand::EffectiveAddress{ base: Some(register), .. } = *operand {
+        let size = operand_size.bytes() as i64;
+        let value = state.get_register_value(register) + if state.get_flag(Flags::Direction) { -size } else { size };
+        state.set_register_value(register, value);
+    }
+}
+
+pub fn stos(state: &mut State, op: &Operands) {
+    state.print_("stos", &op);
+    let operand_size = op.size();
+    repeat(state, op, false, |state: &mut State| {
+        let (register, destination) = op.operands();
+        let value = state.get_value(register, operand_size);
+        state.set_value(value, destination, operand_size);
+        string_advance(state, destination, operand_size);
+    });
 }
 
 pub fn movs(state: &mut State, op: &Operands) {
-    if let Repeat::Equal | Repeat::NotEqual = op.repeat { state.print("repe"); }
-    state.print("movs %ds:(%rsi),%es:(%rdi)");
-    repeat(state, op, |state: &mut State| {
-        let from = state.get_value(&Operand::Register(Register::RSI), OperandSize::Bit64) as u64;
-        let to = state.get_value(&Operand::Register(Register::RDI), OperandSize::Bit64) as u64;
-        let size = match op.explicit_size.expect("movs need an explicit_size") {
-            OperandSize::Bit64 => {state.memory.write(to, &state.memory.read::<u64>(from)); 8},
-            OperandSize::Bit32 => {state.memory.write(to, &state.memory.read::<u32>(from)); 4},
-            OperandSize::Bit16 => {state.memory.write(to, &state.memory.read::<u16>(from)); 2},
-            OperandSize::Bit8 =>   {state.memory.write(to, &state.memory.read::<u8  >(from)); 1},
-            _ => unreachable!(),
-        };
-        let update = if state.get_flag(Flags::Direction) { -size as i64 } else { size };
-        state.set_register_value(Register::RSI, from as i64 + update);
-        state.set_register_value(Register::RDI, to as i64 + update);
+    state.print_("movs", &op);
+    let operand_size = op.size();
+    repeat(state, op, false, |state: &mut State| {
+        let (source, destination) = op.operands();
+        let value = state.get_value(source, operand_size);
+        state.set_value(value, destination, operand_size);
+        string_advance(state, source, operand_size);
+        string_advance(state, destination, operand_size);
     });
 }
 
 pub fn scas(state: &mut State, op: &Operands) {
     state.print_("scas", &op);
-    repeat(state, op, |state: &mut State| {
-        let operand_size = op.size();
-        match operand_size {
-            OperandSize::Bit8 => (),
-            _ => panic!("scas: only 8bit values supported")
-        }
-        let (source_op, needle) = op.operands();
-        let source = state.get_value(&source_op, operand_size);
-        let needle = state.get_value(&needle, operand_size);
-        sub__(state, source, needle, operand_size);
-        state.set_register_value(Register::RDI, state.get_register_value(Register::RDI) + if state.get_flag(Flags::Direction) { -1 } else { 1 } );
+    let operand_size = op.size();
+    repeat(state, op, true, |state: &mut State| {
+        let (destination, register) = op.operands();
+        let value = state.get_value(destination, operand_size);
+        let needle = state.get_value(register, operand_size);
+        sub__(state, value, needle, operand_size);
+        string_advance(state, destination, operand_size);
     })
 }
 
@@ -1257,32 +1479,69 @@ pub fn setg(state: &mut State, op: &Operands) {
     set_byte(state, op, set);
 }
 
-pub fn out(state: &mut State) {
-    state.print("out   %al,(%dx)");
-    //let al = state.get_register_value(Register::AL);
-    //let dx = state.get_register_value(Register::DX);
-    //println!("AL: {:x}, DX: {:x}", al as u8, dx);
+pub fn in_(state: &mut State, op: &Operands) {
+    state.print_("in", &op);
+    if !io_privileged(state) { return; }
+    let operand_size = op.size();
+    let (port, register) = op.operands();
+    let port = state.get_value(port, OperandSize::Bit16) as u16;
+    let value = state.io.read(port, operand_size.bytes());
+    state.set_value(value as i64, register, operand_size);
+}
+
+pub fn out(state: &mut State, op: &Operands) {
+    state.print_("out", &op);
+    if !io_privileged(state) { return; }
+    let operand_size = op.size();
+    let (register, port) = op.operands();
+    let port = state.get_value(port, OperandSize::Bit16) as u16;
+    let value = state.get_value(register, operand_size) as u32;
+    state.io.write(port, operand_size.bytes(), value);
+}
+
+pub fn ins(state: &mut State, op: &Operands) {
+    state.print_("ins", &op);
+    if !io_privileged(state) { return; }
+    let operand_size = op.size();
+    repeat(state, op, false, |state: &mut State| {
+        let (port, destination) = op.operands();
+        let port = state.get_value(port, OperandSize::Bit16) as u16;
+        let value = state.io.read(port, operand_size.bytes());
+        state.set_value(value as i64, destination, operand_size);
+        string_advance(state, destination, operand_size);
+    });
+}
+
+pub fn outs(state: &mut State, op: &Operands) {
+    state.print_("outs", &op);
+    if !io_privileged(state) { return; }
+    let operand_size = op.size();
+    repeat(state, op, false, |state: &mut State| {
+        let (source, port) = op.operands();
+        let port = state.get_value(port, OperandSize::Bit16) as u16;
+        let value = state.get_value(source, operand_size) as u32;
+        state.io.write(port, operand_size.bytes(), value);
+        string_advance(state, source, operand_size);
+    });
 }
 
 pub fn wrmsr(state: &mut State) {
     state.print("wrmsr");
-    // save_state(state, "state.bin");
-    // panic!("machine state saved!");
-    // todo: ement instruction
+    if !privileged(state) { return; }
+    let ecx = state.get_register_value(Register::ECX) as u32;
+    if !state.msr_implemented(ecx) { return state.fault(GENERAL_PROTECTION, Some(0)); }
+    let value = ((state.rdx as u64) << 32) | (state.rax as u32 as u64);
+    state.write_msr(ecx, value);
 }
 
 pub fn rdmsr(state: &mut State) {
     state.print("rdmsr");
-    let ecx = state.get_register_value(Register::RCX);
-    match ecx {
-        0xC0000080 => {
-            state.set_register_value(Register::RAX, 0x500);
-            state.set_register_value(Register::RDX, 0x0);
-        }
-        _ => {
-            panic!("RDMSR: unsupported operand: {:x}", ecx);
-        }
-    }
+    if !privileged(state) { return; }
+    let ecx = state.get_register_value(Register::ECX) as u32;
+    if !state.msr_implemented(ecx) { return state.fault(GENERAL_PROTECTION, Some(0)); }
+    let value = state.read_msr(ecx) as i64;
+    state.set_register_value(Register::EAX, value);
+    state.set_register_value(Register::EDX, value >> 32);
 }
 
 pub fn bit_manipulation(state: &mut State, op: &Operands) {
@@ -1398,30 +1657,164 @@ pub fn xchg(state: &mut State, op: &Operands) {
     state.set_value(op1, &second_operand, operand_size);
 }
 
-pub fn syscall(_state: &mut State) {
-    unimplemented!();
-    /*let rax = state.get_register_value(Register::RAX);
+pub fn syscall(state: &mut State) {
+    state.print("syscall");
+    if state.system_mode {
+        state.rcx = state.rip;
+        state.r11 = state.rflags;
+        let selector = ((state.read_msr(msr::STAR) >> 32) as u16) & !0b11;
+        state.cs = Segment::flat_code(selector, 0, true);
+        state.ss = Segment::flat_data(selector + 8, 0);
+        state.rflags &= !(state.read_msr(msr::SFMASK) as i64);
+        state.rip = state.read_msr(msr::LSTAR) as i64;
+        return;
+    }
+    let rax = state.get_register_value(Register::RAX);
     let p1 = state.get_register_value(Register::RDI) as u64;
     let p2 = state.get_register_value(Register::RSI) as u64;
-    let p3 = state.get_register_value(Register::RDX) as u64;
+    // let p3 = state.get_register_value(Register::RDX) as u64;
     // let p4 = state.get_register_value(Register::RCX) as u64;
     // let p5 = state.get_register_value(Register::R8) as u64;
     // let p6 = state.get_register_value(Register::R9) as u64;
     match rax {
+        158 => { // arch_prctl
+            const ARCH_SET_GS : u64 = 0x1001;
+            const ARCH_SET_FS : u64 = 0x1002;
+            const ARCH_GET_FS : u64 = 0x1003;
+            const ARCH_GET_GS : u64 = 0x1004;
+            match p1 {
+                ARCH_SET_GS => state.gs.base = p2,
+                ARCH_SET_FS => state.fs.base = p2,
+                ARCH_GET_FS => state.memory.write_unaligned(p2, &state.fs.base),
+                ARCH_GET_GS => state.memory.write_unaligned(p2, &state.gs.base),
+                _ => panic!("arch_prctl: unsupported code: {:x}", p1),
+            }
+            state.rax = 0;
+        }
+        60 | 231 => { // exit, exit_group: ends execute
+            state.exit_code = Some(p1 as i32);
+            state.rip = !0;
+        }
+        228 => { // clock_gettime: every clock reads virtual time
+            let nanoseconds = state.nanoseconds();
+            state.memory.write_unaligned(p2, &[nanoseconds / 1_000_000_000, nanoseconds % 1_000_000_000]);
+            state.rax = 0;
+        }
         _ => panic!("unsupported syscall: {}", rax),
-    }*/
+    }
+}
+
+pub fn sysret(state: &mut State, op: &Operands) {
+    state.print_("sysret", &op);
+    if !privileged(state) { return; }
+    let selector = (state.read_msr(msr::STAR) >> 48) as u16;
+    let long = matches!(op.size(), OperandSize::Bit64);
+    state.cs = Segment::flat_code((if long { selector + 16 } else { selector }) | 3, 3, long);
+    state.ss = Segment::flat_data((selector + 8) | 3, 3);
+    state.rip = if long { state.rcx } else { state.rcx as u32 as i64 };
+    state.rflags = (state.r11 & 0x3C7FD7) | 2;
+}
+
+pub fn sysenter(state: &mut State) {
+    state.print("sysenter");
+    let selector = state.read_msr(msr::SYSENTER_CS) as u16 & !0b11;
+    if state.mode() == Mode::Real || selector == 0 { return state.fault(GENERAL_PROTECTION, Some(0)); }
+    // The kernel runs in 64bit mode only when long mode is active
+    let long = state.read_msr(msr::EFER) & msr::EFER_LMA != 0;
+    let mask = if long { !0 } else { 0xFFFF_FFFF };
+    state.cs = Segment::flat_code(selector, 0, long);
+    state.ss = Segment::flat_data(selector + 8, 0);
+    state.rsp = state.read_msr(msr::SYSENTER_ESP) as i64 & mask;
+    state.rip = state.read_msr(msr::SYSENTER_EIP) as i64 & mask;
+    state.set_flag(Flags::Interrupt, false);
+    state.set_flag(Flags::Virtual8086, false);
+}
+
+pub fn sysexit(state: &mut State, op: &Operands) {
+    state.print_("sysexit", &op);
+    if !privileged(state) { return; }
+    let selector = state.read_msr(msr::SYSENTER_CS) as u16;
+    if state.mode() == Mode::Real || selector & !0b11 == 0 { return state.fault(GENERAL_PROTECTION, Some(0)); }
+    let long = matches!(op.size(), OperandSize::Bit64);
+    state.cs = Segment::flat_code((selector + if long { 32 } else { 16 }) | 3, 3, long);
+    state.ss = Segment::flat_data((selector + if long { 40 } else { 24 }) | 3, 3);
+    (state.rip, state.rsp) = if long { (state.rdx, state.rcx) } else { (state.rdx as u32 as i64, state.rcx as u32 as i64) };
+}
+
+// CR4.TSD restricts the time stamp counter to CPL 0
+fn tsc_privileged(state: &mut State) -> bool {
+    if state.cr4 & (1 << 2) != 0 { privileged(state) } else { true }
+}
+
+pub fn rdtsc(state: &mut State) {
+    state.print("rdtsc");
+    if !tsc_privileged(state) { return; }
+    let tsc = state.tsc() as i64;
+    state.set_register_value(Register::EAX, tsc);
+    state.set_register_value(Register::EDX, tsc >> 32);
+}
+
+pub fn rdtscp(state: &mut State) {
+    state.print("rdtscp");
+    if !tsc_privileged(state) { return; }
+    let (tsc, aux) = (state.tsc() as i64, state.read_msr(msr::TSC_AUX) as i64);
+    state.set_register_value(Register::EAX, tsc);
+    state.set_register_value(Register::EDX, tsc >> 32);
+    state.set_register_value(Register::ECX, aux);
+}
+
+pub fn swapgs(state: &mut State) {
+    state.print("swapgs");
+    if !privileged(state) { return; }
+    std::mem::swap(&mut state.gs.base, &mut state.kernel_gs_base);
+}
+
+fn fsgsbase_value(value: i64, operand_size: OperandSize) -> i64 {
+    match operand_size {
+        OperandSize::Bit32 => value as u32 as i64,
+        OperandSize::Bit64 => value,
+        _ => unreachable!(),
+    }
+}
+
+pub fn rdfsbase(state: &mut State, op: &Operands) {
+    state.print_("rdfsbase", &op);
+    let value = fsgsbase_value(state.fs.base as i64, op.size());
+    state.set_value(value, op.op(), op.size());
+}
+
+pub fn rdgsbase(state: &mut State, op: &Operands) {
+    state.print_("rdgsbase", &op);
+    let value = fsgsbase_value(state.gs.base as i64, op.size());
+    state.set_value(value, op.op(), op.size());
+}
+
+pub fn wrfsbase(state: &mut State, op: &Operands) {
+    state.print_("wrfsbase", &op);
+    state.fs.base = fsgsbase_value(state.get_value(op.op(), op.size()), op.size()) as u64;
+}
+
+pub fn wrgsbase(state: &mut State, op: &Operands) {
+    state.print_("wrgsbase", &op);
+    state.gs.base = fsgsbase_value(state.get_value(op.op(), op.size()), op.size()) as u64;
+}
+
+fn read_descriptor_table(state: &State, op: &Operands) -> DescriptorTable {
+    let address = state.calculate_effective_address(op.op());
+    let base: u64 = state.memory.read_unaligned(address+2);
+    DescriptorTable{limit: state.memory.read_unaligned(address), base: if state.cs.long() { base } else { base as u32 as u64 }}
 }
 
 pub fn lgdt(state: &mut State, op: &Operands) {
     state.print_no_size("lgdt", &op);
-    let first_operand = op.op();
-    state.gdt = state.get_value(first_operand, OperandSize::Bit64);
+    if !privileged(state) { return; }
+    state.gdt = read_descriptor_table(state, op);
 }
 
 pub fn lidt(state: &mut State, op: &Operands) {
     state.print_no_size("lidt", &op);
-    let first_operand = op.op();
-    state.idt = state.get_value(first_operand, OperandSize::Bit64);
+    if !privileged(state) { return; }
+    state.idt = read_descriptor_table(state, op);
 }
 
 pub fn cpuid(state: &mut State) {
@@ -1439,7 +1832,7 @@ pub fn cpuid(state: &mut State) {
                         0 << 1 | // Virtual 8086 mode extensions (such as VIF, VIP, PIV)
                         0 << 2 | // Debugging extensions (CR4 bit 3)
                         1 << 3 | // Page Size Extension
-                        0 << 4 | // Time Stamp Counter
+                        1 << 4 | // Time Stamp Counter
                         1 << 5 | // Model-specific registers
                         1 << 6 | // Physical Address Extension
                         0 << 7 | //  Check Exception
@@ -1492,7 +1885,7 @@ pub fn cpuid(state: &mut State) {
                         0 << 21 | // x2APIC support
                         0 << 22 | // MOVBE instruction (big-endian)
                         0 << 23 | // POPCNT instruction
-                        0 << 24 | // APIC supports one-shot operation using a TSC deadline value
+                        1 << 24 | // APIC supports one-shot operation using a TSC deadline value
                         0 << 25 | // AES instruction set
                         0 << 26 | // XSAVE, XRESTOR, XSETBV, XGETBV
                         0 << 27 | // XSAVE enabled by OS
@@ -1516,13 +1909,113 @@ pub fn cpuid(state: &mut State) {
             state.set_register_value(Register::RAX, 0x663);
             state.set_register_value(Register::RBX, 0x0);
             state.set_register_value(Register::RCX, 0x5);
-            state.set_register_value(Register::RDX, 0x2193fbfd);
+            state.set_register_value(Register::RDX, 0x2993fbfd); // RDTSCP
         }
         _ => panic!("CPUID: unsupported input: {:x}", value),
     }
 }
 
-pub fn int(state: &mut State, _op: &Operands) {
-    state.print("int    $0x80");
-    unimplemented!();
+pub fn int(state: &mut State, op: &Operands) {
+    state.print_("int", &op);
+    let vector = state.get_value(op.op(), OperandSize::Bit8) as u8;
+    state.software_interrupt(vector);
+}
+
+pub fn iret(state: &mut State, op: &Operands) {
+    state.print_("iret", &op);
+    let operand_size = op.size();
+    let original_rsp = state.rsp;
+    let rip = stack_pop_size(state, operand_size);
+    let selector = stack_pop_size(state, operand_size) as u16;
+    let rflags = stack_pop_size(state, operand_size);
+    // 64bit mode always pops SS:RSP, protected mode only when returning to an outer privilege level
+    let mode = state.mode();
+    let stack = if mode == Mode::Long64 || (mode != Mode::Real && (selector & 0b11) as u8 > state.cpl()) {
+        Some((stack_pop_size(state, operand_size), stack_pop_size(state, operand_size) as u16))
+    } else { None };
+    let mask = writable_flags(state);
+    if !far_jump(state, selector, rip) { state.rsp = original_rsp; return; }
+    state.rflags = (rflags & mask) | (state.rflags & !mask);
+    if let Some((rsp, ss)) = stack {
+        state.rsp = rsp;
+        state.load_segment(Register::SS, ss);
+    }
+}
+
+pub fn ltr(state: &mut State, op: &Operands) {
+    state.print_("ltr", &op);
+    if !privileged(state) { return; }
+    let selector = state.get_value(op.op(), OperandSize::Bit16) as u16;
+    state.load_task_register(selector);
+}
+
+pub fn lldt(state: &mut State, op: &Operands) {
+    state.print_("lldt", &op);
+    if !privileged(state) { return; }
+    let selector = state.get_value(op.op(), OperandSize::Bit16) as u16;
+    state.load_ldt_register(selector);
+}
+
+pub fn sldt(state: &mut State, op: &Operands) {
+    state.print_("sldt", &op);
+    let selector = state.ldtr.selector;
+    state.set_value(selector as i64, op.op(), OperandSize::Bit16);
+}
+
+pub fn str_(state: &mut State, op: &Operands) {
+    state.print_("str", &op);
+    let selector = state.tr.selector;
+    state.set_value(selector as i64, op.op(), OperandSize::Bit16);
+}
+
+// verr, verw: ZF is set if the segment is readable (writable) at the current privilege level
+fn verify_segment(state: &mut State, op: &Operands, write: bool) {
+    let selector = state.get_value(op.op(), OperandSize::Bit16) as u16;
+    let accessible = match state.read_descriptor(selector) {
+        Some(descriptor) if selector & !0b11 != 0 => {
+            let segment = Segment::from_descriptor(selector, descriptor);
+            // Type bit 1: readable code or writable data. Data is always readable
+            let (code, conforming, bit1) = (segment.access & 0x8 != 0, segment.access & 0xC == 0xC, segment.access & 0x2 != 0);
+            let allowed = if write { !code && bit1 } else { !code || bit1 };
+            let privilege = (!write && conforming) || (segment.dpl() >= state.cpl() && segment.dpl() >= (selector & 0b11) as u8);
+            !segment.system() && privilege && allowed
+        }
+        _ => false,
+    };
+    state.set_flag(Flags::Zero, accessible);
+}
+
+pub fn verr(state: &mut State, op: &Operands) {
+    state.print_("verr", &op);
+    verify_segment(state, op, false);
+}
+
+pub fn verw(state: &mut State, op: &Operands) {
+    state.print_("verw", &op);
+    verify_segment(state, op, true);
+}
+
+pub fn mov_cr(state: &mut State, op: &Operands) {
+    state.print_("mov", &op);
+    if !privileged(state) { return; }
+    mov_(state, op);
+}
+
+pub fn cli(state: &mut State) {
+    state.print("cli");
+    if !io_privileged(state) { return; }
+    state.set_flag(Flags::Interrupt, false);
+}
+
+pub fn sti(state: &mut State) {
+    state.print("sti");
+    if !io_privileged(state) { return; }
+    state.interrupt_shadow = !state.get_flag(Flags::Interrupt);
+    state.set_flag(Flags::Interrupt, true);
+}
+
+pub fn hlt(state: &mut State) {
+    state.print("hlt");
+    if !privileged(state) { return; }
+    state.halted = true;
 }
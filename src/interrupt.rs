@@ -0,0 +1,180 @@
+use crate::{state::State, segment::{Segment, Mode}, instruction::{Flags, Register}, interpreter::stack_push};
+
+pub mod vector {
+	pub const DIVIDE_ERROR: u8 = 0;
+	pub const DEBUG: u8 = 1;
+	pub const BREAKPOINT: u8 = 3;
+	pub const OVERFLOW: u8 = 4;
+	pub const BOUND_RANGE: u8 = 5;
+	pub const INVALID_OPCODE: u8 = 6;
+	pub const DOUBLE_FAULT: u8 = 8;
+	pub const INVALID_TSS: u8 = 10;
+	pub const SEGMENT_NOT_PRESENT: u8 = 11;
+	pub const STACK_FAULT: u8 = 12;
+	pub const GENERAL_PROTECTION: u8 = 13;
+	pub const PAGE_FAULT: u8 = 14;
+}
+use vector::{DIVIDE_ERROR, DOUBLE_FAULT, INVALID_TSS, SEGMENT_NOT_PRESENT, STACK_FAULT, GENERAL_PROTECTION, PAGE_FAULT};
+
+// IDT gate descriptor: 16 byte in long mode, 8 byte in protected mode
+#[derive(Debug, Clone, Copy)]
+pub struct Gate {
+	pub offset: u64,
+	pub selector: u16,
+	pub ist: u8,
+	pub gate_type: u8, // 0xE: interrupt gate, 0xF: trap gate (0x6, 0x7: 16bit)
+	pub dpl: u8,
+	pub present: bool,
+}
+
+impl State {
+	// ltr: #GP for anything but an available TSS, #NP if it is not present
+	pub fn load_task_register(&mut self, selector: u16) {
+		let error_code = Some((selector & !0b11) as u32);
+		let low = match self.read_descriptor(selector) { Some(low) if selector & !0b11 != 0 && selector & 0b100 == 0 => low, _ => return self.fault(GENERAL_PROTECTION, error_code) };
+		let mut tr = Segment::from_descriptor(selector, low);
+		if !tr.system() || tr.access & 0xF != 0x9 { return self.fault(GENERAL_PROTECTION, error_code); }
+		if !tr.present() { return self.fault(SEGMENT_NOT_PRESENT, error_code); }
+		let high: u64 = self.memory.read_unaligned(self.gdt.base + (selector & !0b111) as u64 + 8);
+		tr.base |= (high & 0xFFFF_FFFF) << 32;
+		self.tr = tr;
+	}
+
+	// 16 byte gates in long mode, 8 byte 286/386 gates in protected mode. None outside the IDT limit
+	pub fn read_gate(&self, vector: u8) -> Option<Gate> {
+		let long = self.mode().long();
+		let offset = vector as u64 * if long { 16 } else { 8 };
+		if offset + if long { 15 } else { 7 } > self.idt.limit as u64 { return None; }
+		let low: u64 = self.memory.read_unaligned(self.idt.base + offset);
+		let high: u64 = if long { self.memory.read_unaligned(self.idt.base + offset + 8) } else { 0 };
+		Some(Gate{
+			offset: (low & 0xFFFF) | ((low >> 32) & 0xFFFF_0000) | (high << 32),
+			selector: (low >> 16) as u16,
+			ist: if long { ((low >> 32) & 0b111) as u8 } else { 0 },
+			gate_type: ((low >> 40) & 0xF) as u8,
+			dpl: ((low >> 45) & 0b11) as u8,
+			present: low & (1 << 47) != 0,
+		})
+	}
+
+	// A fault while delivering vector. It becomes a double fault if vector was a contributory fault or page fault
+	fn delivery_fault(&mut self, vector: u8, fault: u8, error_code: Option<u32>) {
+		if vector == DOUBLE_FAULT {
+			self.shutdown = true; // Triple fault
+		} else if matches!(vector, DIVIDE_ERROR | INVALID_TSS | SEGMENT_NOT_PRESENT | STACK_FAULT | GENERAL_PROTECTION | PAGE_FAULT) {
+			self.raise_exception(DOUBLE_FAULT, Some(0));
+		} else {
+			self.raise_exception(fault, error_code);
+		}
+	}
+
+	// Delivers an exception or interrupt through the IDT. self.rip is the return address
+	pub fn raise_exception(&mut self, vector: u8, error_code: Option<u32>) {
+		let mode = self.mode();
+		if mode == Mode::Real { return self.raise_real_mode_interrupt(vector); }
+		let long = mode.long();
+		let idt_error_code = Some(vector as u32 * 8 + 2);
+		let gate = match self.read_gate(vector) { Some(gate) => gate, None => return self.delivery_fault(vector, GENERAL_PROTECTION, idt_error_code) };
+		// Interrupt and trap gates: 0xE, 0xF and in protected mode also the 16bit 0x6, 0x7
+		if gate.gate_type & 0b0110 != 0b0110 || (long && gate.gate_type & 0x8 == 0) { return self.delivery_fault(vector, GENERAL_PROTECTION, idt_error_code); }
+		if !gate.present { return self.delivery_fault(vector, SEGMENT_NOT_PRESENT, idt_error_code); }
+		let selector_error_code = Some((gate.selector & !0b11) as u32);
+		let code = match self.read_descriptor(gate.selector) {
+			Some(descriptor) if gate.selector & !0b11 != 0 => Segment::from_descriptor(gate.selector, descriptor),
+			_ => return self.delivery_fault(vector, GENERAL_PROTECTION, selector_error_code),
+		};
+		let cpl = self.cpl();
+		let new_cpl = code.dpl();
+		// Must be a code segment at the same or an inner privilege level
+		if code.system() || code.access & 0x8 == 0 || new_cpl > cpl { return self.delivery_fault(vector, GENERAL_PROTECTION, selector_error_code); }
+		if !code.present() { return self.delivery_fault(vector, SEGMENT_NOT_PRESENT, selector_error_code); }
+		let (ss, rsp, rflags, cs, rip) = (self.ss.selector, self.rsp, self.rflags, self.cs.selector, self.rip);
+		let inner = new_cpl < cpl;
+		if long {
+			// Stack switch from the 64bit TSS
+			if gate.ist != 0 {
+				self.rsp = self.memory.read_unaligned(self.tr.base + 36 + 8*(gate.ist as u64-1));
+			} else if inner {
+				self.rsp = self.memory.read_unaligned(self.tr.base + 4 + 8*new_cpl as u64);
+			}
+			if inner { self.ss = Segment{selector: new_cpl as u16, ..Default::default()}; } // Null SS with RPL = new CPL
+			self.rsp &= !0xF;
+		} else if inner {
+			// Stack switch from the 32bit TSS: ESPn at 4 + 8n, SSn at 8 + 8n
+			let esp: u32 = self.memory.read_unaligned(self.tr.base + 4 + 8*new_cpl as u64);
+			let selector: u16 = self.memory.read_unaligned(self.tr.base + 8 + 8*new_cpl as u64);
+			let stack_error_code = Some((selector & !0b11) as u32);
+			let stack = match self.read_descriptor(selector) {
+				Some(descriptor) if selector & !0b11 != 0 => Segment::from_descriptor(selector, descriptor),
+				_ => return self.delivery_fault(vector, INVALID_TSS, stack_error_code),
+			};
+			if stack.system() || stack.dpl() != new_cpl { return self.delivery_fault(vector, INVALID_TSS, stack_error_code); }
+			if !stack.present() { return self.delivery_fault(vector, STACK_FAULT, stack_error_code); }
+			self.ss = stack;
+			self.rsp = esp as i64;
+		}
+		self.cs = Segment{selector: (gate.selector & !0b11) | new_cpl as u16, ..code};
+		// 64bit frames always include SS:RSP, protected mode frames only on a privilege level change
+		let mut frame = if long || inner { vec![ss as u64, rsp as u64] } else { Vec::new() };
+		frame.extend_from_slice(&[rflags as u64, cs as u64, rip as u64]);
+		frame.extend(error_code.map(|error_code| error_code as u64));
+		for &value in frame.iter() {
+			if long { stack_push(self, &value) }
+			else if gate.gate_type & 0x8 != 0 { stack_push(self, &(value as u32)) }
+			else { stack_push(self, &(value as u16)) }
+		}
+		self.rip = gate.offset as i64;
+		if gate.gate_type & 1 == 0 { self.set_flag(Flags::Interrupt, false); } // Interrupt gate
+		self.set_flag(Flags::Trap, false);
+		self.set_flag(Flags::NestedTask, false);
+		self.set_flag(Flags::Resume, false);
+	}
+
+	// IVT of 4 byte far pointers. Real mode exceptions have no error code
+	fn raise_real_mode_interrupt(&mut self, vector: u8) {
+		let offset: u16 = self.memory.read_unaligned(self.idt.base + vector as u64 * 4);
+		let selector: u16 = self.memory.read_unaligned(self.idt.base + vector as u64 * 4 + 2);
+		let (flags, cs, ip) = (self.rflags as u16, self.cs.selector, self.rip as u16);
+		for value in [flags, cs, ip].iter() { stack_push(self, value); }
+		self.load_segment(Register::CS, selector);
+		self.rip = offset as i64;
+		self.set_flag(Flags::Interrupt, false);
+		self.set_flag(Flags::Trap, false);
+	}
+
+	// Faults return to the faulting instruction
+	pub fn fault(&mut self, vector: u8, error_code: Option<u32>) {
+		self.rip = self.instruction_start;
+		self.raise_exception(vector, error_code);
+	}
+
+	// External interrupts are accepted between instructions while IF is set and wake the CPU from hlt
+	pub fn deliver_interrupt(&mut self) {
+		let interrupts_enabled = !std::mem::take(&mut self.interrupt_shadow) && self.get_flag(Flags::Interrupt);
+		if let Some(pit) = &self.pit { pit.borrow_mut().update(self.cycles, self.frequency); }
+		if let Some(keyboard) = &self.keyboard { keyboard.borrow_mut().update(); }
+		if let Some(rtc) = &self.rtc { rtc.borrow_mut().update(self.cycles, self.frequency); }
+		if let Some(serial) = &self.serial { serial.borrow_mut().update(); }
+		if let Some(vector) = self.update_apic(interrupts_enabled).or_else(|| self.update_pic(interrupts_enabled)) {
+			self.halted = false;
+			self.raise_exception(vector, None);
+		}
+	}
+
+	// Cycle count of the next timer interrupt
+	pub fn next_timer_event(&self) -> Option<u64> {
+		let apic = self.apic.as_ref().and_then(|apic| apic.borrow().next_timer_event());
+		let pit = self.pit.as_ref().and_then(|pit| pit.borrow().next_event());
+		let rtc = self.rtc.as_ref().and_then(|rtc| rtc.borrow().next_event());
+		apic.into_iter().chain(pit).chain(rtc).min()
+	}
+
+	// int n, int3: gate DPL must allow the current privilege level
+	pub fn software_interrupt(&mut self, vector: u8) {
+		if self.mode() == Mode::Real { return self.raise_real_mode_interrupt(vector); }
+		match self.read_gate(vector) {
+			Some(gate) if gate.dpl >= self.cpl() => self.raise_exception(vector, None),
+			_ => self.fault(GENERAL_PROTECTION, Some(vector as u32 * 8 + 2)),
+		}
+	}
+}
@@ -0,0 +1,43 @@
+use std::{ops::RangeInclusive, rc::Rc, cell::RefCell};
+
+// Device on the I/O port bus. size is 1, 2 or 4 bytes
+pub trait PortDevice {
+	fn read(&mut self, port: u16, size: usize) -> u32;
+	fn write(&mut self, port: u16, size: usize, value: u32);
+}
+
+fn mask(size: usize) -> u32 { (!0u64 >> (64 - 8*size)) as u32 }
+
+// Shared devices: the embedder keeps a handle to inspect or drive the device
+impl<T: PortDevice> PortDevice for Rc<RefCell<T>> {
+	fn read(&mut self, port: u16, size: usize) -> u32 { self.borrow_mut().read(port, size) }
+	fn write(&mut self, port: u16, size: usize, value: u32) { self.borrow_mut().write(port, size, value) }
+}
+
+#[derive(Default)]
+pub struct PortBus {
+	devices: Vec<(RangeInclusive<u16>, Box<dyn PortDevice>)>,
+}
+
+impl PortBus {
+	pub fn register(&mut self, ports: RangeInclusive<u16>, device: Box<dyn PortDevice>) {
+		assert!(!self.devices.iter().any(|(range, _)| range.start() <= ports.end() && ports.start() <= range.end()), "I/O ports {:x?} already registered", ports);
+		self.devices.push((ports, device));
+	}
+
+	fn device(&mut self, port: u16) -> Option<&mut Box<dyn PortDevice>> {
+		self.devices.iter_mut().find(|(range, _)| range.contains(&port)).map(|(_, device)| device)
+	}
+
+	// Unclaimed ports read as all ones and ignore writes
+	pub fn read(&mut self, port: u16, size: usize) -> u32 {
+		match self.device(port) {
+			Some(device) => device.read(port, size),
+			None => mask(size),
+		}
+	}
+
+	pub fn write(&mut self, port: u16, size: usize, value: u32) {
+		if let Some(device) = self.device(port) { device.write(port, size, value & mask(size)); }
+	}
+}
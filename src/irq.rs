@@ -0,0 +1,26 @@
+use std::{rc::Rc, cell::Cell};
+
+// Interrupt request line levels shared between devices and interrupt controllers (ISA IRQ 0-15, IOAPIC pins 16-23)
+#[derive(Clone, Default)]
+pub struct IrqLines(Rc<Cell<u32>>);
+
+impl IrqLines {
+	pub fn line(&self, irq: u8) -> Irq { Irq{lines: self.clone(), irq} }
+	pub fn levels(&self) -> u32 { self.0.get() }
+	pub fn level(&self, irq: u8) -> bool { self.levels() & (1 << irq) != 0 }
+	pub fn set(&self, irq: u8, level: bool) {
+		let levels = self.levels() & !(1 << irq);
+		self.0.set(if level { levels | (1 << irq) } else { levels });
+	}
+}
+
+// Interrupt output of a device
+#[derive(Clone)]
+pub struct Irq {
+	lines: IrqLines,
+	irq: u8,
+}
+
+impl Irq {
+	pub fn set(&self, level: bool) { self.lines.set(self.irq, level) }
+}
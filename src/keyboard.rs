@@ -0,0 +1,226 @@
+use std::{rc::Rc, cell::RefCell, collections::VecDeque};
+use crate::{state::State, io::PortDevice, irq::Irq};
+
+pub const I8042_DATA: u16 = 0x60;
+pub const I8042_COMMAND: u16 = 0x64;
+pub const KEYBOARD_IRQ: u8 = 1;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_SYSTEM: u8 = 1 << 2;
+const STATUS_COMMAND: u8 = 1 << 3;
+const STATUS_UNLOCKED: u8 = 1 << 4;
+const CONFIG_INTERRUPT: u8 = 1 << 0;
+const CONFIG_SYSTEM: u8 = 1 << 2;
+const CONFIG_DISABLE_KEYBOARD: u8 = 1 << 4;
+const CONFIG_TRANSLATE: u8 = 1 << 6;
+
+const ACK: u8 = 0xFA;
+const RESEND: u8 = 0xFE;
+const EXTENDED: u16 = 0xE000;
+
+// Set 2 make codes indexed by set 1 make code
+const SET2: [u8; 0x59] = [
+	0x00, 0x76, 0x16, 0x1E, 0x26, 0x25, 0x2E, 0x36, 0x3D, 0x3E, 0x46, 0x45, 0x4E, 0x55, 0x66, 0x0D,
+	0x15, 0x1D, 0x24, 0x2D, 0x2C, 0x35, 0x3C, 0x43, 0x44, 0x4D, 0x54, 0x5B, 0x5A, 0x14, 0x1C, 0x1B,
+	0x23, 0x2B, 0x34, 0x33, 0x3B, 0x42, 0x4B, 0x4C, 0x52, 0x0E, 0x12, 0x5D, 0x1A, 0x22, 0x21, 0x2A,
+	0x32, 0x31, 0x3A, 0x41, 0x49, 0x4A, 0x59, 0x7C, 0x11, 0x29, 0x58, 0x05, 0x06, 0x04, 0x0C, 0x03,
+	0x0B, 0x83, 0x0A, 0x01, 0x09, 0x77, 0x7E, 0x6C, 0x75, 0x7D, 0x7B, 0x6B, 0x73, 0x74, 0x79, 0x69,
+	0x72, 0x7A, 0x70, 0x71, 0x84, 0x00, 0x61, 0x78, 0x07,
+];
+
+// US layout: set 1 make code and shift for printable ASCII
+fn ascii_key(character: char) -> Option<(u16, bool)> {
+	const UNSHIFTED: &str = "\x00\x001234567890-=\x08\tqwertyuiop[]\n\x00asdfghjkl;'`\x00\\zxcvbnm,./";
+	const SHIFTED: &str = "\x00\x00!@#$%^&*()_+\x00\x00QWERTYUIOP{}\x00\x00ASDFGHJKL:\"~\x00|ZXCVBNM<>?";
+	if character == ' ' { return Some((0x39, false)); }
+	if character == '\x1b' { return Some((0x01, false)); }
+	if character == '\0' { return None; }
+	UNSHIFTED.chars().position(|c| c == character).map(|code| (code as u16, false))
+		.or_else(|| SHIFTED.chars().position(|c| c == character).map(|code| (code as u16, true)))
+}
+
+// Key names used by scripts: {Enter}, {F1}, {Up}, {Ctrl+C}
+pub fn key_code(name: &str) -> Option<u16> {
+	let code = match name.to_ascii_lowercase().as_str() {
+		"esc" | "escape" => 0x01, "backspace" => 0x0E, "tab" => 0x0F, "enter" | "return" => 0x1C, "space" => 0x39,
+		"ctrl" | "control" => 0x1D, "shift" => 0x2A, "rshift" => 0x36, "alt" => 0x38, "capslock" => 0x3A,
+		"numlock" => 0x45, "scrolllock" => 0x46,
+		"f11" => 0x57, "f12" => 0x58,
+		"rctrl" => EXTENDED | 0x1D, "ralt" => EXTENDED | 0x38,
+		"home" => EXTENDED | 0x47, "up" => EXTENDED | 0x48, "pageup" => EXTENDED | 0x49, "left" => EXTENDED | 0x4B,
+		"right" => EXTENDED | 0x4D, "end" => EXTENDED | 0x4F, "down" => EXTENDED | 0x50, "pagedown" => EXTENDED | 0x51,
+		"insert" => EXTENDED | 0x52, "delete" => EXTENDED | 0x53,
+		function if function.len() >= 2 && function.starts_with('f') && function[1..].parse::<u16>().map_or(false, |n| (1..=10).contains(&n)) => 0x3A + function[1..].parse::<u16>().unwrap(),
+		_ => {
+			let mut characters = name.chars();
+			match (characters.next(), characters.next()) {
+				(Some(character), None) => ascii_key(character.to_ascii_lowercase())?.0,
+				_ => return None,
+			}
+		}
+	};
+	Some(code)
+}
+
+// Presses and releases typing text, with shift for upper case and symbols
+fn type_events(text: &str, events: &mut Vec<(u16, bool)>) -> Result<(), String> {
+	for character in text.chars() {
+		let (code, shift) = ascii_key(character).ok_or_else(|| format!("keyboard: no key for {:?}", character))?;
+		if shift { events.push((0x2A, true)); }
+		events.extend([(code, true), (code, false)]);
+		if shift { events.push((0x2A, false)); }
+	}
+	Ok(())
+}
+
+// 8042 keyboard controller with an attached keyboard. No auxiliary (mouse) port
+pub struct I8042 {
+	config: u8,
+	output: Option<u8>,
+	output_read: bool, // The line stays low until the next update so the PIC sees a new edge
+	responses: VecDeque<u8>, // Controller and keyboard replies take precedence over keystrokes
+	keys: VecDeque<u8>,
+	controller_command: Option<u8>, // Waiting for its data byte
+	keyboard_command: Option<u8>,
+	last_write_command: bool,
+	scancode_set: u8,
+	scanning: bool,
+	irq: Irq,
+}
+
+impl I8042 {
+	pub fn new(irq: Irq) -> Self {
+		Self{config: CONFIG_INTERRUPT | CONFIG_SYSTEM | CONFIG_TRANSLATE, output: None, output_read: false, responses: VecDeque::new(), keys: VecDeque::new(),
+			controller_command: None, keyboard_command: None, last_write_command: false, scancode_set: 2, scanning: true, irq}
+	}
+
+	// Keystroke in the scancode set seen by the guest (set 1 when the controller translates)
+	fn key(&mut self, code: u16, pressed: bool) {
+		if code & EXTENDED != 0 { self.keys.push_back(0xE0); }
+		let code = code as u8;
+		if self.scancode_set == 1 || self.config & CONFIG_TRANSLATE != 0 {
+			self.keys.push_back(if pressed { code } else { code | 0x80 });
+		} else {
+			if !pressed { self.keys.push_back(0xF0); }
+			self.keys.push_back(SET2[code as usize]);
+		}
+	}
+
+	pub fn press(&mut self, code: u16) { self.key(code, true) }
+	pub fn release(&mut self, code: u16) { self.key(code, false) }
+	pub fn tap(&mut self, code: u16) { self.press(code); self.release(code); }
+
+	// Nothing is queued unless every character has a key
+	pub fn type_text(&mut self, text: &str) -> Result<(), String> {
+		let mut events = Vec::new();
+		type_events(text, &mut events)?;
+		for (code, pressed) in events { self.key(code, pressed); }
+		Ok(())
+	}
+
+	// Text with {Key} and {Modifier+Key} chords, e.g. "root{Enter}" or "{Ctrl+Alt+Delete}". Nothing is queued unless the whole script is valid
+	pub fn run_script(&mut self, script: &str) -> Result<(), String> {
+		let mut events = Vec::new();
+		let mut rest = script;
+		while let Some(start) = rest.find('{') {
+			type_events(&rest[..start], &mut events)?;
+			let end = start + rest[start..].find('}').ok_or("keyboard script: unterminated {")?;
+			let keys = rest[start+1..end].split('+').map(|name| key_code(name).ok_or_else(|| format!("keyboard script: unknown key {}", name))).collect::<Result<Vec<_>, _>>()?;
+			events.extend(keys.iter().map(|&key| (key, true)));
+			events.extend(keys.iter().rev().map(|&key| (key, false)));
+			rest = &rest[end+1..];
+		}
+		type_events(rest, &mut events)?;
+		for (code, pressed) in events { self.key(code, pressed); }
+		Ok(())
+	}
+
+	// Loads the output buffer once the guest has read the previous byte, so each byte is a new IRQ1 edge
+	pub fn update(&mut self) {
+		if std::mem::take(&mut self.output_read) { return; }
+		if self.output.is_none() {
+			let keyboard_enabled = self.scanning && self.config & CONFIG_DISABLE_KEYBOARD == 0;
+			self.output = self.responses.pop_front().or_else(|| if keyboard_enabled { self.keys.pop_front() } else { None });
+		}
+		self.irq.set(self.output.is_some() && self.config & CONFIG_INTERRUPT != 0);
+	}
+
+	fn respond(&mut self, bytes: &[u8]) {
+		self.responses.extend(bytes);
+		self.update();
+	}
+
+	fn controller(&mut self, command: u8) {
+		match command {
+			0x20 => { let config = self.config; self.respond(&[config]); }
+			0x60 | 0xD1 | 0xD2 => self.controller_command = Some(command),
+			0xA7 | 0xA8 => {} // Auxiliary port
+			0xA9 => self.respond(&[0xFF]), // No auxiliary port
+			0xAA => self.respond(&[0x55]), // Self test passed
+			0xAB => self.respond(&[0x00]), // Keyboard interface test passed
+			0xAD => self.config |= CONFIG_DISABLE_KEYBOARD,
+			0xAE => self.config &= !CONFIG_DISABLE_KEYBOARD,
+			0xD0 => self.respond(&[0b11]), // Output port: A20 enabled, not in reset
+			_ => {} // Pulse output lines (reset) is ignored
+		}
+	}
+
+	fn keyboard(&mut self, byte: u8) {
+		if let Some(command) = self.keyboard_command.take() {
+			match command {
+				0xF0 if byte == 0 => { let set = self.scancode_set; self.respond(&[ACK, set]); }
+				0xF0 if byte <= 3 => { self.scancode_set = byte; self.respond(&[ACK]); }
+				_ => self.respond(&[ACK]), // LEDs, typematic rate
+			}
+			return;
+		}
+		match byte {
+			0xED | 0xF0 | 0xF3 => { self.keyboard_command = Some(byte); self.respond(&[ACK]); }
+			0xEE => self.respond(&[0xEE]), // Echo
+			0xF2 => self.respond(&[ACK, 0xAB, if self.config & CONFIG_TRANSLATE != 0 { 0x41 } else { 0x83 }]),
+			0xF4 => { self.scanning = true; self.respond(&[ACK]); }
+			0xF5 => { self.scanning = false; self.respond(&[ACK]); }
+			0xF6 => { self.scanning = true; self.scancode_set = 2; self.respond(&[ACK]); }
+			0xFF => { self.keys.clear(); self.scancode_set = 2; self.scanning = true; self.respond(&[ACK, 0xAA]); } // Reset and self test
+			_ => self.respond(&[RESEND]),
+		}
+	}
+}
+
+impl PortDevice for I8042 {
+	fn read(&mut self, port: u16, _size: usize) -> u32 {
+		match port {
+			I8042_DATA => {
+				let byte = self.output.take().unwrap_or(0);
+				self.output_read = true;
+				self.irq.set(false);
+				byte as u32
+			}
+			_ => (STATUS_UNLOCKED | if self.output.is_some() { STATUS_OUTPUT_FULL } else { 0 } | if self.config & CONFIG_SYSTEM != 0 { STATUS_SYSTEM } else { 0 }
+				| if self.last_write_command { STATUS_COMMAND } else { 0 }) as u32,
+		}
+	}
+
+	fn write(&mut self, port: u16, _size: usize, value: u32) {
+		let value = value as u8;
+		self.last_write_command = port == I8042_COMMAND;
+		if port == I8042_COMMAND { return self.controller(value); }
+		match self.controller_command.take() {
+			Some(0x60) => { self.config = value; self.update(); }
+			Some(0xD1) => {} // Output port
+			Some(_) => self.respond(&[value]), // Write keyboard output buffer
+			None => self.keyboard(value),
+		}
+	}
+}
+
+impl State {
+	// The returned handle injects keystrokes
+	pub fn enable_keyboard(&mut self) -> Rc<RefCell<I8042>> {
+		let keyboard = Rc::new(RefCell::new(I8042::new(self.irq.line(KEYBOARD_IRQ))));
+		self.io.register(I8042_DATA..=I8042_DATA, Box::new(keyboard.clone()));
+		self.io.register(I8042_COMMAND..=I8042_COMMAND, Box::new(keyboard.clone()));
+		self.keyboard = Some(keyboard.clone());
+		keyboard
+	}
+}
@@ -1,28 +1,107 @@
 #![feature(destructuring_assignment, type_ascription)]
-mod memory; pub use memory::PAGE_SIZE;
+mod memory; pub use memory::{PAGE_SIZE, MmioDevice, Access, WatchHit, MemoryAccess};
 mod state; pub use state::State;
-mod instruction; use instruction::{Opcode, Operands};
+mod segment; pub use segment::{Segment, DescriptorTable, Mode};
+mod interrupt; pub use interrupt::{Gate, vector};
+mod io; pub use io::{PortBus, PortDevice};
+mod irq; pub use irq::{IrqLines, Irq};
+mod serial; pub use serial::{Uart, COM1, COM1_IRQ};
+mod apic; pub use apic::{LocalApic, IoApic, APIC_BASE, IOAPIC_BASE};
+mod pic; pub use pic::{Pic, PIC_MASTER, PIC_SLAVE};
+mod pit; pub use pit::{Pit, PIT, PIT_IRQ, PIT_HZ, SYSTEM_CONTROL_B};
+mod pci; pub use pci::{PciBus, PciDevice, PciConfig, BarKind, PCI_ECAM_BASE, PCI_MMIO_WINDOW, PCI_IO_WINDOW, PCI_IRQ_BASE};
+mod virtio; pub use virtio::{VirtioPci, VirtioDevice, Virtqueue, Chain};
+mod virtio_blk; pub use virtio_blk::{VirtioBlock, Storage, SECTOR_SIZE};
+mod virtio_net; pub use virtio_net::{VirtioNet, NetBackend, Loopback, loopback_pair, Pcap};
+mod display; pub use display::{Display, VBE_DISPI_INDEX, VBE_DISPI_DATA};
+mod keyboard; pub use keyboard::{I8042, key_code, I8042_DATA, I8042_COMMAND, KEYBOARD_IRQ};
+mod rtc; pub use rtc::{Rtc, RTC, RTC_IRQ};
+mod linux; pub use linux::{load_linux, KERNEL_BASE};
+mod elf; pub use elf::load_elf;
+mod multiboot; pub use multiboot::{load_multiboot, MULTIBOOT_MAGIC, MULTIBOOT2_MAGIC};
+mod symbols; pub use symbols::{Symbolizer, Location};
+mod unwind; pub use unwind::Unwinder;
+mod debug; pub use debug::StopReason;
+mod gdb; pub use gdb::GdbStub;
+mod trace; pub use trace::{Tracer, TraceSink, TraceRecord, TRACE_MAGIC};
+mod instruction;
 mod decoder; use decoder::decode;
 mod interpreter;
 mod dispatch; use dispatch::dispatch;
 
 impl State {
-	pub fn execute(&mut self) {
-		let mut instruction_cache = fnv::FnvHashMap::<u64,(Opcode, Operands, usize)>::default();
-		while self.rip != !0 {
-			let instruction_start = self.rip as u64;
-			let instruction = match instruction_cache.entry(instruction_start) {
-				std::collections::hash_map::Entry::Occupied(entry) => {
-					let instruction = entry.into_mut();
-					self.rip += instruction.2 as i64;
-					instruction
-				},
-				std::collections::hash_map::Entry::Vacant(slot) => {
-					let instruction = decode(&mut self.rip, &self.memory);
-					slot.insert((instruction.0, instruction.1, ((self.rip as u64) - instruction_start) as usize))
-				}
-			};
-			dispatch(self, instruction);
+	// Runs until a breakpoint, a watchpoint, the instruction limit, a triple fault or the end of the program
+	pub fn execute(&mut self) -> StopReason {
+		self.flush_instruction_cache(); // The host may have written code since the last call
+		loop {
+			// Resuming from a breakpoint executes its instruction
+			let (rip, resume) = (self.rip as u64, self.breakpoint_resume.take());
+			if self.breakpoints.contains(&rip) && resume != Some(rip) {
+				self.breakpoint_resume = Some(rip);
+				return StopReason::Breakpoint{address: rip};
+			}
+			if let Some(reason) = self.step() { return reason; }
+		}
+	}
+
+	// Retires one instruction, or skips idle time while halted. Returns why execution cannot continue
+	pub fn step(&mut self) -> Option<StopReason> {
+		if self.rip == !0 { return Some(StopReason::Exited); }
+		if self.instructions >= self.instruction_limit { return Some(StopReason::InstructionLimit); }
+		if self.shutdown { return Some(StopReason::TripleFault); }
+		self.update_pci();
+		self.deliver_interrupt();
+		if self.shutdown { return Some(StopReason::TripleFault); }
+		if self.halted {
+			// Skips idle time to the next timer event. Nothing can wake the CPU otherwise
+			match self.next_timer_event() {
+				Some(time) if self.get_flag(instruction::Flags::Interrupt) => { self.cycles = self.cycles.max(time); return None; }
+				_ => return Some(StopReason::Halted),
+			}
+		}
+		self.instructions += 1;
+		self.cycles += self.cycles_per_instruction;
+		let mode = self.mode();
+		if mode != self.instruction_cache_mode { self.flush_instruction_cache(); self.instruction_cache_mode = mode; } // Decoding depends on the mode
+		self.rip &= mode.ip_mask();
+		self.instruction_start = self.rip;
+		// CS:IP outside 64bit mode
+		let instruction_start = self.rip as u64 + if mode == Mode::Long64 { 0 } else { self.cs.base };
+		if self.debug_instruction_breakpoint(instruction_start) { return None; }
+		let instruction = match self.instruction_cache.get(&instruction_start) {
+			Some(instruction) => instruction.clone(),
+			None => {
+				let mut address = instruction_start as i64;
+				let instruction = decode(&mut address, &self.memory, mode);
+				self.memory.take_watch_hit(); // Instruction fetches are not data accesses
+				self.memory.take_debug_hits();
+				let length = (address as u64 - instruction_start) as usize;
+				let instruction = std::rc::Rc::new((instruction.0, instruction.1, length));
+				self.instruction_cache.insert(instruction_start, instruction.clone());
+				instruction
+			}
+		};
+		self.rip += instruction.2 as i64;
+		if self.print_instructions { print!("{}\t", (self.find_location)(instruction_start)); }
+		let resume = self.get_flag(instruction::Flags::Resume);
+		let trace = self.trace_begin(instruction_start, instruction.2, &instruction.1);
+		dispatch(self, &instruction);
+		if let Some(trace) = trace { self.trace_end(trace, || format!("{:?} {}", instruction.0, instruction.1).to_lowercase()); }
+		if resume { self.set_flag(instruction::Flags::Resume, false); } // RF suppresses instruction breakpoints for one instruction
+		self.debug_data_breakpoints();
+		if self.shutdown { return Some(StopReason::TripleFault); }
+		self.memory.take_watch_hit().map(StopReason::from)
+	}
+
+	// Code written by the host (loaders, debuggers) is decoded again
+	pub fn flush_instruction_cache(&mut self) { self.instruction_cache.clear(); }
+
+	// exit syscall, return value of a function, or 0 once a kernel halts
+	pub fn exit_status(&self) -> i32 {
+		match self.exit_code {
+			Some(code) => code,
+			None if self.rip == !0 => self.rax as i32,
+			None => 0,
 		}
 	}
 }
@@ -0,0 +1,110 @@
+use crate::{state::{State, msr}, segment::{Segment, DescriptorTable}, memory::from_raw};
+
+// Guest physical layout below the kernel
+const GDT: u64 = 0x500;
+const BOOT_PARAMS: u64 = 0x7000; // Zero page
+const PAGE_TABLES: u64 = 0x9000; // PML4, PDPT and 4 page directories
+const CMDLINE: u64 = 0x2_0000;
+pub const KERNEL_BASE: u64 = 0x10_0000;
+
+// Segment selectors of the 64bit boot protocol
+const BOOT_CS: u16 = 0x10;
+const BOOT_DS: u16 = 0x18;
+
+// boot_params offsets
+const E820_ENTRIES: u64 = 0x1E8;
+const SETUP_HEADER: usize = 0x1F1;
+const E820_TABLE: u64 = 0x2D0;
+const E820_RAM: u32 = 1;
+const E820_RESERVED: u32 = 2;
+
+// Setup header offsets (in the image and in boot_params)
+const SETUP_SECTS: usize = 0x1F1;
+const BOOT_FLAG: usize = 0x1FE;
+const HEADER: usize = 0x202;
+const VERSION: usize = 0x206;
+const TYPE_OF_LOADER: u64 = 0x210;
+const RAMDISK_IMAGE: u64 = 0x218;
+const RAMDISK_SIZE: u64 = 0x21C;
+const CMD_LINE_PTR: u64 = 0x228;
+const INITRD_ADDR_MAX: usize = 0x22C;
+const XLOADFLAGS: usize = 0x236;
+const CMDLINE_SIZE: usize = 0x238;
+const INIT_SIZE: usize = 0x260;
+const XLF_KERNEL_64: u16 = 1 << 0;
+
+fn get<T>(image: &[u8], offset: usize) -> T { from_raw(&image[offset..offset+std::mem::size_of::<T>()]) }
+
+// Identity maps the first 4GB with 2MB pages
+fn write_page_tables(state: &mut State) {
+	const PRESENT_WRITABLE: u64 = 0b11;
+	const HUGE: u64 = 1 << 7;
+	state.memory.write(PAGE_TABLES, &((PAGE_TABLES + 0x1000) | PRESENT_WRITABLE));
+	for directory in 0..4 {
+		let address = PAGE_TABLES + 0x2000 + directory * 0x1000;
+		state.memory.write(PAGE_TABLES + 0x1000 + directory * 8, &(address | PRESENT_WRITABLE));
+		for entry in 0..512 { state.memory.write(address + entry * 8, &((directory << 30) | (entry << 21) | HUGE | PRESENT_WRITABLE)); }
+	}
+}
+
+// Linux x86 boot protocol: allocates memory_size bytes of RAM, loads the protected mode kernel of a bzImage at 1MB
+// and enters its 64bit entry point with boot_params in rsi
+pub fn load_linux(state: &mut State, kernel: &[u8], initrd: Option<&[u8]>, cmdline: &str, memory_size: u64) {
+	assert!(kernel.len() > INIT_SIZE && get::<u16>(kernel, BOOT_FLAG) == 0xAA55 && &kernel[HEADER..HEADER+4] == b"HdrS", "Not a bzImage");
+	let version = get::<u16>(kernel, VERSION);
+	assert!(version >= 0x20C && get::<u16>(kernel, XLOADFLAGS) & XLF_KERNEL_64 != 0, "Boot protocol {:x} without a 64bit entry point", version);
+	let setup_sects = match kernel[SETUP_SECTS] { 0 => 4, sectors => sectors as usize };
+	let protected_mode_kernel = &kernel[(setup_sects + 1) * 512..];
+	let init_size = get::<u32>(kernel, INIT_SIZE) as u64;
+	assert!(KERNEL_BASE + init_size <= memory_size, "{} bytes of memory is too small for the kernel", memory_size);
+	state.memory.host_allocate_physical(0, memory_size as usize);
+	state.memory.write_unaligned_bytes(KERNEL_BASE, protected_mode_kernel);
+
+	// Zero page: the setup header is copied from the image then completed by the loader
+	state.memory.write_unaligned_bytes(BOOT_PARAMS, &[0; 0x1000]);
+	let setup_header_end = HEADER + kernel[0x201] as usize;
+	state.memory.write_unaligned_bytes(BOOT_PARAMS + SETUP_HEADER as u64, &kernel[SETUP_HEADER..setup_header_end]);
+	state.memory.write_byte(BOOT_PARAMS + TYPE_OF_LOADER, 0xFF); // Undefined loader
+
+	let cmdline_size = get::<u32>(kernel, CMDLINE_SIZE) as usize;
+	assert!(cmdline.len() <= cmdline_size, "Command line longer than {} bytes", cmdline_size);
+	state.memory.write_unaligned_bytes(CMDLINE, cmdline.as_bytes());
+	state.memory.write_byte(CMDLINE + cmdline.len() as u64, 0);
+	state.memory.write_unaligned(BOOT_PARAMS + CMD_LINE_PTR, &(CMDLINE as u32));
+
+	// The initramfs goes at the top of memory addressable by the kernel
+	if let Some(initrd) = initrd {
+		let top = memory_size.min(get::<u32>(kernel, INITRD_ADDR_MAX) as u64 + 1);
+		let address = top.checked_sub(initrd.len() as u64).map(|address| address & !0xFFF);
+		let address = address.filter(|&address| address >= KERNEL_BASE + init_size).unwrap_or_else(|| panic!("No room for a {} bytes initrd", initrd.len()));
+		state.memory.write_unaligned_bytes(address, initrd);
+		state.memory.write_unaligned(BOOT_PARAMS + RAMDISK_IMAGE, &(address as u32));
+		state.memory.write_unaligned(BOOT_PARAMS + RAMDISK_SIZE, &(initrd.len() as u32));
+	}
+
+	// Conventional memory, EBDA and BIOS area, extended memory
+	let e820 = [(0, 0x9_FC00, E820_RAM), (0x9_FC00, 0x400, E820_RESERVED), (0xF_0000, 0x1_0000, E820_RESERVED), (KERNEL_BASE, memory_size - KERNEL_BASE, E820_RAM)];
+	for (index, &(address, size, kind)) in e820.iter().enumerate() {
+		let entry = BOOT_PARAMS + E820_TABLE + index as u64 * 20;
+		state.memory.write_unaligned(entry, &address);
+		state.memory.write_unaligned(entry + 8, &size);
+		state.memory.write_unaligned(entry + 16, &kind);
+	}
+	state.memory.write_byte(BOOT_PARAMS + E820_ENTRIES, e820.len() as u8);
+
+	// 64bit mode with identity page tables and a GDT with __BOOT_CS and __BOOT_DS
+	let gdt: [u64; 4] = [0, 0, 0x00AF9A000000FFFF, 0x00CF92000000FFFF];
+	for (index, descriptor) in gdt.iter().enumerate() { state.memory.write(GDT + index as u64 * 8, descriptor); }
+	state.gdt = DescriptorTable{base: GDT, limit: (gdt.len() * 8 - 1) as u16};
+	write_page_tables(state);
+	state.cr3 = PAGE_TABLES as i64;
+	state.cr4 |= 1 << 5; // PAE
+	state.cr0 = 0x8000_0001; // PG, PE
+	state.write_msr(msr::EFER, msr::EFER_LME | msr::EFER_LMA);
+	state.cs = Segment::flat_code(BOOT_CS, 0, true);
+	let data = Segment::flat_data(BOOT_DS, 0);
+	(state.ds, state.es, state.ss) = (data, data, data);
+	state.rflags = 0x2; // Interrupts disabled
+	state.rsi = BOOT_PARAMS as i64;
+	state.rip = (KERNEL_BASE + 0x200) as i64;
+}
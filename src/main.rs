@@ -0,0 +1,216 @@
+use std::{fs, io::BufWriter, ops::Range, panic::{catch_unwind, AssertUnwindSafe}, process::exit};
+use x86emu::*;
+
+const USAGE: &str = "\
+Usage: x86emu [options] image [arguments...]
+  --loader flat|elf|linux|multiboot  Image format (default: elf)
+  --cpu emu                          Execution backend (only the interpreter)
+  --symbol name                      Calls this ELF function instead of the entry point
+  --memory size                      Guest RAM with an optional K, M or G suffix (default: 128M)
+  --cmdline string                   Kernel command line (default: the arguments)
+  --initrd file                      Linux initramfs
+  --module file                      Multiboot module, repeatable
+  --env name=value                   Guest environment variable, repeatable
+  --rtc-epoch seconds                RTC wall clock at reset in seconds since 1970 (default: 0)
+  --trace                            Prints each executed instruction
+  --trace-file path                  Writes a record of each retired instruction (rip, bytes, disassembly, registers, memory)
+  --trace-format jsonl|binary        Trace file format (default: jsonl)
+  --trace-range 0xstart..0xend       Only traces instructions in this address range
+  --trace-instructions first..end    Only traces these retired instructions (counted from 0)
+  --limit count                      Stops after count instructions with exit code 124
+  --gdb port|host:port|path          Waits for gdb on a localhost port, a TCP address or a Unix socket
+  --break symbol|0xaddress           Stops with a backtrace and exit code 133, repeatable";
+
+const LIMIT_EXIT_CODE: i32 = 124;
+const BREAK_EXIT_CODE: i32 = 128 + 5; // SIGTRAP
+const TRIPLE_FAULT_EXIT_CODE: i32 = 128 + 11; // SIGSEGV
+
+fn usage(error: &str) -> ! {
+	eprintln!("{}\n{}", error, USAGE);
+	exit(2)
+}
+
+fn read(path: &str) -> Vec<u8> { fs::read(path).unwrap_or_else(|error| usage(&format!("{}: {}", path, error))) }
+
+// start..end with hexadecimal (0x prefix) or decimal bounds
+fn parse_range(range: &str) -> Range<u64> {
+	let parse = |bound: &str| match bound.strip_prefix("0x") { Some(hex) => u64::from_str_radix(hex, 16).ok(), None => bound.parse().ok() };
+	match range.split_once("..").map(|(start, end)| (parse(start), parse(end))) {
+		Some((Some(start), Some(end))) => start..end,
+		_ => usage(&format!("Invalid range {}", range)),
+	}
+}
+
+fn parse_size(size: &str) -> u64 {
+	let (digits, unit) = match size.char_indices().last() {
+		Some((index, 'K')) | Some((index, 'k')) => (&size[..index], 1 << 10),
+		Some((index, 'M')) | Some((index, 'm')) => (&size[..index], 1 << 20),
+		Some((index, 'G')) | Some((index, 'g')) => (&size[..index], 1 << 30),
+		_ => (size, 1),
+	};
+	digits.parse::<u64>().map(|size| size * unit).unwrap_or_else(|_| usage(&format!("Invalid size {}", size)))
+}
+
+// System V process entry: argc, argv, envp and the auxiliary vector on the stack. Returns the argv and envp addresses
+fn push_process_arguments(state: &mut State, arguments: &[String], environment: &[String], entry: u64) -> (u64, u64) {
+	const AT_NULL: u64 = 0;
+	const AT_PAGESZ: u64 = 6;
+	const AT_ENTRY: u64 = 9;
+	const AT_RANDOM: u64 = 25;
+	fn push_bytes(state: &mut State, bytes: &[u8]) -> u64 {
+		state.rsp -= bytes.len() as i64;
+		state.memory.write_unaligned_bytes(state.rsp as u64, bytes);
+		state.rsp as u64
+	}
+	let random = push_bytes(state, &[0x5A; 16]); // Deterministic
+	let mut push_strings = |strings: &[String]| strings.iter().map(|string| push_bytes(state, format!("{}\0", string).as_bytes())).collect::<Vec<_>>();
+	let (argv, envp) = (push_strings(arguments), push_strings(environment));
+	let mut words = vec![argv.len() as u64];
+	words.extend(argv.iter().chain(&[0]).chain(envp.iter()).chain(&[0]));
+	words.extend_from_slice(&[AT_PAGESZ, PAGE_SIZE, AT_ENTRY, entry, AT_RANDOM, random, AT_NULL, 0]);
+	state.rsp &= !0xF;
+	if words.len() % 2 == 1 { state.rsp -= 8; } // argc is 16 byte aligned
+	for word in words.iter().rev() { stack_push(state, word); }
+	let argv = state.rsp as u64 + 8;
+	(argv, argv + 8 * (arguments.len() as u64 + 1))
+}
+
+// exit skips destructors
+fn flush_trace(state: &State) { if let Some(tracer) = &state.tracer { tracer.borrow_mut().flush(); } }
+
+// Interrupt controllers, timers, serial console, PCI and keyboard for kernels. The RTC starts at a fixed time so runs are reproducible
+fn enable_devices(state: &mut State, rtc_epoch: u64) {
+	state.enable_apic();
+	state.enable_pic();
+	state.enable_pit();
+	state.enable_rtc(rtc_epoch);
+	state.enable_serial(Box::new(std::io::stdout()), Some(Box::new(std::io::stdin())));
+	state.enable_pci();
+	state.enable_keyboard();
+}
+
+fn main() {
+	let (mut loader, mut symbol, mut memory_size, mut cmdline, mut initrd, mut rtc_epoch) = ("elf".to_string(), None, 128 << 20, None, None, 0);
+	let (mut modules, mut environment, mut trace, mut limit, mut gdb, mut breakpoints) = (Vec::new(), Vec::new(), false, !0, None, Vec::new());
+	let (mut trace_file, mut trace_format, mut trace_range, mut trace_instructions) = (None, "jsonl".to_string(), 0..!0, 0..!0);
+	let mut arguments = std::env::args().skip(1);
+	let image_path = loop {
+		let argument = arguments.next().unwrap_or_else(|| usage("Missing image"));
+		let mut value = || arguments.next().unwrap_or_else(|| usage(&format!("Missing value for {}", argument)));
+		match argument.as_str() {
+			"--loader" => loader = value(),
+			"--cpu" => if value() != "emu" { usage("The only CPU backend is emu") },
+			"--symbol" => symbol = Some(value()),
+			"--memory" => memory_size = parse_size(&value()),
+			"--cmdline" => cmdline = Some(value()),
+			"--initrd" => initrd = Some(value()),
+			"--module" => modules.push(value()),
+			"--env" => environment.push(value()),
+			"--rtc-epoch" => rtc_epoch = value().parse().unwrap_or_else(|_| usage("Invalid RTC epoch")),
+			"--trace" => trace = true,
+			"--trace-file" => trace_file = Some(value()),
+			"--trace-format" => trace_format = value(),
+			"--trace-range" => trace_range = parse_range(&value()),
+			"--trace-instructions" => trace_instructions = parse_range(&value()),
+			"--limit" => limit = value().parse().unwrap_or_else(|_| usage("Invalid instruction limit")),
+			"--gdb" => gdb = Some(value()),
+			"--break" => breakpoints.push(value()),
+			"--help" | "-h" => { println!("{}", USAGE); return; }
+			option if option.starts_with("--") => usage(&format!("Unknown option {}", option)),
+			_ => break argument,
+		}
+	};
+	let guest_arguments = std::iter::once(image_path.clone()).chain(arguments).collect::<Vec<_>>();
+	let cmdline = cmdline.unwrap_or_else(|| guest_arguments[1..].join(" "));
+	let image = read(&image_path);
+
+	let mut state = State::new();
+	// Kernels may be flat or bzImage
+	let symbolizer = Symbolizer::new(&image).map(|symbolizer| state.enable_symbols(symbolizer));
+	if let Some(unwinder) = Unwinder::new(&image) { state.enable_unwind(unwinder); }
+	match loader.as_str() {
+		"flat" => {
+			state.memory.host_allocate_physical(0, memory_size as usize);
+			load(&mut state, &image);
+			allocate_stack(&mut state);
+			stack_push(&mut state, &!0u64);
+		}
+		"elf" => {
+			state.memory.host_allocate_physical(0, memory_size as usize);
+			let (entry, _) = load_elf(&mut state, &image, false);
+			allocate_stack(&mut state);
+			match &symbol {
+				Some(symbol) => {
+					let function = symbolizer.as_ref().and_then(|symbolizer| symbolizer.symbol(symbol)).unwrap_or_else(|| usage(&format!("Unknown symbol {}", symbol)));
+					let (argv, envp) = push_process_arguments(&mut state, &guest_arguments, &environment, entry);
+					(state.rdi, state.rsi, state.rdx) = (guest_arguments.len() as i64, argv as i64, envp as i64);
+					stack_push(&mut state, &!0u64); // Returning ends execution
+					state.rip = function as i64;
+				}
+				None => {
+					push_process_arguments(&mut state, &guest_arguments, &environment, entry);
+					state.rip = entry as i64;
+				}
+			}
+		}
+		"linux" => {
+			let initrd = initrd.map(|path| read(&path));
+			load_linux(&mut state, &image, initrd.as_deref(), &cmdline, memory_size);
+			enable_devices(&mut state, rtc_epoch);
+		}
+		"multiboot" => {
+			let modules = modules.iter().map(|path| (read(path), path.as_str())).collect::<Vec<_>>();
+			let modules = modules.iter().map(|(data, path)| (data.as_slice(), *path)).collect::<Vec<_>>();
+			load_multiboot(&mut state, &image, &cmdline, &modules, memory_size);
+			enable_devices(&mut state, rtc_epoch);
+		}
+		loader => usage(&format!("Unknown loader {}", loader)),
+	}
+	state.print_instructions = trace;
+	if let Some(path) = trace_file {
+		let output = Box::new(BufWriter::new(fs::File::create(&path).unwrap_or_else(|error| usage(&format!("{}: {}", path, error)))));
+		let mut tracer = Tracer::new(match trace_format.as_str() {
+			"jsonl" => TraceSink::Jsonl(output),
+			"binary" => TraceSink::Binary(output),
+			format => usage(&format!("Unknown trace format {}", format)),
+		});
+		(tracer.addresses, tracer.instructions) = (trace_range, trace_instructions);
+		state.enable_trace(tracer);
+	}
+	state.instruction_limit = limit;
+	for breakpoint in breakpoints {
+		match breakpoint.strip_prefix("0x").map(|address| u64::from_str_radix(address, 16)) {
+			Some(Ok(address)) => state.add_breakpoint(address),
+			Some(Err(_)) => usage(&format!("Invalid address {}", breakpoint)),
+			None => { state.add_symbol_breakpoint(&breakpoint).unwrap_or_else(|| usage(&format!("Unknown symbol {}", breakpoint))); }
+		}
+	}
+	let mut gdb = gdb.map(|address| {
+		eprintln!("Waiting for gdb on {}", address);
+		GdbStub::listen(&address).unwrap_or_else(|error| usage(&format!("{}: {}", address, error)))
+	});
+	let run = || match &mut gdb {
+		// Detaching continues without the debugger. Killing ends execution
+		Some(gdb) => match gdb.run(&mut state) {
+			Ok(true) => state.execute(),
+			Ok(false) => StopReason::Exited,
+			Err(error) => { eprintln!("gdb: {}", error); StopReason::Exited }
+		},
+		None => state.execute(),
+	};
+	let reason = catch_unwind(AssertUnwindSafe(run)).unwrap_or_else(|_| {
+		eprint!("{}", state.format_backtrace(state.instruction_start as u64));
+		flush_trace(&state);
+		exit(101)
+	});
+
+	let code = match reason {
+		StopReason::Exited | StopReason::Halted => state.exit_status(),
+		reason => {
+			eprint!("{}\n{}", reason, state.format_backtrace(state.rip as u64));
+			match reason { StopReason::InstructionLimit => LIMIT_EXIT_CODE, StopReason::TripleFault => TRIPLE_FAULT_EXIT_CODE, _ => BREAK_EXIT_CODE }
+		}
+	};
+	flush_trace(&state);
+	exit(code)
+}
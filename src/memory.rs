@@ -1,3 +1,5 @@
+use std::{borrow::Cow, cell::{Cell, RefCell}, ops::Range, rc::Rc};
+
 pub fn raw<T>(value: &T) -> &[u8] { unsafe{std::slice::from_raw_parts((value as *const T) as *const u8, std::mem::size_of::<T>())} }
 pub fn raw_mut<T>(value: &mut std::mem::MaybeUninit<T>) -> &mut [u8] {
     unsafe{std::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, std::mem::size_of::<T>())}
@@ -11,9 +13,37 @@ pub fn from_raw<T>(raw : &[u8]) -> T {
 pub const PAGE_SIZE: u64 = 0x1000;
 fn is_aligned(virtual_address: u64, size: usize) -> bool { size.is_power_of_two() && virtual_address%(size as u64)==0 && size<=PAGE_SIZE as usize }
 
+// Device mapped in the physical address space. offset is relative to the start of the region, size is 1, 2, 4 or 8 bytes
+pub trait MmioDevice {
+    fn read(&mut self, offset: u64, size: usize) -> u64;
+    fn write(&mut self, offset: u64, size: usize, value: u64);
+}
+
+impl<T: MmioDevice> MmioDevice for Rc<RefCell<T>> {
+    fn read(&mut self, offset: u64, size: usize) -> u64 { self.borrow_mut().read(offset, size) }
+    fn write(&mut self, offset: u64, size: usize, value: u64) { self.borrow_mut().write(offset, size, value) }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access { Read, Write, ReadWrite }
+
+// First access to a watched range since the last take_watch_hit. value holds the low 8 bytes read or written
+#[derive(Debug, Clone, Copy)]
+pub struct WatchHit { pub address: u64, pub access: Access, pub value: u64 }
+
+// Data access of a traced instruction
+#[derive(Debug, Clone)]
+pub struct MemoryAccess { pub address: u64, pub access: Access, pub bytes: Vec<u8> }
+
 #[derive(Default)]
 pub struct Memory {
-    pub physical_to_host: fnv::FnvHashMap<u64, Vec<u8>>
+    pub physical_to_host: fnv::FnvHashMap<u64, Vec<u8>>,
+    mmio: Vec<(Range<u64>, RefCell<Box<dyn MmioDevice>>)>, // Reads through &self
+    watchpoints: Vec<(Range<u64>, Access)>,
+    watch_hit: Cell<Option<WatchHit>>,
+    debug_watchpoints: [Option<(Range<u64>, Access)>; 4], // Guest DR0-DR3 data breakpoints
+    debug_hits: Cell<u8>, // DR6 B0-B3
+    accesses: Option<RefCell<Vec<MemoryAccess>>>, // Recorded while tracing
 }
 
 impl Memory {
@@ -27,35 +57,124 @@ impl Memory {
         }
     }
 
-    fn try_read_aligned_physical(&self, physical_address: u64, size: usize) -> Option<&[u8]> {
+    // MMIO regions take precedence over RAM pages
+    pub fn register_mmio(&mut self, range: Range<u64>, device: Box<dyn MmioDevice>) {
+        assert!(!self.mmio.iter().any(|(region, _)| region.start < range.end && range.start < region.end), "MMIO {:x?} already registered", range);
+        self.mmio.push((range, RefCell::new(device)));
+    }
+
+    fn mmio(&self, physical_address: u64) -> Option<(u64, &RefCell<Box<dyn MmioDevice>>)> {
+        self.mmio.iter().find(|(region, _)| region.contains(&physical_address)).map(|(region, device)| (physical_address - region.start, device))
+    }
+
+    pub fn add_watchpoint(&mut self, range: Range<u64>, access: Access) { self.watchpoints.push((range, access)); }
+    pub fn remove_watchpoint(&mut self, range: Range<u64>, access: Access) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|watchpoint| watchpoint != &(range.clone(), access));
+        self.watchpoints.len() != count
+    }
+    pub fn take_watch_hit(&self) -> Option<WatchHit> { self.watch_hit.take() }
+
+    pub(crate) fn set_debug_watchpoints(&mut self, watchpoints: [Option<(Range<u64>, Access)>; 4]) { self.debug_watchpoints = watchpoints; }
+    pub(crate) fn take_debug_hits(&self) -> u8 { self.debug_hits.take() }
+
+    pub(crate) fn record_accesses(&mut self, record: bool) { self.accesses = if record { Some(Default::default()) } else { None }; }
+    pub(crate) fn take_accesses(&self) -> Vec<MemoryAccess> { self.accesses.as_ref().map(|accesses| accesses.take()).unwrap_or_default() }
+
+    fn watch(&self, virtual_address: u64, bytes: &[u8], access: Access) {
+        if let Some(accesses) = &self.accesses { accesses.borrow_mut().push(MemoryAccess{address: virtual_address, access, bytes: bytes.to_vec()}); }
+        let end = virtual_address + bytes.len() as u64;
+        for (index, watchpoint) in self.debug_watchpoints.iter().enumerate() {
+            if let Some((range, kind)) = watchpoint {
+                if (*kind == access || *kind == Access::ReadWrite) && range.start < end && virtual_address < range.end { self.debug_hits.set(self.debug_hits.get() | 1 << index); }
+            }
+        }
+        if self.watchpoints.is_empty() || self.watch_hit.get().is_some() { return; }
+        let watched = self.watchpoints.iter().find(|(range, kind)| (*kind == access || *kind == Access::ReadWrite) && range.start < end && virtual_address < range.end);
+        if let Some((range, _)) = watched {
+            let mut value = [0; 8];
+            let length = bytes.len().min(8);
+            value[..length].copy_from_slice(&bytes[..length]);
+            self.watch_hit.set(Some(WatchHit{address: virtual_address.max(range.start), access, value: u64::from_le_bytes(value)}));
+        }
+    }
+
+    // Debugger accesses: RAM only, without MMIO side effects or watchpoints
+    pub fn peek(&self, virtual_address: u64, size: usize) -> Option<Vec<u8>> {
+        (virtual_address..virtual_address+size as u64).map(|address| {
+            let page = self.physical_to_host.get(&(self.translate(address)/PAGE_SIZE))?;
+            Some(page[(self.translate(address)%PAGE_SIZE) as usize])
+        }).collect()
+    }
+    pub fn poke(&mut self, virtual_address: u64, bytes: &[u8]) -> bool {
+        if self.peek(virtual_address, bytes.len()).is_none() { return false; }
+        for (address, &byte) in (virtual_address..).zip(bytes) {
+            let physical_address = self.translate(address);
+            self.physical_to_host.get_mut(&(physical_address/PAGE_SIZE)).unwrap()[(physical_address%PAGE_SIZE) as usize] = byte;
+        }
+        true
+    }
+
+    fn try_read_aligned_physical(&self, physical_address: u64, size: usize) -> Option<Cow<'_, [u8]>> {
         assert!(is_aligned(physical_address, size), "unaligned read {:x} {}", physical_address, size);
+        if let Some((offset, device)) = self.mmio(physical_address) {
+            assert!(size <= 8, "MMIO read {:x} {}", physical_address, size);
+            return Some(Cow::Owned(device.borrow_mut().read(offset, size).to_le_bytes()[..size].to_vec()));
+        }
         let page = self.physical_to_host.get(&(physical_address/PAGE_SIZE))?;
         let offset = (physical_address%PAGE_SIZE) as usize;
-        Some(&page[offset..offset+size])
+        Some(Cow::Borrowed(&page[offset..offset+size]))
     }
 
-    pub fn try_read_aligned(&self, virtual_address: u64, size: usize) -> Option<&[u8]> {
+    pub fn try_read_aligned(&self, virtual_address: u64, size: usize) -> Option<Cow<'_, [u8]>> {
         self.try_read_aligned_physical(self.translate(virtual_address), size)
     }
-    fn read_aligned(&self, virtual_address: u64, size: usize) -> &[u8] {
+    fn read_aligned(&self, virtual_address: u64, size: usize) -> Cow<'_, [u8]> {
         self.try_read_aligned(virtual_address, size).unwrap_or_else(|| panic!("read {:x} {}", virtual_address, size))
     }
 
     pub fn write_aligned_bytes(&mut self, virtual_address: u64, bytes: &[u8]) {
         assert!(is_aligned(virtual_address, bytes.len()), "unaligned write {:x} {}", virtual_address, bytes.len());
+        self.watch(virtual_address, bytes, Access::Write);
         let physical_address = self.translate(virtual_address);
+        if let Some((offset, device)) = self.mmio(physical_address) {
+            assert!(bytes.len() <= 8, "MMIO write {:x} {}", physical_address, bytes.len());
+            let mut value = [0; 8];
+            value[..bytes.len()].copy_from_slice(bytes);
+            device.borrow_mut().write(offset, bytes.len(), u64::from_le_bytes(value));
+            return;
+        }
         let page = self.physical_to_host.get_mut(&(physical_address/PAGE_SIZE)).unwrap_or_else(|| panic!("write {:x} {}",physical_address, bytes.len()));
         let offset = (physical_address%PAGE_SIZE) as usize;
         page[offset..offset+bytes.len()].copy_from_slice(bytes);
     }
 
-    pub fn read_byte(&self, virtual_address: u64) -> u8 { self.read_aligned(virtual_address, 1)[0] }
+    pub fn read_byte(&self, virtual_address: u64) -> u8 {
+        let byte = self.read_aligned(virtual_address, 1)[0];
+        self.watch(virtual_address, &[byte], Access::Read);
+        byte
+    }
     pub fn write_byte(&mut self, virtual_address: u64, value: u8) { self.write_aligned_bytes(virtual_address, &[value]) }
 
-    pub fn read<T>(&self, virtual_address: u64) -> T { from_raw(self.read_aligned(virtual_address, std::mem::size_of::<T>())) }
+    pub fn read<T>(&self, virtual_address: u64) -> T {
+        let bytes = self.read_aligned(virtual_address, std::mem::size_of::<T>());
+        self.watch(virtual_address, &bytes, Access::Read);
+        from_raw(&bytes)
+    }
+
+    // Unaligned 1, 2, 4 or 8 byte MMIO accesses reach the device as a single access of exactly the requested bytes
+    fn mmio_unaligned(&self, virtual_address: u64, size: usize) -> Option<(u64, &RefCell<Box<dyn MmioDevice>>)> {
+        if !size.is_power_of_two() || size > 8 { return None; }
+        self.mmio(self.translate(virtual_address))
+    }
 
     pub fn read_unaligned<T>(&self, virtual_address: u64) -> T {
         let size = std::mem::size_of::<T>();
+        if let Some((offset, device)) = self.mmio_unaligned(virtual_address, size) {
+            let value = device.borrow_mut().read(offset, size).to_le_bytes();
+            self.watch(virtual_address, &value[..size], Access::Read);
+            return from_raw(&value[..size]);
+        }
         let line_size = size.next_power_of_two();
         let offset = (virtual_address%line_size as u64) as usize;
         let split = line_size-offset;
@@ -67,6 +186,7 @@ impl Memory {
             let line = self.read_aligned((virtual_address+size as u64)/line_size as u64*line_size as u64, line_size);
             raw_mut(&mut value)[split..].copy_from_slice(&line[..size-split]);
         }
+        self.watch(virtual_address, raw_mut(&mut value), Access::Read);
         unsafe{value.assume_init()}
     }
 }
@@ -83,11 +203,33 @@ impl Iterator for Bytes<'_> {
     }
 }
 impl Memory {
-    pub fn read_bytes(&self, virtual_address: u64, size: usize) -> Bytes { Bytes{memory: &self, virtual_address, size} }
+    pub fn read_bytes(&self, virtual_address: u64, size: usize) -> Bytes<'_> { Bytes{memory: self, virtual_address, size} }
     pub fn write<T>(&mut self, virtual_address: u64, value: &T) { self.write_aligned_bytes(virtual_address, raw(value)) }
 
     pub fn write_unaligned_bytes(&mut self, virtual_address: u64, bytes: &[u8]) {
-        for (offset, &byte) in bytes.iter().enumerate() { self.write_byte(virtual_address+offset as u64, byte); }
+        if is_aligned(virtual_address, bytes.len()) { return self.write_aligned_bytes(virtual_address, bytes); }
+        self.watch(virtual_address, bytes, Access::Write);
+        if let Some((offset, device)) = self.mmio_unaligned(virtual_address, bytes.len()) {
+            let mut value = [0; 8];
+            value[..bytes.len()].copy_from_slice(bytes);
+            return device.borrow_mut().write(offset, bytes.len(), u64::from_le_bytes(value));
+        }
+        // RAM is copied a page at a time, MMIO a byte at a time
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let address = virtual_address+offset as u64;
+            let length = ((PAGE_SIZE-address%PAGE_SIZE) as usize).min(bytes.len()-offset);
+            let physical_address = self.translate(address);
+            let ram = !self.mmio.iter().any(|(region, _)| region.start < physical_address+length as u64 && physical_address < region.end);
+            match self.physical_to_host.get_mut(&(physical_address/PAGE_SIZE)) {
+                Some(page) if ram => {
+                    let page_offset = (physical_address%PAGE_SIZE) as usize;
+                    page[page_offset..page_offset+length].copy_from_slice(&bytes[offset..offset+length]);
+                }
+                _ => for (index, &byte) in bytes[offset..offset+length].iter().enumerate() { self.write_byte(address+index as u64, byte); },
+            }
+            offset += length;
+        }
     }
     pub fn write_unaligned<T>(&mut self, virtual_address: u64, value: &T) { self.write_unaligned_bytes(virtual_address, raw(value)) }
 
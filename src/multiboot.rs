@@ -0,0 +1,206 @@
+use crate::{state::{State, msr}, segment::{Segment, DescriptorTable}, memory::{raw, from_raw, PAGE_SIZE}, elf::load_elf};
+
+pub const MULTIBOOT_MAGIC: u32 = 0x1BAD_B002;
+pub const MULTIBOOT2_MAGIC: u32 = 0xE852_50D6;
+const MULTIBOOT_BOOTLOADER_MAGIC: u32 = 0x2BAD_B002;
+const MULTIBOOT2_BOOTLOADER_MAGIC: u32 = 0x36D7_6289;
+
+// Guest physical layout below the kernel
+const GDT: u64 = 0x500;
+const INFO: u64 = 0x1_0000; // Multiboot information, lists and strings up to the EBDA
+const INFO_SIZE: usize = 0x8_0000;
+const LOADER_NAME: &str = "x86emu";
+
+// Multiboot 1 header flags
+const PAGE_ALIGN: u32 = 1 << 0;
+const MEMORY_INFO: u32 = 1 << 1;
+const VIDEO_MODE: u32 = 1 << 2;
+const ADDRESS_FIELDS: u32 = 1 << 16;
+
+// Multiboot 1 information flags
+const INFO_MEMORY: u32 = 1 << 0;
+const INFO_CMDLINE: u32 = 1 << 2;
+const INFO_MODULES: u32 = 1 << 3;
+const INFO_MEMORY_MAP: u32 = 1 << 6;
+const INFO_LOADER_NAME: u32 = 1 << 9;
+
+// Multiboot 2 tags
+mod tag {
+	pub const END: u16 = 0;
+	pub const INFORMATION_REQUEST: u16 = 1;
+	pub const ADDRESS: u16 = 2;
+	pub const ENTRY_ADDRESS: u16 = 3;
+	pub const CONSOLE_FLAGS: u16 = 4;
+	pub const FRAMEBUFFER: u16 = 5;
+	pub const MODULE_ALIGNMENT: u16 = 6;
+	pub const OPTIONAL: u16 = 1;
+	// Boot information
+	pub const CMDLINE: u32 = 1;
+	pub const LOADER_NAME: u32 = 2;
+	pub const MODULE: u32 = 3;
+	pub const BASIC_MEMORY_INFO: u32 = 4;
+	pub const MEMORY_MAP: u32 = 6;
+}
+
+const AVAILABLE: u32 = 1;
+const RESERVED: u32 = 2;
+
+fn get<T>(image: &[u8], offset: usize) -> T { from_raw(&image[offset..offset+std::mem::size_of::<T>()]) }
+
+// Header offset: 4 byte aligned in the first 8KB (multiboot 1), 8 byte aligned in the first 32KB (multiboot 2)
+fn find_header(image: &[u8], magic: u32, alignment: usize, search: usize, size: usize) -> Option<usize> {
+	(0..search.min(image.len().saturating_sub(size-1))).step_by(alignment).find(|&offset| {
+		get::<u32>(image, offset) == magic && (0..size/4).fold(0u32, |sum, index| sum.wrapping_add(get(image, offset+4*index))) == 0
+	})
+}
+
+// a.out kludge: the header gives the load addresses of the image
+struct Address { header: u32, load: u32, load_end: u32, bss_end: u32 }
+
+fn load_address(state: &mut State, image: &[u8], header_offset: usize, address: &Address) -> u64 {
+	let file_offset = header_offset - (address.header - address.load) as usize;
+	let load_end = if address.load_end == 0 { image.len() } else { file_offset + (address.load_end - address.load) as usize };
+	state.memory.write_unaligned_bytes(address.load as u64, &image[file_offset..load_end]);
+	let end = address.load as u64 + (load_end - file_offset) as u64;
+	if address.bss_end == 0 { return end; }
+	state.memory.write_unaligned_bytes(end, &vec![0; (address.bss_end as u64 - end) as usize]);
+	address.bss_end as u64
+}
+
+// Boot information built in host memory then copied to INFO
+#[derive(Default)]
+struct Info(Vec<u8>);
+impl Info {
+	fn address(&self) -> u32 { (INFO + self.0.len() as u64) as u32 }
+	fn push<T>(&mut self, value: T) -> u32 { let address = self.address(); self.0.extend_from_slice(raw(&value)); address }
+	fn push_str(&mut self, value: &str) -> u32 { let address = self.address(); self.0.extend_from_slice(value.as_bytes()); self.0.push(0); address }
+	fn set<T>(&mut self, address: u32, value: T) {
+		let offset = (address as u64 - INFO) as usize;
+		self.0[offset..offset+std::mem::size_of::<T>()].copy_from_slice(raw(&value));
+	}
+	fn align(&mut self, alignment: usize) { self.0.resize((self.0.len() + alignment - 1) / alignment * alignment, 0); }
+	// Multiboot2 tag: type, size, contents padded to 8 bytes
+	fn push_tag(&mut self, kind: u32, contents: impl FnOnce(&mut Self)) {
+		let tag = self.push(kind);
+		let size = self.push(0u32);
+		contents(self);
+		self.set(size, self.address() - tag);
+		self.align(8);
+	}
+}
+
+// Loads a Multiboot or Multiboot2 kernel, its modules (image, command line) and enters it in 32bit protected mode
+pub fn load_multiboot(state: &mut State, image: &[u8], cmdline: &str, modules: &[(&[u8], &str)], memory_size: u64) {
+	assert!(memory_size > 0x10_0000, "{} bytes of memory is too small", memory_size);
+	state.memory.host_allocate_physical(0, memory_size as usize);
+	let (multiboot2, entry, end) = if let Some(offset) = find_header(image, MULTIBOOT_MAGIC, 4, 8192, 12) {
+		let flags = get::<u32>(image, offset+4);
+		assert!(flags & 0xFFFF & !(PAGE_ALIGN | MEMORY_INFO | VIDEO_MODE) == 0, "Unsupported multiboot flags {:x}", flags);
+		if flags & ADDRESS_FIELDS != 0 {
+			let address = Address{header: get(image, offset+12), load: get(image, offset+16), load_end: get(image, offset+20), bss_end: get(image, offset+24)};
+			let end = load_address(state, image, offset, &address);
+			(false, get::<u32>(image, offset+28) as u64, end)
+		} else {
+			let (entry, end) = load_elf(state, image, true);
+			(false, entry, end)
+		}
+	} else if let Some(offset) = find_header(image, MULTIBOOT2_MAGIC, 8, 32768, 16) {
+		assert!(get::<u32>(image, offset+4) == 0, "Multiboot2 header is not for i386");
+		let header_end = offset + get::<u32>(image, offset+8) as usize;
+		let (mut address, mut entry) = (None, None);
+		let mut tag = offset + 16;
+		while tag + 8 <= header_end {
+			let (kind, flags, size) = (get::<u16>(image, tag), get::<u16>(image, tag+2), get::<u32>(image, tag+4) as usize);
+			match kind {
+				tag::END => break,
+				tag::ADDRESS => address = Some(Address{header: get(image, tag+8), load: get(image, tag+12), load_end: get(image, tag+16), bss_end: get(image, tag+20)}),
+				tag::ENTRY_ADDRESS => entry = Some(get::<u32>(image, tag+8) as u64),
+				tag::INFORMATION_REQUEST if flags & tag::OPTIONAL == 0 => {
+					for index in 0..(size-8)/4 {
+						let request = get::<u32>(image, tag+8+4*index);
+						assert!([tag::CMDLINE, tag::LOADER_NAME, tag::MODULE, tag::BASIC_MEMORY_INFO, tag::MEMORY_MAP].contains(&request), "Unsupported multiboot2 information request {}", request);
+					}
+				}
+				tag::INFORMATION_REQUEST | tag::CONSOLE_FLAGS | tag::MODULE_ALIGNMENT => {} // Modules are always page aligned
+				tag::FRAMEBUFFER if flags & tag::OPTIONAL != 0 => {}
+				_ => assert!(flags & tag::OPTIONAL != 0, "Unsupported multiboot2 tag {}", kind),
+			}
+			tag += (size + 7) & !7;
+		}
+		let (elf_entry, end) = match address {
+			Some(address) => (None, load_address(state, image, offset, &address)),
+			None => { let (entry, end) = load_elf(state, image, true); (Some(entry), end) }
+		};
+		(true, entry.or(elf_entry).expect("Multiboot2 kernel without an entry address"), end)
+	} else { panic!("No multiboot header") };
+
+	// Modules are page aligned after the kernel
+	let mut module_address = (end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+	let modules = modules.iter().map(|&(data, cmdline)| {
+		let start = module_address;
+		assert!(start + data.len() as u64 <= memory_size, "{} bytes of memory is too small for the modules", memory_size);
+		state.memory.write_unaligned_bytes(start, data);
+		module_address = (start + data.len() as u64 + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+		(start as u32, (start + data.len() as u64) as u32, cmdline)
+	}).collect::<Vec<_>>();
+
+	// Conventional memory, EBDA and BIOS area, extended memory
+	let memory_map: [(u64, u64, u32); 4] = [(0, 0x9_FC00, AVAILABLE), (0x9_FC00, 0x400, RESERVED), (0xF_0000, 0x1_0000, RESERVED), (0x10_0000, memory_size - 0x10_0000, AVAILABLE)];
+	let (memory_lower, memory_upper): (u32, u32) = (0x9_FC00 / 1024, ((memory_size - 0x10_0000) / 1024) as u32);
+
+	let mut info = Info::default();
+	if multiboot2 {
+		let start = info.push(0u32); // Total size
+		info.push(0u32);
+		info.push_tag(tag::CMDLINE, |info| { info.push_str(cmdline); });
+		info.push_tag(tag::LOADER_NAME, |info| { info.push_str(LOADER_NAME); });
+		for &(start, end, cmdline) in modules.iter() { info.push_tag(tag::MODULE, |info| { info.push(start); info.push(end); info.push_str(cmdline); }); }
+		info.push_tag(tag::BASIC_MEMORY_INFO, |info| { info.push(memory_lower); info.push(memory_upper); });
+		info.push_tag(tag::MEMORY_MAP, |info| {
+			info.push(24u32); // Entry size
+			info.push(0u32); // Entry version
+			for &(address, size, kind) in memory_map.iter() { info.push(address); info.push(size); info.push(kind); info.push(0u32); }
+		});
+		info.push(tag::END as u32);
+		info.push(8u32);
+		let total_size = info.address() - start;
+		info.set(start, total_size);
+		state.rax = MULTIBOOT2_BOOTLOADER_MAGIC as i64;
+	} else {
+		let start = info.push([0u32; 30]);
+		let field = |offset: u32| start + offset;
+		info.set(field(0), INFO_MEMORY | INFO_CMDLINE | INFO_MODULES | INFO_MEMORY_MAP | INFO_LOADER_NAME);
+		info.set(field(4), memory_lower);
+		info.set(field(8), memory_upper);
+		let address = info.push_str(cmdline);
+		info.set(field(16), address);
+		let address = info.push_str(LOADER_NAME);
+		info.set(field(64), address);
+		let strings = modules.iter().map(|&(_, _, cmdline)| info.push_str(cmdline)).collect::<Vec<_>>();
+		info.align(4);
+		let address = info.address();
+		for (&(start, end, _), &string) in modules.iter().zip(strings.iter()) { info.push([start, end, string, 0]); }
+		info.set(field(20), modules.len() as u32);
+		info.set(field(24), address);
+		let address = info.address();
+		for &(base, size, kind) in memory_map.iter() { info.push(20u32); info.push(base); info.push(size); info.push(kind); } // size excludes itself
+		info.set(field(44), info.address() - address);
+		info.set(field(48), address);
+		state.rax = MULTIBOOT_BOOTLOADER_MAGIC as i64;
+	}
+	assert!(info.0.len() <= INFO_SIZE, "Multiboot information overflow");
+	state.memory.write_unaligned_bytes(INFO, &info.0);
+	state.rbx = INFO as i64;
+
+	// 32bit protected mode without paging, flat segments
+	let gdt: [u64; 3] = [0, 0x00CF9A000000FFFF, 0x00CF92000000FFFF];
+	for (index, descriptor) in gdt.iter().enumerate() { state.memory.write(GDT + index as u64 * 8, descriptor); }
+	state.gdt = DescriptorTable{base: GDT, limit: (gdt.len() * 8 - 1) as u16};
+	state.cr0 = 0x1; // PE
+	state.write_msr(msr::EFER, 0);
+	state.cs = Segment::flat_code(0x08, 0, false);
+	let data = Segment::flat_data(0x10, 0);
+	(state.ds, state.es, state.fs, state.gs, state.ss) = (data, data, data, data, data);
+	state.rflags = 0x2; // Interrupts disabled
+	state.rip = entry as i64;
+}
@@ -0,0 +1,295 @@
+use std::{rc::Rc, cell::RefCell, ops::Range};
+use crate::{state::State, memory::{Memory, MmioDevice}, io::PortDevice, irq::{IrqLines, Irq}};
+
+pub const PCI_CONFIG_ADDRESS: u16 = 0xCF8;
+pub const PCI_CONFIG_DATA: u16 = 0xCFC;
+pub const PCI_ECAM_BASE: u64 = 0xB000_0000; // Bus 0 only: 32 devices * 8 functions * 4KB
+pub const PCI_MMIO_WINDOW: Range<u64> = 0xC000_0000..0xFEC0_0000;
+pub const PCI_IO_WINDOW: std::ops::RangeInclusive<u16> = 0xC000..=0xFFFF;
+pub const PCI_IRQ_BASE: u8 = 16; // INTA-D of slot n route to IOAPIC pins 16 + (n + pin) % 4
+
+pub mod command {
+	pub const IO: u16 = 1 << 0;
+	pub const MEMORY: u16 = 1 << 1;
+	pub const BUS_MASTER: u16 = 1 << 2;
+	pub const INTX_DISABLE: u16 = 1 << 10;
+}
+const STATUS_INTERRUPT: u16 = 1 << 3;
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BarKind { Io, Memory32, Memory64 }
+
+#[derive(Clone, Copy)]
+struct Bar {
+	size: u64,
+	kind: BarKind,
+}
+
+// Type 0 configuration header. Guest writes are masked by write_mask
+pub struct PciConfig {
+	pub data: [u8; 256],
+	pub write_mask: [u8; 256],
+	bars: [Option<Bar>; 6],
+	next_capability: usize,
+	irq: Option<Irq>,
+	interrupt: bool,
+}
+
+impl PciConfig {
+	// class: base class, subclass, programming interface
+	pub fn new(vendor: u16, device: u16, class: u32, revision: u8) -> Self {
+		let mut config = Self{data: [0; 256], write_mask: [0; 256], bars: [None; 6], next_capability: 0x40, irq: None, interrupt: false};
+		config.set_u16(0x00, vendor);
+		config.set_u16(0x02, device);
+		config.set_u32(0x08, class << 8 | revision as u32);
+		config.write_mask[0x04..0x06].copy_from_slice(&(command::IO | command::MEMORY | command::BUS_MASTER | command::INTX_DISABLE).to_le_bytes());
+		config.write_mask[0x0C] = 0xFF; // Cache line size
+		config.write_mask[0x0D] = 0xFF; // Latency timer
+		config.write_mask[0x3C] = 0xFF; // Interrupt line
+		config
+	}
+
+	pub fn get_u8(&self, offset: usize) -> u8 { self.data[offset] }
+	pub fn get_u16(&self, offset: usize) -> u16 { u16::from_le_bytes([self.data[offset], self.data[offset+1]]) }
+	pub fn get_u32(&self, offset: usize) -> u32 { u32::from_le_bytes([self.data[offset], self.data[offset+1], self.data[offset+2], self.data[offset+3]]) }
+	pub fn set_u8(&mut self, offset: usize, value: u8) { self.data[offset] = value; }
+	pub fn set_u16(&mut self, offset: usize, value: u16) { self.data[offset..offset+2].copy_from_slice(&value.to_le_bytes()); }
+	pub fn set_u32(&mut self, offset: usize, value: u32) { self.data[offset..offset+4].copy_from_slice(&value.to_le_bytes()); }
+
+	pub fn set_subsystem(&mut self, vendor: u16, id: u16) {
+		self.set_u16(0x2C, vendor);
+		self.set_u16(0x2E, id);
+	}
+
+	// size: power of two. 64bit BARs also take the next index
+	pub fn add_bar(&mut self, index: usize, size: u64, kind: BarKind) {
+		assert!(size.is_power_of_two() && size >= if kind == BarKind::Io { 4 } else { 16 }, "PCI BAR size {:x}", size);
+		let offset = 0x10 + index * 4;
+		let mask = !(size - 1);
+		let flags = match kind { BarKind::Io => 0b01, BarKind::Memory32 => 0b000, BarKind::Memory64 => 0b100 };
+		self.set_u32(offset, flags);
+		self.write_mask[offset..offset+4].copy_from_slice(&((mask as u32) & if kind == BarKind::Io { !0b11 } else { !0b1111 }).to_le_bytes());
+		if kind == BarKind::Memory64 {
+			self.write_mask[offset+4..offset+8].copy_from_slice(&((mask >> 32) as u32).to_le_bytes());
+		}
+		self.bars[index] = Some(Bar{size, kind});
+	}
+
+	// Current address of an enabled BAR
+	pub fn bar_address(&self, index: usize) -> Option<u64> {
+		let bar = self.bars[index]?;
+		let offset = 0x10 + index * 4;
+		let command = self.get_u16(0x04);
+		let (address, enabled) = match bar.kind {
+			BarKind::Io => ((self.get_u32(offset) & !0b11) as u64, command & command::IO != 0),
+			BarKind::Memory32 => ((self.get_u32(offset) & !0b1111) as u64, command & command::MEMORY != 0),
+			BarKind::Memory64 => ((self.get_u32(offset) & !0b1111) as u64 | (self.get_u32(offset+4) as u64) << 32, command & command::MEMORY != 0),
+		};
+		if enabled && address != 0 { Some(address) } else { None }
+	}
+
+	// Appends to the capability list. data starts after the ID and next pointer. Returns the capability offset
+	pub fn add_capability(&mut self, id: u8, data: &[u8]) -> usize {
+		let offset = self.next_capability;
+		assert!(offset + 2 + data.len() <= 256, "PCI capabilities overflow the configuration space");
+		// Link from the previous capability or the header
+		let mut link = 0x34;
+		while self.data[link] != 0 { link = self.data[link] as usize + 1; }
+		self.data[link] = offset as u8;
+		self.data[offset] = id;
+		self.data[offset+2..offset+2+data.len()].copy_from_slice(data);
+		self.set_u16(0x06, self.get_u16(0x06) | STATUS_CAPABILITIES);
+		self.next_capability = (offset + 2 + data.len() + 3) & !3;
+		offset
+	}
+
+	pub fn set_interrupt_pin(&mut self, pin: u8) { self.data[0x3D] = pin; } // 1-4: INTA-D
+
+	// INTx level, gated by the command register
+	pub fn set_interrupt(&mut self, level: bool) {
+		self.interrupt = level;
+		let status = self.get_u16(0x06) & !STATUS_INTERRUPT;
+		self.set_u16(0x06, if level { status | STATUS_INTERRUPT } else { status });
+		let disabled = self.get_u16(0x04) & command::INTX_DISABLE != 0;
+		if let Some(irq) = &self.irq { irq.set(level && !disabled); }
+	}
+
+	// DMA is only allowed while bus mastering is enabled
+	pub fn bus_master(&self) -> bool { self.get_u16(0x04) & command::BUS_MASTER != 0 }
+
+	pub fn read(&self, offset: usize, size: usize) -> u32 {
+		let mut bytes = [0; 4];
+		bytes[..size].copy_from_slice(&self.data[offset..offset+size]);
+		u32::from_le_bytes(bytes)
+	}
+
+	pub fn write(&mut self, offset: usize, size: usize, value: u32) {
+		for (i, byte) in value.to_le_bytes()[..size].iter().enumerate() {
+			let mask = self.write_mask[offset+i];
+			self.data[offset+i] = (self.data[offset+i] & !mask) | (byte & mask);
+		}
+		if offset <= 0x05 && offset + size > 0x04 { let level = self.interrupt; self.set_interrupt(level); } // INTx disable
+	}
+}
+
+pub trait PciDevice {
+	fn config(&mut self) -> &mut PciConfig;
+	// offset is relative to the BAR
+	fn read_bar(&mut self, _bar: usize, _offset: u64, size: usize) -> u64 { !0 >> (64 - 8*size) }
+	fn write_bar(&mut self, _bar: usize, _offset: u64, _size: usize, _value: u64) {}
+	// Between instructions: processes requests which need guest memory (DMA)
+	fn update(&mut self, _memory: &mut Memory) {}
+}
+
+struct HostBridge(PciConfig);
+impl PciDevice for HostBridge {
+	fn config(&mut self) -> &mut PciConfig { &mut self.0 }
+}
+
+// Bus 0, function 0 of each slot
+pub struct PciBus {
+	pub devices: Vec<Box<dyn PciDevice>>,
+	address: u32, // CONFIG_ADDRESS
+	irq: IrqLines,
+}
+
+impl PciBus {
+	pub fn new(irq: IrqLines) -> Self {
+		let mut bus = Self{devices: Vec::new(), address: 0, irq};
+		bus.add(Box::new(HostBridge(PciConfig::new(0x8086, 0x1237, 0x06_00_00, 2)))); // i440FX
+		bus
+	}
+
+	// Returns the slot
+	pub fn add(&mut self, mut device: Box<dyn PciDevice>) -> u8 {
+		let slot = self.devices.len() as u8;
+		assert!(slot < 32, "PCI bus 0 is full");
+		let config = device.config();
+		let pin = config.get_u8(0x3D);
+		if pin != 0 {
+			let irq = PCI_IRQ_BASE + (slot + pin - 1) % 4;
+			config.set_u8(0x3C, irq);
+			config.irq = Some(self.irq.line(irq));
+		}
+		self.devices.push(device);
+		slot
+	}
+
+	// address: device << 15 | function << 12 | register. Accesses are clipped at the end of the configuration space
+	fn read_config(&mut self, address: u32, size: usize) -> u32 {
+		let (slot, function, offset) = ((address >> 15) as usize & 0x1F, (address >> 12) & 0b111, address as usize & 0xFFF);
+		match self.devices.get_mut(slot) {
+			Some(device) if function == 0 && offset < 256 => device.config().read(offset, size.min(256 - offset)),
+			Some(_) if function == 0 => 0, // No extended capabilities
+			_ => !0 >> (32 - 8*size),
+		}
+	}
+
+	fn write_config(&mut self, address: u32, size: usize, value: u32) {
+		let (slot, function, offset) = ((address >> 15) as usize & 0x1F, (address >> 12) & 0b111, address as usize & 0xFFF);
+		if let Some(device) = self.devices.get_mut(slot) {
+			if function == 0 && offset < 256 { device.config().write(offset, size.min(256 - offset), value); }
+		}
+	}
+
+	// Device, BAR and offset decoding an address
+	fn bar(&mut self, address: u64, io: bool) -> Option<(&mut Box<dyn PciDevice>, usize, u64)> {
+		let (slot, index, base) = self.devices.iter_mut().enumerate().find_map(|(slot, device)| {
+			let config = device.config();
+			(0..6).find_map(|index| {
+				let (bar, base) = (config.bars[index]?, config.bar_address(index)?);
+				if (bar.kind == BarKind::Io) == io && (base..base+bar.size).contains(&address) { Some((slot, index, base)) } else { None }
+			})
+		})?;
+		Some((&mut self.devices[slot], index, address - base))
+	}
+}
+
+// CONFIG_ADDRESS, CONFIG_DATA and I/O BARs
+impl PortDevice for PciBus {
+	fn read(&mut self, port: u16, size: usize) -> u32 {
+		match port {
+			PCI_CONFIG_ADDRESS if size == 4 => self.address,
+			PCI_CONFIG_DATA..=0xCFF => {
+				let address = self.address;
+				if address & (1 << 31) == 0 || (address >> 16) & 0xFF != 0 { return !0 >> (32 - 8*size); }
+				let offset = port - PCI_CONFIG_DATA; // Within the 4 byte data window
+				self.read_config((address & 0xFF00) << 4 | (address & 0xFC) | offset as u32, size.min(4 - offset as usize))
+			}
+			0xCF8..=0xCFB => !0 >> (32 - 8*size),
+			_ => match self.bar(port as u64, true) {
+				Some((device, bar, offset)) => device.read_bar(bar, offset, size) as u32,
+				None => !0 >> (32 - 8*size),
+			},
+		}
+	}
+
+	fn write(&mut self, port: u16, size: usize, value: u32) {
+		match port {
+			PCI_CONFIG_ADDRESS if size == 4 => self.address = value & 0x80FF_FFFC,
+			PCI_CONFIG_DATA..=0xCFF => {
+				let address = self.address;
+				if address & (1 << 31) == 0 || (address >> 16) & 0xFF != 0 { return; }
+				let offset = port - PCI_CONFIG_DATA;
+				self.write_config((address & 0xFF00) << 4 | (address & 0xFC) | offset as u32, size.min(4 - offset as usize), value)
+			}
+			0xCF8..=0xCFB => {}
+			_ => if let Some((device, bar, offset)) = self.bar(port as u64, true) { device.write_bar(bar, offset, size, value as u64) },
+		}
+	}
+}
+
+// Enhanced configuration access mechanism (memory mapped configuration space)
+struct Ecam(Rc<RefCell<PciBus>>);
+// 8 byte accesses are split into two dword accesses
+impl MmioDevice for Ecam {
+	fn read(&mut self, offset: u64, size: usize) -> u64 {
+		let mut bus = self.0.borrow_mut();
+		if size == 8 { bus.read_config(offset as u32, 4) as u64 | (bus.read_config(offset as u32 + 4, 4) as u64) << 32 }
+		else { bus.read_config(offset as u32, size) as u64 }
+	}
+	fn write(&mut self, offset: u64, size: usize, value: u64) {
+		let mut bus = self.0.borrow_mut();
+		if size == 8 {
+			bus.write_config(offset as u32, 4, value as u32);
+			bus.write_config(offset as u32 + 4, 4, (value >> 32) as u32);
+		} else { bus.write_config(offset as u32, size, value as u32) }
+	}
+}
+
+// Memory BARs
+struct MmioWindow(Rc<RefCell<PciBus>>);
+impl MmioDevice for MmioWindow {
+	fn read(&mut self, offset: u64, size: usize) -> u64 {
+		match self.0.borrow_mut().bar(PCI_MMIO_WINDOW.start + offset, false) {
+			Some((device, bar, offset)) => device.read_bar(bar, offset, size),
+			None => !0 >> (64 - 8*size),
+		}
+	}
+	fn write(&mut self, offset: u64, size: usize, value: u64) {
+		if let Some((device, bar, offset)) = self.0.borrow_mut().bar(PCI_MMIO_WINDOW.start + offset, false) { device.write_bar(bar, offset, size, value) }
+	}
+}
+
+impl State {
+	pub fn enable_pci(&mut self) {
+		let pci = Rc::new(RefCell::new(PciBus::new(self.irq.clone())));
+		self.io.register(PCI_CONFIG_ADDRESS..=0xCFF, Box::new(pci.clone()));
+		self.io.register(PCI_IO_WINDOW, Box::new(pci.clone()));
+		self.memory.register_mmio(PCI_ECAM_BASE..PCI_ECAM_BASE+(1 << 20), Box::new(Ecam(pci.clone())));
+		self.memory.register_mmio(PCI_MMIO_WINDOW, Box::new(MmioWindow(pci.clone())));
+		self.pci = Some(pci);
+	}
+
+	// Returns the slot
+	pub fn add_pci_device(&mut self, device: Box<dyn PciDevice>) -> u8 {
+		self.pci.as_ref().expect("PCI is not enabled").borrow_mut().add(device)
+	}
+
+	pub fn update_pci(&mut self) {
+		if let Some(pci) = &self.pci {
+			for device in pci.borrow_mut().devices.iter_mut() { device.update(&mut self.memory); }
+		}
+	}
+}
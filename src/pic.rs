@@ -0,0 +1,131 @@
+use std::{rc::Rc, cell::RefCell};
+use crate::{state::State, io::PortDevice};
+
+pub const PIC_MASTER: u16 = 0x20;
+pub const PIC_SLAVE: u16 = 0xA0;
+const CASCADE: u8 = 2;
+
+// One 8259A: edge triggered, fixed priority (IR0 highest)
+#[derive(Default)]
+struct Chip {
+	irr: u8,
+	isr: u8,
+	imr: u8,
+	vector_base: u8,
+	initialization: Option<u8>, // Next expected ICW (2-4)
+	icw4: bool,
+	auto_eoi: bool,
+	read_isr: bool, // OCW3 register read select
+	levels: u8,
+}
+
+impl Chip {
+	fn update(&mut self, levels: u8) {
+		self.irr |= levels & !self.levels;
+		self.levels = levels;
+	}
+
+	// Highest priority request not masked and above the highest priority in service
+	fn pending(&self) -> Option<u8> {
+		let requests = self.irr & !self.imr;
+		if requests == 0 { return None; }
+		let irq = requests.trailing_zeros() as u8;
+		if self.isr != 0 && self.isr.trailing_zeros() as u8 <= irq { return None; }
+		Some(irq)
+	}
+
+	fn acknowledge(&mut self, irq: u8) -> u8 {
+		self.irr &= !(1 << irq);
+		if !self.auto_eoi { self.isr |= 1 << irq; }
+		self.vector_base + irq
+	}
+
+	fn read(&self, command: bool) -> u8 {
+		if !command { self.imr } else if self.read_isr { self.isr } else { self.irr }
+	}
+
+	fn write(&mut self, command: bool, value: u8) {
+		if command {
+			if value & 0x10 != 0 { // ICW1
+				*self = Self{levels: self.levels, icw4: value & 1 != 0, initialization: Some(2), ..Default::default()};
+			} else if value & 0x08 != 0 { // OCW3
+				if value & 0b10 != 0 { self.read_isr = value & 1 != 0; }
+			} else { // OCW2
+				match value >> 5 {
+					0b001 | 0b101 => if self.isr != 0 { self.isr &= self.isr - 1; }, // Non-specific EOI clears the highest priority
+					0b011 | 0b111 => self.isr &= !(1 << (value & 0b111)),
+					_ => {}
+				}
+			}
+		} else {
+			match self.initialization {
+				Some(2) => {
+					self.vector_base = value & 0xF8;
+					self.initialization = Some(3);
+				}
+				Some(3) => self.initialization = if self.icw4 { Some(4) } else { None }, // Cascade wiring is fixed
+				Some(4) => {
+					self.auto_eoi = value & 0b10 != 0;
+					self.initialization = None;
+				}
+				_ => self.imr = value, // OCW1
+			}
+		}
+	}
+}
+
+// Cascaded master and slave 8259A on IRQ 0-7 and 8-15
+#[derive(Default)]
+pub struct Pic {
+	master: Chip,
+	slave: Chip,
+}
+
+impl Pic {
+	pub fn update(&mut self, levels: u32) {
+		self.slave.update((levels >> 8) as u8);
+		let cascade = if self.slave.pending().is_some() { 1 << CASCADE } else { 0 };
+		self.master.update(levels as u8 & !(1 << CASCADE) | cascade);
+	}
+
+	// INTA: vector of the highest priority request
+	pub fn acknowledge(&mut self) -> Option<u8> {
+		let irq = self.master.pending()?;
+		if irq == CASCADE {
+			let slave_irq = self.slave.pending()?;
+			self.master.acknowledge(irq);
+			self.master.levels &= !(1 << CASCADE); // Next slave request is a new edge
+			Some(self.slave.acknowledge(slave_irq))
+		} else {
+			Some(self.master.acknowledge(irq))
+		}
+	}
+}
+
+impl PortDevice for Pic {
+	fn read(&mut self, port: u16, _size: usize) -> u32 {
+		let chip = if port & !1 == PIC_SLAVE { &self.slave } else { &self.master };
+		chip.read(port & 1 == 0) as u32
+	}
+
+	fn write(&mut self, port: u16, _size: usize, value: u32) {
+		let chip = if port & !1 == PIC_SLAVE { &mut self.slave } else { &mut self.master };
+		chip.write(port & 1 == 0, value as u8)
+	}
+}
+
+impl State {
+	pub fn enable_pic(&mut self) {
+		let pic = Rc::new(RefCell::new(Pic::default()));
+		self.io.register(PIC_MASTER..=PIC_MASTER+1, Box::new(pic.clone()));
+		self.io.register(PIC_SLAVE..=PIC_SLAVE+1, Box::new(pic.clone()));
+		self.pic = Some(pic);
+	}
+
+	// Samples the IRQ lines. Returns the vector accepted by the CPU if interrupts are enabled
+	pub fn update_pic(&mut self, interrupts_enabled: bool) -> Option<u8> {
+		let mut pic = self.pic.as_ref()?.borrow_mut();
+		pic.update(self.irq.levels());
+		if interrupts_enabled { pic.acknowledge() } else { None }
+	}
+}
@@ -0,0 +1,194 @@
+use std::{rc::Rc, cell::RefCell};
+use crate::{state::State, io::PortDevice, irq::Irq};
+
+pub const PIT: u16 = 0x40;
+pub const PIT_IRQ: u8 = 0;
+pub const SYSTEM_CONTROL_B: u16 = 0x61; // Channel 2 gate, speaker and OUT2
+pub const PIT_HZ: u64 = 1_193_182;
+
+fn ticks(cycles: u64, frequency: u64) -> u64 { (cycles as u128 * PIT_HZ as u128 / frequency as u128) as u64 }
+fn cycles(ticks: u64, frequency: u64) -> u64 { ((ticks as u128 * frequency as u128 + PIT_HZ as u128 - 1) / PIT_HZ as u128) as u64 }
+
+#[derive(Default, Clone, Copy)]
+struct Channel {
+	mode: u8,
+	access: u8, // 1: low byte, 2: high byte, 3: low then high byte
+	reload: u16,
+	count: u64, // 0 counts 0x10000
+	loaded: bool,
+	gate: bool,
+	elapsed: u64, // Ticks counted before counting_since
+	counting_since: Option<u64>,
+	latch: Option<u16>,
+	status: Option<u8>,
+	read_high: bool,
+	write_high: bool,
+}
+
+impl Channel {
+	fn elapsed(&self, now: u64) -> u64 { self.elapsed + self.counting_since.map_or(0, |since| now - since) }
+
+	fn output(&self, now: u64) -> bool {
+		if !self.loaded { return self.mode != 0; }
+		let (elapsed, count) = (self.elapsed(now), self.count);
+		match self.mode {
+			0 | 1 => elapsed >= count,
+			2 => elapsed % count != count - 1, // Low for one tick when the counter reaches 1
+			3 => elapsed % count < (count + 1) / 2, // Square wave
+			_ => elapsed != count, // Strobe
+		}
+	}
+
+	fn value(&self, now: u64) -> u16 {
+		if !self.loaded { return self.reload; }
+		let elapsed = self.elapsed(now);
+		match self.mode {
+			2 | 3 => (self.count - elapsed % self.count) as u16,
+			_ => (self.count.wrapping_sub(elapsed)) as u16,
+		}
+	}
+
+	// Ticks until the output changes
+	fn next_change(&self, now: u64) -> Option<u64> {
+		self.counting_since?;
+		let (elapsed, count) = (self.elapsed(now), self.count);
+		let phase = elapsed % count;
+		match self.mode {
+			0 | 1 => if elapsed < count { Some(count - elapsed) } else { None },
+			2 => if count == 1 { None } else if phase < count - 1 { Some(count - 1 - phase) } else { Some(1) },
+			3 => if phase < (count + 1) / 2 { Some((count + 1) / 2 - phase) } else { Some(count - phase) },
+			_ => if elapsed < count { Some(count - elapsed) } else if elapsed == count { Some(1) } else { None },
+		}
+	}
+
+	fn load(&mut self, now: u64) {
+		self.count = if self.reload == 0 { 0x10000 } else { self.reload as u64 };
+		self.loaded = true;
+		self.elapsed = 0;
+		// Modes 1 and 5 wait for a gate trigger
+		self.counting_since = if self.gate && self.mode != 1 && self.mode != 5 { Some(now) } else { None };
+	}
+
+	fn set_gate(&mut self, gate: bool, now: u64) {
+		if gate && !self.gate {
+			if matches!(self.mode, 1 | 2 | 3 | 5) { self.elapsed = 0; } // Retrigger
+			if self.loaded { self.counting_since = Some(now); }
+		} else if !gate && self.gate {
+			self.elapsed = self.elapsed(now);
+			self.counting_since = None;
+		}
+		self.gate = gate;
+	}
+
+	fn read(&mut self, now: u64) -> u8 {
+		if let Some(status) = self.status.take() { return status; }
+		let value = self.latch.unwrap_or_else(|| self.value(now));
+		let (byte, done) = match self.access {
+			1 => (value as u8, true),
+			2 => ((value >> 8) as u8, true),
+			_ => {
+				self.read_high = !self.read_high;
+				if self.read_high { (value as u8, false) } else { ((value >> 8) as u8, true) }
+			}
+		};
+		if done { self.latch = None; }
+		byte
+	}
+
+	fn write(&mut self, value: u8, now: u64) {
+		match self.access {
+			1 => { self.reload = value as u16; self.load(now); }
+			2 => { self.reload = (value as u16) << 8; self.load(now); }
+			_ => {
+				self.write_high = !self.write_high;
+				if self.write_high { self.reload = (self.reload & 0xFF00) | value as u16; }
+				else { self.reload = (self.reload & 0x00FF) | (value as u16) << 8; self.load(now); }
+			}
+		}
+	}
+}
+
+// 8254 programmable interval timer. Channel 0 drives IRQ0, channel 2 is gated by port 0x61
+pub struct Pit {
+	channels: [Channel; 3],
+	speaker: bool,
+	now: u64, // PIT ticks
+	frequency: u64, // CPU cycles per second
+	irq: Irq,
+}
+
+impl Pit {
+	pub fn new(irq: Irq) -> Self {
+		let channel = Channel{gate: true, access: 3, ..Default::default()};
+		Self{channels: [channel, channel, Channel{gate: false, ..channel}], speaker: false, now: 0, frequency: 1, irq}
+	}
+
+	pub fn update(&mut self, cycles: u64, frequency: u64) {
+		(self.now, self.frequency) = (ticks(cycles, frequency), frequency);
+		self.irq.set(self.channels[0].output(self.now));
+	}
+
+	// Cycle count of the next IRQ0 edge
+	pub fn next_event(&self) -> Option<u64> {
+		self.channels[0].next_change(self.now).map(|ticks| cycles(self.now + ticks, self.frequency))
+	}
+
+	fn control(&mut self, value: u8) {
+		let now = self.now;
+		match value >> 6 {
+			3 => { // Read-back
+				for (_, channel) in self.channels.iter_mut().enumerate().filter(|(i, _)| value & (2 << i) != 0) {
+					if value & 0x20 == 0 && channel.latch.is_none() { channel.latch = Some(channel.value(now)); }
+					if value & 0x10 == 0 && channel.status.is_none() {
+						channel.status = Some((channel.output(now) as u8) << 7 | (!channel.loaded as u8) << 6 | channel.access << 4 | channel.mode << 1);
+					}
+				}
+			}
+			index => {
+				let channel = &mut self.channels[index as usize];
+				match (value >> 4) & 0b11 {
+					0 => if channel.latch.is_none() { channel.latch = Some(channel.value(now)); },
+					access => {
+						let mode = (value >> 1) & 0b111;
+						*channel = Channel{mode: if mode > 5 { mode - 4 } else { mode }, access, gate: channel.gate, ..Default::default()};
+					}
+				}
+			}
+		}
+	}
+}
+
+impl PortDevice for Pit {
+	fn read(&mut self, port: u16, _size: usize) -> u32 {
+		let now = self.now;
+		match port {
+			SYSTEM_CONTROL_B => {
+				let refresh = (now / 18) & 1 != 0; // Toggles every 15us
+				(self.channels[2].gate as u32) | (self.speaker as u32) << 1 | (refresh as u32) << 4 | (self.channels[2].output(now) as u32) << 5
+			}
+			0x40..=0x42 => self.channels[(port - PIT) as usize].read(now) as u32,
+			_ => 0xFF, // Control word is write only
+		}
+	}
+
+	fn write(&mut self, port: u16, _size: usize, value: u32) {
+		let (now, value) = (self.now, value as u8);
+		match port {
+			SYSTEM_CONTROL_B => {
+				self.channels[2].set_gate(value & 1 != 0, now);
+				self.speaker = value & 0b10 != 0;
+			}
+			0x40..=0x42 => self.channels[(port - PIT) as usize].write(value, now),
+			_ => self.control(value),
+		}
+	}
+}
+
+impl State {
+	pub fn enable_pit(&mut self) {
+		let pit = Rc::new(RefCell::new(Pit::new(self.irq.line(PIT_IRQ))));
+		self.io.register(PIT..=PIT+3, Box::new(pit.clone()));
+		self.io.register(SYSTEM_CONTROL_B..=SYSTEM_CONTROL_B, Box::new(pit.clone()));
+		self.pit = Some(pit);
+	}
+}
@@ -0,0 +1,265 @@
+use std::{rc::Rc, cell::RefCell};
+use crate::{state::State, io::PortDevice, irq::Irq};
+
+pub const RTC: u16 = 0x70;
+pub const RTC_IRQ: u8 = 8;
+const NANOSECONDS: u64 = 1_000_000_000;
+const UPDATE_CYCLE: u64 = 244_000; // UIP is set this long before each update
+
+// Registers
+const SECONDS: usize = 0x00;
+const SECONDS_ALARM: usize = 0x01;
+const MINUTES: usize = 0x02;
+const MINUTES_ALARM: usize = 0x03;
+const HOURS: usize = 0x04;
+const HOURS_ALARM: usize = 0x05;
+const WEEKDAY: usize = 0x06;
+const DAY: usize = 0x07;
+const MONTH: usize = 0x08;
+const YEAR: usize = 0x09;
+const REGISTER_A: usize = 0x0A;
+const REGISTER_B: usize = 0x0B;
+const REGISTER_C: usize = 0x0C;
+const REGISTER_D: usize = 0x0D;
+const CENTURY: usize = 0x32;
+const TIME_REGISTERS: [usize; 8] = [SECONDS, MINUTES, HOURS, WEEKDAY, DAY, MONTH, YEAR, CENTURY];
+
+const A_UIP: u8 = 0x80;
+const B_SET: u8 = 0x80;
+const B_PIE: u8 = 0x40;
+const B_AIE: u8 = 0x20;
+const B_UIE: u8 = 0x10;
+const B_BINARY: u8 = 0x04;
+const B_24_HOUR: u8 = 0x02;
+const C_IRQF: u8 = 0x80;
+const C_PF: u8 = 0x40;
+const C_AF: u8 = 0x20;
+const C_UF: u8 = 0x10;
+const D_VRT: u8 = 0x80;
+
+// Days since 1970-01-01 of a proleptic Gregorian date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+	let year = if month <= 2 { year - 1 } else { year };
+	let era = year.div_euclid(400);
+	let year_of_era = year - era * 400;
+	let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+	let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+	era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+	let days = days + 719468;
+	let era = days.div_euclid(146097);
+	let day_of_era = days - era * 146097;
+	let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+	let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+	let month_index = (5 * day_of_year + 2) / 153;
+	let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+	let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+	(year_of_era + era * 400 + (month <= 2) as i64, month, day)
+}
+
+// MC146818 real time clock and CMOS NVRAM. Time only advances with the virtual clock
+pub struct Rtc {
+	nvram: [u8; 128], // Time registers hold a frozen copy while SET is on
+	index: usize,
+	seconds: u64, // Unix time
+	second_start: u64, // Virtual nanoseconds
+	next_periodic: u64,
+	flags: u8, // Register C
+	now: u64,
+	frequency: u64,
+	irq: Irq,
+}
+
+impl Rtc {
+	pub fn new(irq: Irq, epoch: u64) -> Self {
+		let mut nvram = [0; 128];
+		nvram[REGISTER_A] = 0x26; // 32.768kHz time base, 1024Hz periodic rate
+		nvram[REGISTER_B] = B_24_HOUR;
+		let mut rtc = Self{nvram, index: 0, seconds: epoch, second_start: 0, next_periodic: 0, flags: 0, now: 0, frequency: 1, irq};
+		rtc.next_periodic = rtc.periodic_interval().unwrap_or(0);
+		rtc
+	}
+
+	// Current wall-clock time as Unix seconds
+	pub fn time(&self) -> u64 { self.seconds }
+
+	pub fn read_nvram(&self, offset: usize) -> u8 { self.nvram[offset] }
+
+	// Configures CMOS contents and keeps the standard checksum over 0x10-0x2D valid
+	pub fn write_nvram(&mut self, offset: usize, bytes: &[u8]) {
+		assert!(offset > REGISTER_D && offset + bytes.len() <= self.nvram.len(), "rtc: nvram offset out of range");
+		self.nvram[offset..offset+bytes.len()].copy_from_slice(bytes);
+		let checksum = self.nvram[0x10..=0x2D].iter().map(|&byte| byte as u16).sum::<u16>();
+		self.nvram[0x2E..=0x2F].copy_from_slice(&checksum.to_be_bytes());
+	}
+
+	// Memory size fields read by BIOSes: base, extended (KB), above 16MB and above 4GB (64KB units)
+	pub fn set_memory_size(&mut self, bytes: u64) {
+		let extended = (bytes.saturating_sub(1 << 20) >> 10).min(0xFFFF) as u16;
+		let above_16m = (bytes.min(1 << 32).saturating_sub(16 << 20) >> 16).min(0xFFFF) as u16;
+		let above_4g = bytes.saturating_sub(1 << 32) >> 16;
+		self.write_nvram(0x15, &640u16.to_le_bytes());
+		self.write_nvram(0x17, &extended.to_le_bytes());
+		self.write_nvram(0x30, &extended.to_le_bytes());
+		self.write_nvram(0x34, &above_16m.to_le_bytes());
+		self.write_nvram(0x5B, &above_4g.to_le_bytes()[..3]);
+	}
+
+	// Boot devices in order as in QEMU's -boot: a floppy, c disk, d cdrom, n network
+	pub fn set_boot_order(&mut self, order: &str) {
+		let devices = order.chars().map(|device| match device {
+			'a' => 1, 'c' => 2, 'd' => 3, 'n' => 4,
+			_ => panic!("rtc: unknown boot device {}", device),
+		}).chain(std::iter::repeat(0)).take(3).collect::<Vec<u8>>();
+		self.nvram[0x3D] = devices[1] << 4 | devices[0];
+		self.nvram[0x38] = devices[2] << 4 | (self.nvram[0x38] & 0x0F);
+	}
+
+	pub fn update(&mut self, cycles: u64, frequency: u64) {
+		(self.now, self.frequency) = ((cycles as u128 * NANOSECONDS as u128 / frequency as u128) as u64, frequency);
+		if let Some(interval) = self.periodic_interval() {
+			if self.now >= self.next_periodic {
+				self.flags |= C_PF;
+				self.next_periodic += ((self.now - self.next_periodic) / interval + 1) * interval;
+			}
+		}
+		if self.now >= self.second_start + NANOSECONDS {
+			let elapsed = (self.now - self.second_start) / NANOSECONDS;
+			self.second_start += elapsed * NANOSECONDS;
+			if self.nvram[REGISTER_B] & B_SET == 0 {
+				// Alarms repeat at most daily
+				let alarm = (elapsed.saturating_sub(86400)+1..=elapsed).any(|second| self.alarm_matches(self.seconds + second));
+				self.seconds += elapsed;
+				self.flags |= C_UF | if alarm { C_AF } else { 0 };
+			}
+		}
+		self.update_irq();
+	}
+
+	// Cycle count of the next update or periodic interrupt
+	pub fn next_event(&self) -> Option<u64> {
+		let enabled = self.nvram[REGISTER_B];
+		let periodic = self.periodic_interval().filter(|_| enabled & B_PIE != 0).map(|_| self.next_periodic);
+		let update = Some(self.second_start + NANOSECONDS).filter(|_| enabled & (B_UIE | B_AIE) != 0 && enabled & B_SET == 0);
+		let time = periodic.into_iter().chain(update).min()?;
+		Some(((time as u128 * self.frequency as u128 + NANOSECONDS as u128 - 1) / NANOSECONDS as u128) as u64)
+	}
+
+	fn update_irq(&mut self) {
+		// Flags in C line up with their enables in B
+		if self.flags & self.nvram[REGISTER_B] & (C_PF | C_AF | C_UF) != 0 { self.flags |= C_IRQF; }
+		self.irq.set(self.flags & C_IRQF != 0);
+	}
+
+	fn periodic_interval(&self) -> Option<u64> {
+		let rate = match self.nvram[REGISTER_A] & 0x0F { 0 => return None, rate @ 1..=2 => rate + 7, rate => rate };
+		Some(NANOSECONDS * (1 << (rate - 1)) / 32768)
+	}
+
+	fn encode(&self, value: u64) -> u8 {
+		if self.nvram[REGISTER_B] & B_BINARY != 0 { value as u8 } else { ((value / 10) << 4 | value % 10) as u8 }
+	}
+
+	fn decode(&self, value: u8) -> i64 {
+		if self.nvram[REGISTER_B] & B_BINARY != 0 { value as i64 } else { (value >> 4) as i64 * 10 + (value & 0x0F) as i64 }
+	}
+
+	fn time_register(&self, seconds: u64, index: usize) -> u8 {
+		let (days, time) = (seconds / 86400, seconds % 86400);
+		let (year, month, day) = civil_from_days(days as i64);
+		match index {
+			SECONDS => self.encode(time % 60),
+			MINUTES => self.encode(time / 60 % 60),
+			HOURS => {
+				let hour = time / 3600;
+				if self.nvram[REGISTER_B] & B_24_HOUR != 0 { self.encode(hour) }
+				else { self.encode((hour + 11) % 12 + 1) | if hour >= 12 { 0x80 } else { 0 } }
+			}
+			WEEKDAY => self.encode((days + 4) % 7 + 1), // Sunday is 1
+			DAY => self.encode(day as u64),
+			MONTH => self.encode(month as u64),
+			YEAR => self.encode(year as u64 % 100),
+			CENTURY => self.encode(year as u64 / 100),
+			_ => unreachable!(),
+		}
+	}
+
+	// Alarm registers with the top two bits set match any value
+	fn alarm_matches(&self, seconds: u64) -> bool {
+		[(SECONDS_ALARM, SECONDS), (MINUTES_ALARM, MINUTES), (HOURS_ALARM, HOURS)].iter()
+			.all(|&(alarm, field)| self.nvram[alarm] >= 0xC0 || self.nvram[alarm] == self.time_register(seconds, field))
+	}
+
+	fn freeze(&mut self) {
+		for &index in TIME_REGISTERS.iter() { self.nvram[index] = self.time_register(self.seconds, index); }
+	}
+
+	// Sets the clock from the time registers. The weekday register is not checked
+	fn thaw(&mut self) {
+		let hour = self.nvram[HOURS];
+		let hour = if self.nvram[REGISTER_B] & B_24_HOUR != 0 { self.decode(hour) } else { self.decode(hour & 0x7F) % 12 + if hour & 0x80 != 0 { 12 } else { 0 } };
+		let year = self.decode(self.nvram[CENTURY]) * 100 + self.decode(self.nvram[YEAR]);
+		let days = days_from_civil(year, self.decode(self.nvram[MONTH]), self.decode(self.nvram[DAY]));
+		let seconds = days * 86400 + hour * 3600 + self.decode(self.nvram[MINUTES]) * 60 + self.decode(self.nvram[SECONDS]);
+		self.seconds = seconds.max(0) as u64;
+	}
+}
+
+impl PortDevice for Rtc {
+	fn read(&mut self, port: u16, _size: usize) -> u32 {
+		if port == RTC { return 0xFF; } // Index is write only
+		let set = self.nvram[REGISTER_B] & B_SET != 0;
+		(match self.index {
+			index if TIME_REGISTERS.contains(&index) && !set => self.time_register(self.seconds, index),
+			REGISTER_A => self.nvram[REGISTER_A] | if !set && self.now >= self.second_start + NANOSECONDS - UPDATE_CYCLE { A_UIP } else { 0 },
+			REGISTER_C => {
+				let flags = std::mem::take(&mut self.flags);
+				self.update_irq();
+				flags
+			}
+			REGISTER_D => D_VRT,
+			index => self.nvram[index],
+		}) as u32
+	}
+
+	fn write(&mut self, port: u16, _size: usize, value: u32) {
+		let value = value as u8;
+		if port == RTC { self.index = (value & 0x7F) as usize; return; } // Bit 7 masks NMI
+		let set = self.nvram[REGISTER_B] & B_SET != 0;
+		match self.index {
+			index if TIME_REGISTERS.contains(&index) => {
+				if !set { self.freeze(); }
+				self.nvram[index] = value;
+				if !set { self.thaw(); }
+			}
+			REGISTER_A => {
+				self.nvram[REGISTER_A] = value & !A_UIP;
+				self.next_periodic = self.now + self.periodic_interval().unwrap_or(0);
+			}
+			REGISTER_B => {
+				let value = if value & B_SET != 0 { value & !B_UIE } else { value };
+				if value & B_SET != 0 && !set { self.freeze(); }
+				self.nvram[REGISTER_B] = value;
+				if value & B_SET == 0 && set {
+					self.thaw();
+					self.second_start = self.now;
+				}
+				self.update_irq();
+			}
+			REGISTER_C | REGISTER_D => {}
+			index => self.nvram[index] = value,
+		}
+	}
+}
+
+impl State {
+	// epoch: Unix time at reset. The returned handle configures the NVRAM
+	pub fn enable_rtc(&mut self, epoch: u64) -> Rc<RefCell<Rtc>> {
+		let rtc = Rc::new(RefCell::new(Rtc::new(self.irq.line(RTC_IRQ), epoch)));
+		self.io.register(RTC..=RTC+1, Box::new(rtc.clone()));
+		self.rtc = Some(rtc.clone());
+		rtc
+	}
+}
@@ -0,0 +1,152 @@
+use crate::{state::{State, msr}, instruction::Register, interrupt::vector::{SEGMENT_NOT_PRESENT, STACK_FAULT, GENERAL_PROTECTION}};
+
+// Descriptor table register (GDTR, IDTR)
+#[derive(Default, Debug, Clone, Copy)]
+pub struct DescriptorTable {
+	pub base: u64,
+	pub limit: u16,
+}
+
+// Operating mode: CR0.PE, EFER.LMA and the L/D bits of the code segment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode { Real, Protected16, Protected32, LongCompatibility16, LongCompatibility, Long64 }
+
+impl Mode {
+	pub fn long(&self) -> bool { matches!(self, Mode::LongCompatibility16 | Mode::LongCompatibility | Mode::Long64) } // EFER.LMA
+	pub fn default_16bit(&self) -> bool { matches!(self, Mode::Real | Mode::Protected16 | Mode::LongCompatibility16) }
+	pub fn ip_mask(&self) -> i64 {
+		match self {
+			Mode::Real | Mode::Protected16 | Mode::LongCompatibility16 => 0xFFFF,
+			Mode::Protected32 | Mode::LongCompatibility => 0xFFFF_FFFF,
+			Mode::Long64 => !0,
+		}
+	}
+}
+
+// Segment register: visible selector and hidden descriptor cache
+#[derive(Default, Debug, Clone, Copy)]
+pub struct Segment {
+	pub selector: u16,
+	pub base: u64,
+	pub limit: u32,
+	pub access: u8, // P DPL S Type
+	pub flags: u8, // G D/B L AVL
+}
+
+impl Segment {
+	pub const GRANULARITY: u8 = 0b1000;
+	pub const DEFAULT_BIG: u8 = 0b0100;
+	pub const LONG: u8 = 0b0010;
+
+	pub fn from_descriptor(selector: u16, descriptor: u64) -> Self {
+		let flags = ((descriptor >> 52) & 0xF) as u8;
+		let limit = ((descriptor & 0xFFFF) | ((descriptor >> 32) & 0xF_0000)) as u32;
+		Self{
+			selector,
+			base: ((descriptor >> 16) & 0xFF_FFFF) | ((descriptor >> 32) & 0xFF00_0000),
+			limit: if flags & Self::GRANULARITY != 0 { (limit << 12) | 0xFFF } else { limit },
+			access: (descriptor >> 40) as u8,
+			flags,
+		}
+	}
+	pub fn real_mode(selector: u16) -> Self { Self{selector, base: (selector as u64) << 4, limit: 0xFFFF, access: 0x93, flags: 0} }
+	// Fixed flat segments loaded by syscall/sysret/sysenter/sysexit (descriptor is not read)
+	pub fn flat_code(selector: u16, dpl: u8, long: bool) -> Self {
+		Self{selector, base: 0, limit: 0xFFFF_FFFF, access: 0x9B | dpl << 5, flags: Self::GRANULARITY | if long { Self::LONG } else { Self::DEFAULT_BIG }}
+	}
+	pub fn flat_data(selector: u16, dpl: u8) -> Self {
+		Self{selector, base: 0, limit: 0xFFFF_FFFF, access: 0x93 | dpl << 5, flags: Self::GRANULARITY | Self::DEFAULT_BIG}
+	}
+
+	pub fn present(&self) -> bool { self.access & 0x80 != 0 }
+	pub fn dpl(&self) -> u8 { (self.access >> 5) & 0b11 }
+	pub fn system(&self) -> bool { self.access & 0x10 == 0 }
+	pub fn long(&self) -> bool { self.flags & Self::LONG != 0 }
+	pub fn default_big(&self) -> bool { self.flags & Self::DEFAULT_BIG != 0 }
+}
+
+impl State {
+	pub fn cpl(&self) -> u8 { if self.cr0 & 1 == 0 { 0 } else { (self.cs.selector & 0b11) as u8 } }
+
+	pub fn mode(&self) -> Mode {
+		if self.cr0 & 1 == 0 { Mode::Real }
+		else if self.read_msr(msr::EFER) & msr::EFER_LMA != 0 {
+			if self.cs.long() { Mode::Long64 }
+			else if self.cs.default_big() { Mode::LongCompatibility }
+			else { Mode::LongCompatibility16 }
+		}
+		else if self.cs.default_big() { Mode::Protected32 }
+		else { Mode::Protected16 }
+	}
+
+	// SP, ESP or RSP depending on SS.B (ignored in 64bit mode)
+	pub fn stack_mask(&self) -> i64 { if self.cs.long() { !0 } else if self.ss.default_big() { 0xFFFF_FFFF } else { 0xFFFF } }
+	pub fn stack_address(&self) -> u64 { if self.cs.long() { self.rsp as u64 } else { self.ss.base + (self.rsp & self.stack_mask()) as u64 } }
+	pub fn stack_adjust(&mut self, offset: i64) { let mask = self.stack_mask(); self.rsp = (self.rsp & !mask) | (self.rsp.wrapping_add(offset) & mask); }
+
+	pub fn segment(&self, register: Register) -> &Segment {
+		match register {
+			Register::ES => &self.es,
+			Register::CS => &self.cs,
+			Register::SS => &self.ss,
+			Register::DS => &self.ds,
+			Register::FS => &self.fs,
+			Register::GS => &self.gs,
+			_ => panic!("Expected segment register"),
+		}
+	}
+	pub fn segment_mut(&mut self, register: Register) -> &mut Segment {
+		match register {
+			Register::ES => &mut self.es,
+			Register::CS => &mut self.cs,
+			Register::SS => &mut self.ss,
+			Register::DS => &mut self.ds,
+			Register::FS => &mut self.fs,
+			Register::GS => &mut self.gs,
+			_ => panic!("Expected segment register"),
+		}
+	}
+
+	// Raw 8 byte descriptor referenced by selector in the GDT or the LDT (TI). None outside the table limit
+	pub fn read_descriptor(&self, selector: u16) -> Option<u64> {
+		let offset = (selector & !0b111) as u64;
+		let (base, limit) = if selector & 0b100 != 0 { (self.ldtr.base, self.ldtr.limit as u64) } else { (self.gdt.base, self.gdt.limit as u64) };
+		if offset+7 > limit { return None; }
+		Some(self.memory.read_unaligned(base + offset))
+	}
+
+	// lldt: a null selector leaves the LDT unusable, anything but an LDT descriptor in the GDT is #GP, #NP if it is not present
+	pub fn load_ldt_register(&mut self, selector: u16) {
+		if selector & !0b11 == 0 { self.ldtr = Segment{selector, ..Default::default()}; return; }
+		let error_code = Some((selector & !0b11) as u32);
+		let low = match self.read_descriptor(selector) { Some(low) if selector & 0b100 == 0 => low, _ => return self.fault(GENERAL_PROTECTION, error_code) };
+		let mut ldtr = Segment::from_descriptor(selector, low);
+		if !ldtr.system() || ldtr.access & 0xF != 0x2 { return self.fault(GENERAL_PROTECTION, error_code); }
+		if !ldtr.present() { return self.fault(SEGMENT_NOT_PRESENT, error_code); }
+		if self.mode().long() {
+			let high: u64 = self.memory.read_unaligned(self.gdt.base + (selector & !0b111) as u64 + 8);
+			ldtr.base |= (high & 0xFFFF_FFFF) << 32;
+		}
+		self.ldtr = ldtr;
+	}
+
+	// Faults with #GP, #NP or #SS (error code: selector) instead of loading an unusable segment. Returns whether it was loaded
+	pub fn load_segment(&mut self, register: Register, selector: u16) -> bool {
+		let error_code = Some((selector & !0b11) as u32);
+		let segment = if self.cr0 & 1 == 0 { Segment::real_mode(selector) }
+		else if selector & !0b11 == 0 {
+			if matches!(register, Register::CS) { self.fault(GENERAL_PROTECTION, Some(0)); return false; } // Null code segment
+			Segment{selector, ..Default::default()}
+		} else {
+			let segment = match self.read_descriptor(selector) {
+				Some(descriptor) => Segment::from_descriptor(selector, descriptor),
+				None => { self.fault(GENERAL_PROTECTION, error_code); return false; }
+			};
+			if segment.system() { self.fault(GENERAL_PROTECTION, error_code); return false; }
+			if !segment.present() { self.fault(if matches!(register, Register::SS) { STACK_FAULT } else { SEGMENT_NOT_PRESENT }, error_code); return false; }
+			segment
+		};
+		*self.segment_mut(register) = segment;
+		true
+	}
+}
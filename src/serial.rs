@@ -0,0 +1,150 @@
+use std::{io::{Read, Write}, collections::VecDeque, sync::mpsc::{channel, Receiver}, rc::Rc, cell::RefCell};
+use crate::{state::State, io::PortDevice, irq::Irq};
+
+pub const COM1: u16 = 0x3F8;
+pub const COM1_IRQ: u8 = 4;
+
+const IER_RECEIVE: u8 = 1 << 0;
+const IER_TRANSMIT: u8 = 1 << 1;
+const LCR_DLAB: u8 = 1 << 7;
+const MCR_OUT2: u8 = 1 << 3; // Gates the interrupt output on PCs
+const MCR_LOOPBACK: u8 = 1 << 4;
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_TRANSMIT_EMPTY: u8 = 0b0110_0000; // THRE | TEMT: transmission is immediate
+
+// 16550A UART
+pub struct Uart {
+	output: Box<dyn Write>,
+	input: Option<Receiver<u8>>,
+	receive: VecDeque<u8>,
+	irq: Irq,
+	divisor: u16,
+	ier: u8,
+	lcr: u8,
+	mcr: u8,
+	fcr: u8,
+	scratch: u8,
+	transmit_interrupt: bool, // THR empty, cleared by reading IIR or writing THR
+}
+
+impl Uart {
+	// input is read by a host thread so that polling the line status never blocks the guest
+	pub fn new(output: Box<dyn Write>, input: Option<Box<dyn Read + Send>>, irq: Irq) -> Self {
+		let input = input.map(|mut input| {
+			let (sender, receiver) = channel();
+			std::thread::spawn(move || {
+				let mut byte = [0];
+				while let Ok(1) = input.read(&mut byte) { if sender.send(byte[0]).is_err() { break; } }
+			});
+			receiver
+		});
+		Self{output, input, receive: VecDeque::new(), irq, divisor: 12, ier: 0, lcr: 0, mcr: 0, fcr: 0, scratch: 0, transmit_interrupt: false}
+	}
+
+	// Scripted input
+	pub fn receive(&mut self, bytes: &[u8]) {
+		self.receive.extend(bytes);
+		self.update_interrupt();
+	}
+
+	fn poll_input(&mut self) {
+		if let Some(input) = &self.input { self.receive.extend(input.try_iter()); }
+	}
+
+	// Raises the receive interrupt for host input that arrived since the last access
+	pub fn update(&mut self) {
+		self.poll_input();
+		self.update_interrupt();
+	}
+
+	// Highest priority pending interrupt: IIR bits 3:1
+	fn interrupt(&self) -> Option<u8> {
+		if self.ier & IER_RECEIVE != 0 && !self.receive.is_empty() { Some(0b010) }
+		else if self.ier & IER_TRANSMIT != 0 && self.transmit_interrupt { Some(0b001) }
+		else { None }
+	}
+
+	fn update_interrupt(&mut self) {
+		self.irq.set(self.mcr & MCR_OUT2 != 0 && self.interrupt().is_some());
+	}
+
+	fn transmit(&mut self, byte: u8) {
+		if self.mcr & MCR_LOOPBACK != 0 { self.receive.push_back(byte); }
+		else {
+			self.output.write_all(&[byte]).unwrap();
+			self.output.flush().unwrap();
+		}
+		self.transmit_interrupt = true;
+	}
+
+	fn modem_status(&self) -> u8 {
+		if self.mcr & MCR_LOOPBACK != 0 { // CTS=RTS, DSR=DTR, RI=OUT1, DCD=OUT2
+			(self.mcr & 0b0010) << 3 | (self.mcr & 0b0001) << 5 | (self.mcr & 0b0100) << 4 | (self.mcr & 0b1000) << 4
+		} else { 0b1011_0000 } // DCD, DSR, CTS
+	}
+}
+
+impl PortDevice for Uart {
+	fn read(&mut self, port: u16, _size: usize) -> u32 {
+		self.poll_input();
+		let dlab = self.lcr & LCR_DLAB != 0;
+		let value = match port & 0b111 {
+			0 if dlab => self.divisor as u8,
+			0 => self.receive.pop_front().unwrap_or(0),
+			1 if dlab => (self.divisor >> 8) as u8,
+			1 => self.ier,
+			2 => {
+				let fifo = if self.fcr & 1 != 0 { 0b1100_0000 } else { 0 };
+				match self.interrupt() {
+					Some(interrupt) => {
+						if interrupt == 0b001 { self.transmit_interrupt = false; }
+						fifo | interrupt << 1
+					}
+					None => fifo | 1,
+				}
+			}
+			3 => self.lcr,
+			4 => self.mcr,
+			5 => LSR_TRANSMIT_EMPTY | if self.receive.is_empty() { 0 } else { LSR_DATA_READY },
+			6 => self.modem_status(),
+			7 => self.scratch,
+			_ => unreachable!(),
+		};
+		self.update_interrupt();
+		value as u32
+	}
+
+	fn write(&mut self, port: u16, _size: usize, value: u32) {
+		let value = value as u8;
+		let dlab = self.lcr & LCR_DLAB != 0;
+		match port & 0b111 {
+			0 if dlab => self.divisor = (self.divisor & 0xFF00) | value as u16,
+			0 => self.transmit(value),
+			1 if dlab => self.divisor = (self.divisor & 0x00FF) | (value as u16) << 8,
+			1 => {
+				// Enabling the THR empty interrupt raises it immediately
+				if value & IER_TRANSMIT != 0 && self.ier & IER_TRANSMIT == 0 { self.transmit_interrupt = true; }
+				self.ier = value & 0x0F;
+			}
+			2 => {
+				if value & 0b010 != 0 { self.receive.clear(); }
+				self.fcr = value;
+			}
+			3 => self.lcr = value,
+			4 => self.mcr = value & 0x1F,
+			7 => self.scratch = value,
+			_ => {} // LSR, MSR are read only
+		}
+		self.update_interrupt();
+	}
+}
+
+impl State {
+	// COM1 on IRQ4. The returned handle injects scripted input
+	pub fn enable_serial(&mut self, output: Box<dyn Write>, input: Option<Box<dyn Read + Send>>) -> Rc<RefCell<Uart>> {
+		let uart = Rc::new(RefCell::new(Uart::new(output, input, self.irq.line(COM1_IRQ))));
+		self.io.register(COM1..=COM1+7, Box::new(uart.clone()));
+		self.serial = Some(uart.clone());
+		uart
+	}
+}
@@ -1,9 +1,11 @@
-use crate::{memory::Memory, instruction::{Operand, Register, Flags, OperandSize}};
+use std::{rc::Rc, cell::RefCell};
+use crate::{memory::Memory, io::PortBus, irq::IrqLines, apic::{LocalApic, IoApic}, pic::Pic, pit::Pit, pci::PciBus, keyboard::I8042, rtc::Rtc, serial::Uart, symbols::Symbolizer, unwind::Unwinder, trace::Tracer, segment::{Segment, DescriptorTable, Mode}, instruction::{Opcode, Operands, Operand, Register, Flags, OperandSize, get_register_size}};
 
 pub enum Value {
 	I64(i64),
 	I32(i32),
 	XMM(u128),
+	U16(u16),
 	U8(u8),
 }
 use Value::*;
@@ -48,6 +50,7 @@ impl From<Value> for f32 {
 
 impl From<Value> for u16 {
 	fn from(value: Value) -> u16 { match value {
+		Value::U16(value) => value,
 		//Value::I64(value) => value,
 		//Value::I32(value) => value as i64, // /!\ sign extend
 		//Value::XMM(value) => value as i64, // /!\ truncate
@@ -77,22 +80,77 @@ impl std::fmt::Debug for Value {
 			Value::I64(value) => write!(f, "{}", value),
 			Value::I32(value) => write!(f, "{}", value),
 			Value::XMM(value) => write!(f, "{}", f32::from_bits(*value as u32)),
+			Value::U16(value) => write!(f, "{}", value),
 			Value::U8(value) => write!(f, "{}", value),
 		}
 	}
 }
 
+pub mod msr {
+	pub const SYSENTER_CS: u32 = 0x174;
+	pub const SYSENTER_ESP: u32 = 0x175;
+	pub const SYSENTER_EIP: u32 = 0x176;
+	pub const EFER: u32 = 0xC0000080;
+	pub const EFER_LME: u64 = 1 << 8;
+	pub const EFER_LMA: u64 = 1 << 10;
+	pub const STAR: u32 = 0xC0000081;
+	pub const LSTAR: u32 = 0xC0000082;
+	pub const CSTAR: u32 = 0xC0000083;
+	pub const SFMASK: u32 = 0xC0000084;
+	pub const FS_BASE: u32 = 0xC0000100;
+	pub const GS_BASE: u32 = 0xC0000101;
+	pub const KERNEL_GS_BASE: u32 = 0xC0000102;
+	pub const TSC_AUX: u32 = 0xC0000103;
+	pub const TSC: u32 = 0x10;
+	pub const APIC_BASE: u32 = 0x1B;
+	pub const TSC_DEADLINE: u32 = 0x6E0;
+}
+
 pub struct State {
 	pub rip: i64,
 	pub rax: i64, pub rbx: i64, pub rcx: i64, pub rdx: i64, pub rsp: i64, pub rbp: i64, pub rsi: i64, pub rdi: i64,
 	pub r8: i64, pub r9: i64, pub r10: i64, pub r11: i64, pub r12: i64, pub r13: i64, pub r14: i64, pub r15: i64,
 	pub rflags: i64,
-	pub cr0: i64, pub cr2: i64, pub cr4: i64, pub cr8: i64,
-	pub gdt: i64, pub idt: i64,
+	pub cr0: i64, pub cr2: i64, pub cr3: i64, pub cr4: i64, pub cr8: i64,
+	pub dr: [u64; 4], pub dr6: u64, pub dr7: u64, // Debug registers
+	pub es: Segment, pub cs: Segment, pub ss: Segment, pub ds: Segment, pub fs: Segment, pub gs: Segment,
+	pub gdt: DescriptorTable, pub idt: DescriptorTable, pub tr: Segment, pub ldtr: Segment,
+	pub kernel_gs_base: u64,
+	pub msr: fnv::FnvHashMap<u32, u64>,
 	pub xmm: [u128; 16],
 
 	pub memory: Memory,
+	pub io: PortBus,
+	pub irq: IrqLines,
+	pub apic: Option<Rc<RefCell<LocalApic>>>,
+	pub ioapic: Option<Rc<RefCell<IoApic>>>,
+	pub pic: Option<Rc<RefCell<Pic>>>,
+	pub pit: Option<Rc<RefCell<Pit>>>,
+	pub pci: Option<Rc<RefCell<PciBus>>>,
+	pub keyboard: Option<Rc<RefCell<I8042>>>,
+	pub rtc: Option<Rc<RefCell<Rtc>>>,
+	pub serial: Option<Rc<RefCell<Uart>>>,
+	pub instructions: u64, // Retired instructions
+	pub instruction_limit: u64, // execute stops after this many retired instructions
+	pub cycles: u64, // Virtual time: drives the TSC and all timers
+	pub cycles_per_instruction: u64,
+	pub frequency: u64, // Cycles per second
+	pub tsc_offset: u64, // Written by wrmsr TSC
 	pub print_instructions: bool,
+	pub system_mode: bool, // syscall enters the guest kernel (LSTAR) instead of being emulated
+	pub halted: bool,
+	pub shutdown: bool, // Triple fault: stops the processor until reset
+	pub interrupt_shadow: bool, // sti: interrupts are recognized after the next instruction
+	pub instruction_start: i64, // Return address of faults
+	pub exit_code: Option<i32>, // exit or exit_group
+	pub find_location: Box<dyn Fn(u64) -> String>, // Symbolizes guest addresses in panics, traces and stop reasons
+	pub symbols: Option<Rc<Symbolizer>>,
+	pub unwinder: Option<Rc<Unwinder>>, // Call frame information for backtraces
+	pub breakpoints: fnv::FnvHashSet<u64>, // execute stops before these instructions
+	pub(crate) breakpoint_resume: Option<u64>, // Breakpoint execute stopped at
+	pub tracer: Option<Rc<RefCell<Tracer>>>, // Structured trace of retired instructions
+	pub(crate) instruction_cache: fnv::FnvHashMap<u64, Rc<(Opcode, Operands, usize)>>, // Decoded instructions by linear address
+	pub(crate) instruction_cache_mode: Mode,
 }
 
 impl State {
@@ -101,13 +159,84 @@ impl State {
         rax: 0, rbx: 0, rcx: 0, rdx: 0, rsp: 0, rbp: 0, rsi: 0, rdi: 0,
         r8: 0, r9: 0, r10: 0, r11: 0, r12: 0, r13: 0, r14: 0, r15: 0,
         rflags: 0,
-        cr0: 0, cr2: 0, cr4: 0, cr8: 0,
-        gdt: 0, idt: 0,
+        cr0: 0x8000_0001, cr2: 0, cr3: 0, cr4: 0, cr8: 0, // Paged long mode
+        dr: [0; 4], dr6: 0xFFFF_0FF0, dr7: 0x400,
+        es: Default::default(), cs: Segment::flat_code(0, 0, true), ss: Segment::flat_data(0, 0), ds: Default::default(), fs: Default::default(), gs: Default::default(),
+        gdt: Default::default(), idt: Default::default(), tr: Default::default(), ldtr: Default::default(),
+        kernel_gs_base: 0,
+        msr: [(msr::EFER, 0x500), (msr::STAR, 0), (msr::LSTAR, 0), (msr::CSTAR, 0), (msr::SFMASK, 0),
+              (msr::SYSENTER_CS, 0), (msr::SYSENTER_ESP, 0), (msr::SYSENTER_EIP, 0), (msr::TSC_AUX, 0)].iter().copied().collect(),
         xmm: [0; 16],
         memory: Default::default(),
+        io: Default::default(),
+        irq: Default::default(),
+        apic: None,
+        ioapic: None,
+        pic: None,
+        pit: None,
+        pci: None,
+        keyboard: None,
+        rtc: None,
+        serial: None,
+        instructions: 0,
+        instruction_limit: !0,
+        cycles: 0,
+        cycles_per_instruction: 1,
+        frequency: 1_000_000_000,
+        tsc_offset: 0,
         print_instructions: false,
+        system_mode: false,
+        halted: false,
+        shutdown: false,
+        interrupt_shadow: false,
+        instruction_start: 0,
+        exit_code: None,
+        find_location: Box::new(|address| format!("{:x}", address)),
+        symbols: None,
+        unwinder: None,
+        breakpoints: Default::default(),
+        breakpoint_resume: None,
+        tracer: None,
+        instruction_cache: Default::default(),
+        instruction_cache_mode: Mode::Long64,
     } }
 
+    pub fn read_msr(&self, index: u32) -> u64 {
+        match index {
+            msr::FS_BASE => self.fs.base,
+            msr::GS_BASE => self.gs.base,
+            msr::KERNEL_GS_BASE => self.kernel_gs_base,
+            msr::TSC => self.tsc(),
+            msr::TSC_DEADLINE if self.apic.is_some() => self.apic.as_ref().unwrap().borrow().tsc_deadline,
+            _ => *self.msr.get(&index).unwrap_or_else(|| panic!("RDMSR: unsupported operand: {:x}", index)),
+        }
+    }
+
+    pub fn write_msr(&mut self, index: u32, value: u64) {
+        match index {
+            msr::FS_BASE => self.fs.base = value,
+            msr::GS_BASE => self.gs.base = value,
+            msr::KERNEL_GS_BASE => self.kernel_gs_base = value,
+            msr::TSC => self.tsc_offset = value.wrapping_sub(self.cycles),
+            msr::TSC_DEADLINE if self.apic.is_some() => self.apic.as_ref().unwrap().borrow_mut().tsc_deadline = value,
+            _ => { self.msr.insert(index, value); }
+        }
+    }
+
+    // rdmsr and wrmsr raise #GP for any other index
+    pub fn msr_implemented(&self, index: u32) -> bool {
+        match index {
+            msr::FS_BASE | msr::GS_BASE | msr::KERNEL_GS_BASE | msr::TSC => true,
+            msr::TSC_DEADLINE if self.apic.is_some() => true,
+            _ => self.msr.contains_key(&index),
+        }
+    }
+
+    pub fn tsc(&self) -> u64 { self.cycles.wrapping_add(self.tsc_offset) }
+
+    // Virtual time since reset
+    pub fn nanoseconds(&self) -> u64 { (self.cycles as u128 * 1_000_000_000 / self.frequency as u128) as u64 }
+
     pub fn get_flag(&self, flag: Flags) -> bool {
         let f = flag as i64;
         self.rflags & f == f
@@ -250,9 +379,16 @@ impl State {
 
             Register::CR0 => self.cr0,
             Register::CR2 => self.cr2,
-            Register::CR3 => unimplemented!(), //self.memory.cr3,
+            Register::CR3 => self.cr3,
             Register::CR4 => self.cr4,
-            Register::CR8 => self.cr8,
+            Register::CR8 => match &self.apic { Some(apic) => (apic.borrow().tpr >> 4) as i64, None => self.cr8 }, // TPR[7:4]
+
+            Register::DR0 => self.dr[0] as i64,
+            Register::DR1 => self.dr[1] as i64,
+            Register::DR2 => self.dr[2] as i64,
+            Register::DR3 => self.dr[3] as i64,
+            Register::DR6 => self.dr6 as i64,
+            Register::DR7 => self.dr7 as i64,
 
             Register::RIP => self.rip as i64,
 
@@ -318,12 +454,7 @@ impl State {
             Register::SIL => self.rsi as i8 as i64,
             Register::DIL => self.rdi as i8 as i64,
 
-            Register::ES => 0,
-            Register::CS => 0,
-            Register::SS => 0,
-            Register::DS => 0,
-            Register::FS => 0,
-            Register::GS => 0,
+            Register::ES | Register::CS | Register::SS | Register::DS | Register::FS | Register::GS => self.segment(register).selector as i64,
 
             _ => panic!("Expected integer register"),
         }
@@ -429,6 +560,8 @@ impl State {
             Register::R13 => I64(self.r13),
             Register::R14 => I64(self.r14),
             Register::R15 => I64(self.r15),
+            Register::CR0 | Register::CR2 | Register::CR3 | Register::CR4 | Register::CR8 => I64(self.get_register_value(register)),
+            Register::DR0 | Register::DR1 | Register::DR2 | Register::DR3 | Register::DR6 | Register::DR7 => I64(self.get_register_value(register)),
 
             Register::EAX => I32(self.rax as i32),
             Register::EBX => I32(self.rbx as i32),
@@ -447,6 +580,8 @@ impl State {
             Register::R14D => I32(self.r14 as i32),
             Register::R15D => I32(self.r15 as i32),
 
+            register if matches!(get_register_size(register), OperandSize::Bit16) => U16(self.get_register_value(register) as u16),
+
 						Register::XMM0 => XMM(self.xmm[0]),
 						Register::XMM1 => XMM(self.xmm[1]),
 						Register::XMM2 => XMM(self.xmm[2]),
@@ -493,15 +628,11 @@ impl State {
 				Operand::EffectiveAddress { .. } => {
 						let address = self.calculate_effective_address(arg);
 						match operand_size {
-								/*OperandSize::Bit8 => self.memory.read_byte(address) as i64,
-								OperandSize::Bit16 => {
-										let value: i16 = self.memory.read_unaligned(address);
-										value as i64
-								}*/
+								OperandSize::Bit8 => U8(self.memory.read_byte(address)),
+								OperandSize::Bit16 => U16(self.memory.read_unaligned(address)),
 								OperandSize::Bit32 => I32(self.memory.read_unaligned(address)),
 								OperandSize::Bit64 => I64(self.memory.read_unaligned(address)), // fixme
 								OperandSize::Bit128 => XMM(self.memory.read_unaligned(address)),
-								_ => panic!("{:?}", operand_size),
 						}
 				}
 				//_ => unreachable!(),
@@ -532,7 +663,11 @@ impl State {
 
             Register::CR0 => {
                 println!("CR0: {:x}", value);
-                self.cr0 = value
+                self.cr0 = value;
+                // Enabling paging with EFER.LME activates long mode
+                let efer = self.read_msr(msr::EFER);
+                let lma = if value & (1 << 31) != 0 && efer & msr::EFER_LME != 0 { msr::EFER_LMA } else { 0 };
+                self.write_msr(msr::EFER, (efer & !msr::EFER_LMA) | lma);
             },
             Register::CR2 => {
                 println!("CR2: {:x}", value);
@@ -540,17 +675,25 @@ impl State {
             },
             Register::CR3 => {
                 println!("CR3: {:x}", value);
-                unimplemented!(); //self.memory.cr3 = value
+                self.cr3 = value
             },
             Register::CR4 => {
                 println!("CR4: {:x}", value);
                 self.cr4 = value
             },
             Register::CR8 => {
-                println!("CR5: {:x}", value);
-                self.cr8 = value
+                println!("CR8: {:x}", value);
+                match &self.apic { Some(apic) => apic.borrow_mut().tpr = (value as u8 & 0xF) << 4, None => self.cr8 = value }
             },
 
+            // Guest breakpoints are armed in Memory and step
+            Register::DR0 => { self.dr[0] = value as u64; self.update_debug_registers(); },
+            Register::DR1 => { self.dr[1] = value as u64; self.update_debug_registers(); },
+            Register::DR2 => { self.dr[2] = value as u64; self.update_debug_registers(); },
+            Register::DR3 => { self.dr[3] = value as u64; self.update_debug_registers(); },
+            Register::DR7 => { self.dr7 = value as u64 | 0x400; self.update_debug_registers(); },
+            Register::DR6 => self.dr6 = value as u64,
+
             Register::RIP => self.rip = value,
 
             // 32 Bit
@@ -619,12 +762,7 @@ impl State {
             Register::SIL => self.rsi = ((self.rsi as u64 & 0xFFFFFFFFFFFFFF00) | (value as u8 as u64)) as i64,
             Register::DIL => self.rdi = ((self.rdi as u64 & 0xFFFFFFFFFFFFFF00) | (value as u8 as u64)) as i64,
 
-            Register::ES => (),
-            Register::CS => (),
-            Register::SS => (),
-            Register::DS => (),
-            Register::FS => (),
-            Register::GS => (),
+            Register::ES | Register::CS | Register::SS | Register::DS | Register::FS | Register::GS => { self.load_segment(register, value as u16); }
 
             _ => panic!("Expected integer register"),
         }
@@ -847,9 +985,10 @@ impl State {
         }
     }
 
-    pub fn calculate_effective_address(&self, arg: &Operand) -> u64 {
+    // Offset within the segment (lea)
+    pub fn calculate_offset(&self, arg: &Operand) -> u64 {
         match *arg {
-            Operand::EffectiveAddress { ref base, ref index, scale, displacement} => {
+            Operand::EffectiveAddress { ref base, ref index, scale, displacement, address_size, .. } => {
                 let mut address = match *base {
                     Some(base) => self.get_register_value(base),
                     None => 0,
@@ -859,7 +998,33 @@ impl State {
                     Some(index) => self.get_register_value(index) * scale.unwrap() as i64,
                 };
                 address += displacement as i64;
-                address as u64
+                match address_size {
+                    OperandSize::Bit16 => address as u16 as u64,
+                    OperandSize::Bit32 => address as u32 as u64,
+                    _ => address as u64,
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn get_segment_base(&self, segment: Register) -> u64 {
+        match segment {
+            Register::FS | Register::GS => self.segment(segment).base,
+            _ if self.cs.long() => 0, // Flat ES, CS, SS, DS in 64bit mode
+            _ => self.segment(segment).base,
+        }
+    }
+
+    pub fn calculate_effective_address(&self, arg: &Operand) -> u64 {
+        match *arg {
+            Operand::EffectiveAddress { segment, base, .. } => {
+                // Default segment is SS for BP/SP based addressing, DS otherwise
+                let segment = segment.unwrap_or(match base {
+                    Some(Register::RBP) | Some(Register::RSP) | Some(Register::EBP) | Some(Register::ESP) | Some(Register::BP) | Some(Register::SP) => Register::SS,
+                    _ => Register::DS,
+                });
+                self.get_segment_base(segment).wrapping_add(self.calculate_offset(arg))
             }
             _ => unreachable!(),
         }
@@ -0,0 +1,83 @@
+use std::{borrow::Cow, fmt, rc::Rc};
+use object::{Object, ObjectSection, ObjectSymbol, SymbolKind};
+use gimli::{EndianRcSlice, RunTimeEndian};
+use crate::state::State;
+
+pub(crate) type Reader = EndianRcSlice<RunTimeEndian>;
+
+pub(crate) fn parse_elf(image: &[u8]) -> Option<object::File<'_>> { object::File::parse(image).ok().filter(|file| file.format() == object::BinaryFormat::Elf) }
+
+// Missing sections are empty
+pub(crate) fn section(file: &object::File, name: &str) -> Reader {
+	let endian = if file.is_little_endian() { RunTimeEndian::Little } else { RunTimeEndian::Big };
+	let data = file.section_by_name(name).and_then(|section| section.uncompressed_data().ok()).unwrap_or(Cow::Borrowed(&[]));
+	EndianRcSlice::new(Rc::from(&*data), endian)
+}
+
+// Guest address resolved to a function (symbol table) and a source line (DWARF)
+pub struct Location {
+	pub address: u64,
+	pub function: Option<(String, u64)>, // Name and offset
+	pub file: Option<String>,
+	pub line: Option<u32>,
+}
+
+impl fmt::Display for Location {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{:x}", self.address)?;
+		if let Some((function, offset)) = &self.function { write!(f, " {}+{:#x}", function, offset)?; }
+		if let Some(file) = &self.file {
+			write!(f, " {}", file)?;
+			if let Some(line) = self.line { write!(f, ":{}", line)?; }
+		}
+		Ok(())
+	}
+}
+
+pub struct Symbolizer {
+	functions: Vec<(u64, u64, String)>, // Sorted by address: start, size, name
+	lines: Option<addr2line::Context<Reader>>, // .debug_info and .debug_line
+}
+
+impl Symbolizer {
+	// None unless image is an ELF file. Stripped images resolve to addresses only
+	pub fn new(image: &[u8]) -> Option<Self> {
+		let file = parse_elf(image)?;
+		let mut functions = file.symbols().filter(|symbol| symbol.kind() == SymbolKind::Text && symbol.address() != 0)
+			.filter_map(|symbol| Some((symbol.address(), symbol.size(), symbol.name().ok()?.to_string()))).collect::<Vec<_>>();
+		functions.sort();
+		let dwarf = gimli::Dwarf::load(|id| -> Result<Reader, gimli::Error> { Ok(section(&file, id.name())) });
+		let lines = dwarf.and_then(addr2line::Context::from_dwarf).ok();
+		Some(Self{functions, lines})
+	}
+
+	pub fn symbol(&self, name: &str) -> Option<u64> { self.functions.iter().find(|(_, _, function)| function == name).map(|&(address, _, _)| address) }
+
+	// Assembly labels without a size extend to the next symbol
+	fn function(&self, address: u64) -> Option<(String, u64)> {
+		let index = self.functions.partition_point(|&(start, _, _)| start <= address).checked_sub(1)?;
+		let (start, size, name) = &self.functions[index];
+		if *size != 0 && address >= start + size { return None; }
+		Some((name.clone(), address - start))
+	}
+
+	pub fn find_location(&self, address: u64) -> Location {
+		let line = self.lines.as_ref().and_then(|lines| lines.find_location(address).ok().flatten());
+		Location{
+			address,
+			function: self.function(address),
+			file: line.as_ref().and_then(|line| line.file.map(str::to_string)),
+			line: line.and_then(|line| line.line),
+		}
+	}
+}
+
+impl State {
+	pub fn enable_symbols(&mut self, symbolizer: Symbolizer) -> Rc<Symbolizer> {
+		let symbolizer = Rc::new(symbolizer);
+		let find_location = symbolizer.clone();
+		self.find_location = Box::new(move |address| find_location.find_location(address).to_string());
+		self.symbols = Some(symbolizer.clone());
+		symbolizer
+	}
+}
@@ -0,0 +1,193 @@
+use std::{cell::RefCell, collections::VecDeque, io::Write, ops::Range, rc::Rc};
+use crate::{state::State, memory::{Access, MemoryAccess}, instruction::{Register, Operand, Operands, OperandSize, get_register_size}, gdb::hex};
+
+// Registers compared before and after each traced instruction
+const REGISTERS: [Register; 49] = [
+	Register::RAX, Register::RBX, Register::RCX, Register::RDX, Register::RSP, Register::RBP, Register::RSI, Register::RDI,
+	Register::R8, Register::R9, Register::R10, Register::R11, Register::R12, Register::R13, Register::R14, Register::R15,
+	Register::CR0, Register::CR2, Register::CR3, Register::CR4, Register::CR8,
+	Register::DR0, Register::DR1, Register::DR2, Register::DR3, Register::DR6, Register::DR7,
+	Register::ES, Register::CS, Register::SS, Register::DS, Register::FS, Register::GS,
+	Register::XMM0, Register::XMM1, Register::XMM2, Register::XMM3, Register::XMM4, Register::XMM5, Register::XMM6, Register::XMM7,
+	Register::XMM8, Register::XMM9, Register::XMM10, Register::XMM11, Register::XMM12, Register::XMM13, Register::XMM14, Register::XMM15,
+];
+
+fn name(register: Register) -> String { format!("{:?}", register).to_lowercase() }
+
+fn value(state: &State, register: Register) -> u128 {
+	match get_register_size(register) {
+		OperandSize::Bit128 => state.get_register_xmm(register),
+		size => state.get_register_value(register) as u64 as u128 & (!0u128 >> (128 - 8 * size.bytes())),
+	}
+}
+
+fn snapshot(state: &State) -> Vec<(String, u128)> {
+	let mut registers = REGISTERS.iter().map(|&register| (name(register), value(state, register))).collect::<Vec<_>>();
+	registers.push(("rflags".to_string(), state.rflags as u64 as u128));
+	registers
+}
+
+// Explicit register operands (destinations included) and address registers. Implicit operands (e.g. rcx of rep) are not listed
+fn reads(state: &State, operands: &Operands) -> Vec<(String, u128)> {
+	let mut reads: Vec<(String, u128)> = Vec::new();
+	for operand in operands.operands.iter().flatten() {
+		let registers = match *operand {
+			Operand::Register(register) => [Some(register), None],
+			Operand::EffectiveAddress{base, index, ..} => [base, index],
+			Operand::Immediate(_) => [None, None],
+		};
+		for register in registers.iter().flatten() {
+			let register = (name(*register), value(state, *register));
+			if !reads.contains(&register) { reads.push(register); }
+		}
+	}
+	reads
+}
+
+// One retired instruction
+#[derive(Debug, Clone)]
+pub struct TraceRecord {
+	pub index: u64, // Instructions retired before this one
+	pub rip: u64,
+	pub bytes: Vec<u8>,
+	pub disassembly: String,
+	pub reads: Vec<(String, u128)>, // Register values before the instruction
+	pub writes: Vec<(String, u128)>, // Registers changed by the instruction, with their new value
+	pub memory: Vec<MemoryAccess>,
+}
+
+impl TraceRecord {
+	pub fn to_json(&self) -> String {
+		let registers = |registers: &[(String, u128)]| registers.iter().map(|(name, value)| format!("\"{}\":\"{:#x}\"", name, value)).collect::<Vec<_>>().join(",");
+		let memory = self.memory.iter().map(|MemoryAccess{address, access, bytes}|
+			format!("{{\"address\":\"{:#x}\",\"access\":\"{}\",\"bytes\":\"{}\"}}", address, if *access == Access::Write { "write" } else { "read" }, hex(bytes))).collect::<Vec<_>>().join(",");
+		format!("{{\"index\":{},\"rip\":\"{:#x}\",\"bytes\":\"{}\",\"disassembly\":{:?},\"reads\":{{{}}},\"writes\":{{{}}},\"memory\":[{}]}}",
+			self.index, self.rip, hex(&self.bytes), self.disassembly, registers(&self.reads), registers(&self.writes), memory)
+	}
+
+	// Little endian: index, rip, u8 length and bytes, u16 length and disassembly,
+	// u8 count of reads then writes (u8 length and name, u128 value), u32 count of memory accesses (address, u8 access, u32 length and bytes)
+	pub fn to_binary(&self) -> Vec<u8> {
+		let mut binary = [self.index.to_le_bytes(), self.rip.to_le_bytes()].concat();
+		binary.push(self.bytes.len() as u8);
+		binary.extend(&self.bytes);
+		binary.extend(&(self.disassembly.len() as u16).to_le_bytes());
+		binary.extend(self.disassembly.as_bytes());
+		for registers in [&self.reads, &self.writes] {
+			binary.push(registers.len() as u8);
+			for (name, value) in registers {
+				binary.push(name.len() as u8);
+				binary.extend(name.as_bytes());
+				binary.extend(&value.to_le_bytes());
+			}
+		}
+		binary.extend(&(self.memory.len() as u32).to_le_bytes());
+		for MemoryAccess{address, access, bytes} in &self.memory {
+			binary.extend(&address.to_le_bytes());
+			binary.push(if *access == Access::Write { 1 } else { 0 });
+			binary.extend(&(bytes.len() as u32).to_le_bytes());
+			binary.extend(bytes);
+		}
+		binary
+	}
+}
+
+pub const TRACE_MAGIC: &[u8; 8] = b"x86trace";
+
+pub enum TraceSink {
+	Jsonl(Box<dyn Write>), // One JSON object per line
+	Binary(Box<dyn Write>), // TRACE_MAGIC then TraceRecord::to_binary records
+	Ring(VecDeque<TraceRecord>, usize), // Last records up to the capacity
+	Callback(Box<dyn FnMut(&TraceRecord)>),
+}
+
+pub struct Tracer {
+	sink: TraceSink,
+	pub addresses: Range<u64>, // Traced rip
+	pub instructions: Range<u64>, // Traced record indices
+	disassembly: Option<String>, // Of the instruction being traced, from the interpreter print helpers
+}
+
+impl Tracer {
+	pub fn new(mut sink: TraceSink) -> Self {
+		if let TraceSink::Binary(output) = &mut sink { output.write_all(TRACE_MAGIC).unwrap(); }
+		Self{sink, addresses: 0..!0, instructions: 0..!0, disassembly: None}
+	}
+	pub fn ring(capacity: usize) -> Self { Self::new(TraceSink::Ring(VecDeque::with_capacity(capacity), capacity)) }
+
+	// Ring buffer contents, oldest first
+	pub fn records(&self) -> impl Iterator<Item=&TraceRecord> {
+		match &self.sink { TraceSink::Ring(records, _) => Some(records.iter()), _ => None }.into_iter().flatten()
+	}
+
+	pub fn flush(&mut self) {
+		match &mut self.sink {
+			TraceSink::Jsonl(output) | TraceSink::Binary(output) => output.flush().unwrap(),
+			TraceSink::Ring(..) | TraceSink::Callback(_) => {}
+		}
+	}
+
+	fn record(&mut self, record: TraceRecord) {
+		match &mut self.sink {
+			TraceSink::Jsonl(output) => writeln!(output, "{}", record.to_json()).unwrap(),
+			TraceSink::Binary(output) => output.write_all(&record.to_binary()).unwrap(),
+			TraceSink::Ring(records, capacity) => {
+				if records.len() == *capacity { records.pop_front(); }
+				if *capacity > 0 { records.push_back(record); }
+			}
+			TraceSink::Callback(callback) => callback(&record),
+		}
+	}
+}
+
+// State of a traced instruction before dispatch
+pub(crate) struct Pending {
+	index: u64,
+	rip: u64,
+	bytes: Vec<u8>,
+	reads: Vec<(String, u128)>,
+	registers: Vec<(String, u128)>,
+}
+
+impl State {
+	pub fn enable_trace(&mut self, tracer: Tracer) -> Rc<RefCell<Tracer>> {
+		let tracer = Rc::new(RefCell::new(tracer));
+		self.memory.record_accesses(true);
+		self.tracer = Some(tracer.clone());
+		tracer
+	}
+
+	pub fn disable_trace(&mut self) {
+		self.memory.record_accesses(false);
+		self.tracer = None;
+	}
+
+	// Records the disassembly of the traced instruction (the first print of its handler)
+	pub(crate) fn trace_disassembly(&self, text: impl FnOnce() -> String) {
+		if let Some(tracer) = &self.tracer {
+			let mut tracer = tracer.borrow_mut();
+			if tracer.disassembly.as_deref() == Some("") { tracer.disassembly = Some(text()); }
+		}
+	}
+
+	// None unless the instruction at rip passes the filters
+	pub(crate) fn trace_begin(&mut self, rip: u64, length: usize, operands: &Operands) -> Option<Pending> {
+		let index = self.instructions - 1;
+		{
+			let mut tracer = self.tracer.as_ref()?.borrow_mut();
+			if !tracer.addresses.contains(&rip) || !tracer.instructions.contains(&index) { return None; }
+			tracer.disassembly = Some(String::new());
+		}
+		self.memory.take_accesses(); // Instruction fetch and interrupt delivery
+		Some(Pending{index, rip, bytes: self.memory.peek(rip, length).unwrap_or_default(), reads: reads(self, operands), registers: snapshot(self)})
+	}
+
+	pub(crate) fn trace_end(&mut self, Pending{index, rip, bytes, reads, registers}: Pending, fallback: impl FnOnce() -> String) {
+		let writes = snapshot(self).into_iter().zip(registers).filter(|(after, before)| after != before).map(|(after, _)| after).collect();
+		let memory = self.memory.take_accesses();
+		let tracer = self.tracer.clone().unwrap();
+		let mut tracer = tracer.borrow_mut();
+		let disassembly = tracer.disassembly.take().filter(|disassembly| !disassembly.is_empty()).unwrap_or_else(fallback);
+		tracer.record(TraceRecord{index, rip, bytes, disassembly, reads, writes, memory});
+	}
+}
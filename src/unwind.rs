@@ -0,0 +1,106 @@
+use std::{ops::Range, rc::Rc};
+use object::{Object, ObjectSection, SectionKind};
+use gimli::{BaseAddresses, CfaRule, DebugFrame, EhFrame, Register, RegisterRule, UnwindContext, UnwindSection, X86_64};
+use crate::{state::State, memory::{Memory, from_raw}, symbols::{Reader, parse_elf, section}};
+
+// General purpose registers in DWARF order followed by the return address
+type Registers = [u64; 17];
+const RBP: usize = X86_64::RBP.0 as usize;
+const RSP: usize = X86_64::RSP.0 as usize;
+const RA: usize = X86_64::RA.0 as usize;
+const MAX_FRAMES: usize = 256;
+
+fn read(memory: &Memory, address: u64) -> Option<u64> { memory.peek(address, 8).map(|bytes| from_raw(&bytes)) }
+
+// Call frame information of a guest image
+pub struct Unwinder {
+	eh_frame: EhFrame<Reader>,
+	debug_frame: DebugFrame<Reader>,
+	bases: BaseAddresses,
+	text: Vec<Range<u64>>, // Return addresses outside the code of the image end the backtrace (e.g. argc at process entry)
+}
+
+impl Unwinder {
+	pub fn new(image: &[u8]) -> Option<Self> {
+		let file = parse_elf(image)?;
+		let address = |name| file.section_by_name(name).map(|section| section.address()).unwrap_or(0);
+		let bases = BaseAddresses::default().set_eh_frame(address(".eh_frame")).set_text(address(".text"));
+		let (mut eh_frame, mut debug_frame) = (EhFrame::from(section(&file, ".eh_frame")), DebugFrame::from(section(&file, ".debug_frame")));
+		eh_frame.set_address_size(8);
+		debug_frame.set_address_size(8);
+		let text = file.sections().filter(|section| section.kind() == SectionKind::Text).map(|section| section.address()..section.address()+section.size()).collect();
+		Some(Self{eh_frame, debug_frame, bases, text})
+	}
+
+	fn is_code(&self, address: u64) -> bool { self.text.iter().any(|range| range.contains(&address)) }
+
+	// Caller registers from the CFI row of address, None without CFI (or with DWARF expressions)
+	fn caller(&self, memory: &Memory, registers: &Registers, address: u64) -> Option<Registers> {
+		let mut context = UnwindContext::new();
+		let row = self.eh_frame.unwind_info_for_address(&self.bases, &mut context, address, EhFrame::cie_from_offset).ok().cloned()
+			.or_else(|| self.debug_frame.unwind_info_for_address(&self.bases, &mut context, address, DebugFrame::cie_from_offset).ok().cloned())?;
+		let cfa = match row.cfa() {
+			CfaRule::RegisterAndOffset{register, offset} => registers.get(register.0 as usize)?.wrapping_add(*offset as u64),
+			CfaRule::Expression(_) => return None,
+		};
+		let mut caller = *registers;
+		for (register, value) in caller.iter_mut().enumerate() {
+			*value = match row.register(Register(register as u16)) {
+				RegisterRule::Undefined if register == RA => 0, // Outermost frame
+				RegisterRule::Undefined | RegisterRule::SameValue => registers[register],
+				RegisterRule::Offset(offset) => read(memory, cfa.wrapping_add(offset as u64))?,
+				RegisterRule::ValOffset(offset) => cfa.wrapping_add(offset as u64),
+				RegisterRule::Register(other) => *registers.get(other.0 as usize)?,
+				RegisterRule::Constant(constant) => constant,
+				_ => return None,
+			};
+		}
+		caller[RSP] = cfa;
+		Some(caller)
+	}
+}
+
+// rbp chain: [rbp] is the caller rbp and [rbp+8] the return address
+fn frame_pointer_caller(memory: &Memory, registers: &Registers) -> Option<Registers> {
+	let rbp = registers[RBP];
+	if rbp < registers[RSP] { return None; }
+	let mut caller = *registers;
+	caller[RBP] = read(memory, rbp)?;
+	caller[RA] = read(memory, rbp + 8)?;
+	caller[RSP] = rbp + 16;
+	Some(caller)
+}
+
+impl State {
+	pub fn enable_unwind(&mut self, unwinder: Unwinder) -> Rc<Unwinder> {
+		let unwinder = Rc::new(unwinder);
+		self.unwinder = Some(unwinder.clone());
+		unwinder
+	}
+
+	// Guest call stack from rip and the current registers, innermost first
+	pub fn backtrace(&self, rip: u64) -> Vec<u64> {
+		let mut registers: Registers = [self.rax, self.rdx, self.rcx, self.rbx, self.rsi, self.rdi, self.rbp, self.rsp,
+			self.r8, self.r9, self.r10, self.r11, self.r12, self.r13, self.r14, self.r15, rip as i64].map(|register| register as u64);
+		let mut frames = vec![rip];
+		while frames.len() < MAX_FRAMES {
+			// Return addresses point after the call, which may be the start of the next function
+			let address = if frames.len() == 1 { registers[RA] } else { registers[RA] - 1 };
+			let caller = self.unwinder.as_ref().and_then(|unwinder| unwinder.caller(&self.memory, &registers, address))
+				.or_else(|| frame_pointer_caller(&self.memory, &registers));
+			match caller {
+				// The stack grows down: each caller frame is above its callee
+				Some(caller) if caller[RSP] > registers[RSP] && self.unwinder.as_ref().map_or(caller[RA] != 0 && caller[RA] != !0, |unwinder| unwinder.is_code(caller[RA])) => {
+					frames.push(caller[RA]);
+					registers = caller;
+				}
+				_ => break,
+			}
+		}
+		frames
+	}
+
+	pub fn format_backtrace(&self, rip: u64) -> String {
+		self.backtrace(rip).iter().enumerate().map(|(index, &address)| format!("#{} {}\n", index, (self.find_location)(address))).collect()
+	}
+}
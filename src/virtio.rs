@@ -0,0 +1,260 @@
+use crate::{memory::Memory, pci::{PciConfig, PciDevice, BarKind}};
+
+pub const VIRTIO_VENDOR: u16 = 0x1AF4;
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+const QUEUE_SIZE: u16 = 256;
+
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_DEVICE_NEEDS_RESET: u8 = 0x40;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+// BAR 0 layout
+const COMMON_CFG: u64 = 0x0000;
+const ISR_CFG: u64 = 0x1000;
+const DEVICE_CFG: u64 = 0x2000;
+const NOTIFY_CFG: u64 = 0x3000;
+const NOTIFY_OFF_MULTIPLIER: u32 = 4;
+
+// Descriptor chain of a request: (guest physical address, length, device writable)
+pub struct Chain {
+	pub head: u16,
+	pub buffers: Vec<(u64, u32, bool)>,
+}
+
+impl Chain {
+	// Device readable part
+	pub fn read(&self, memory: &Memory) -> Vec<u8> {
+		self.buffers.iter().filter(|&&(_, _, write)| !write).flat_map(|&(address, length, _)| memory.read_bytes(address, length as usize)).collect()
+	}
+
+	pub fn writable_length(&self) -> usize {
+		self.buffers.iter().filter(|&&(_, _, write)| write).map(|&(_, length, _)| length as usize).sum()
+	}
+
+	// Fills the device writable part in order. Returns the number of bytes written
+	pub fn write(&self, memory: &mut Memory, mut data: &[u8]) -> usize {
+		let mut written = 0;
+		for &(address, length, _) in self.buffers.iter().filter(|&&(_, _, write)| write) {
+			let size = data.len().min(length as usize);
+			memory.write_unaligned_bytes(address, &data[..size]);
+			data = &data[size..];
+			written += size;
+		}
+		written
+	}
+}
+
+// Split virtqueue
+#[derive(Default)]
+pub struct Virtqueue {
+	pub size: u16,
+	pub ready: bool,
+	pub desc: u64,
+	pub driver: u64, // Available ring
+	pub device: u64, // Used ring
+	last_available: u16,
+	used: u16,
+	broken: bool, // An invalid descriptor chain stops the queue until the driver resets the device
+}
+
+impl Virtqueue {
+	// Next available descriptor chain
+	pub fn pop(&mut self, memory: &Memory) -> Option<Chain> {
+		if !self.ready || self.size == 0 || self.broken { return None; }
+		let available: u16 = memory.read_unaligned(self.driver + 2);
+		if available == self.last_available { return None; }
+		let head: u16 = memory.read_unaligned(self.driver + 4 + 2 * (self.last_available % self.size) as u64);
+		self.last_available = self.last_available.wrapping_add(1);
+		let mut buffers = Vec::new();
+		let mut index = head;
+		loop {
+			if index >= self.size || buffers.len() >= self.size as usize { self.broken = true; return None; } // Out of the table or a loop
+			let descriptor = self.desc + 16 * index as u64;
+			let (address, length): (u64, u32) = (memory.read_unaligned(descriptor), memory.read_unaligned(descriptor + 8));
+			let (flags, next): (u16, u16) = (memory.read_unaligned(descriptor + 12), memory.read_unaligned(descriptor + 14));
+			buffers.push((address, length, flags & DESC_F_WRITE != 0));
+			if flags & DESC_F_NEXT == 0 { break; }
+			index = next;
+		}
+		Some(Chain{head, buffers})
+	}
+
+	// Returns a chain to the driver with the number of bytes written
+	pub fn push(&mut self, memory: &mut Memory, chain: &Chain, written: usize) {
+		let element = self.device + 4 + 8 * (self.used % self.size) as u64;
+		memory.write_unaligned(element, &(chain.head as u32));
+		memory.write_unaligned(element + 4, &(written as u32));
+		self.used = self.used.wrapping_add(1);
+		memory.write_unaligned(self.device + 2, &self.used);
+	}
+}
+
+pub trait VirtioDevice {
+	fn device_type(&self) -> u16;
+	fn features(&self) -> u64;
+	fn queue_count(&self) -> usize;
+	fn read_config(&mut self, offset: u64, size: usize) -> u64;
+	fn write_config(&mut self, _offset: u64, _size: usize, _value: u64) {}
+	// Processes available buffers after a notification. Returns whether any were used
+	fn process(&mut self, queue_index: usize, queue: &mut Virtqueue, memory: &mut Memory) -> bool;
+	// Between instructions: input from the host side
+	fn poll(&mut self, _queues: &mut [Virtqueue], _memory: &mut Memory) -> bool { false }
+}
+
+// Virtio 1.0 PCI transport with INTx interrupts (no MSI-X)
+pub struct VirtioPci<D: VirtioDevice> {
+	config: PciConfig,
+	pub device: D,
+	device_feature_select: u32,
+	driver_feature_select: u32,
+	driver_features: u64,
+	status: u8,
+	queue_select: u16,
+	queues: Vec<Virtqueue>,
+	notified: u64, // Queue bitmask
+	isr: u8,
+}
+
+impl<D: VirtioDevice> VirtioPci<D> {
+	pub fn new(device: D) -> Self {
+		let device_type = device.device_type();
+		let class = match device_type { 1 => 0x02_00_00, 2 => 0x01_80_00, _ => 0xFF_00_00 };
+		let mut config = PciConfig::new(VIRTIO_VENDOR, 0x1040 + device_type, class, 1);
+		config.set_subsystem(VIRTIO_VENDOR, 0x40);
+		config.add_bar(0, 0x4000, BarKind::Memory32);
+		let capability = |cfg_type: u8, offset: u64, length: u32| {
+			let mut data = vec![16, cfg_type, 0, 0, 0, 0];
+			data.extend_from_slice(&(offset as u32).to_le_bytes());
+			data.extend_from_slice(&length.to_le_bytes());
+			data
+		};
+		config.add_capability(0x09, &capability(1, COMMON_CFG, 0x38));
+		let mut notify = capability(2, NOTIFY_CFG, 0x1000);
+		notify[0] = 20;
+		notify.extend_from_slice(&NOTIFY_OFF_MULTIPLIER.to_le_bytes());
+		config.add_capability(0x09, &notify);
+		config.add_capability(0x09, &capability(3, ISR_CFG, 1));
+		config.add_capability(0x09, &capability(4, DEVICE_CFG, 0x1000));
+		config.set_interrupt_pin(1);
+		let queues = (0..device.queue_count()).map(|_| Virtqueue{size: QUEUE_SIZE, ..Default::default()}).collect();
+		Self{config, device, device_feature_select: 0, driver_feature_select: 0, driver_features: 0, status: 0, queue_select: 0, queues, notified: 0, isr: 0}
+	}
+
+	fn device_features(&self) -> u64 { self.device.features() | VIRTIO_F_VERSION_1 }
+
+	pub fn driver_features(&self) -> u64 { self.driver_features }
+
+	fn reset(&mut self) {
+		for queue in self.queues.iter_mut() { *queue = Virtqueue{size: QUEUE_SIZE, ..Default::default()}; }
+		(self.driver_features, self.status, self.notified, self.isr) = (0, 0, 0, 0);
+		self.config.set_interrupt(false);
+	}
+
+	fn read_common(&self, offset: u64) -> u64 {
+		let queue = self.queues.get(self.queue_select as usize);
+		match offset {
+			0x00 => self.device_feature_select as u64,
+			0x04 => if self.device_feature_select < 2 { (self.device_features() >> (32 * self.device_feature_select)) & 0xFFFF_FFFF } else { 0 },
+			0x08 => self.driver_feature_select as u64,
+			0x0C => if self.driver_feature_select < 2 { (self.driver_features >> (32 * self.driver_feature_select)) & 0xFFFF_FFFF } else { 0 },
+			0x10 | 0x1A => 0xFFFF, // MSI-X: no vector
+			0x12 => self.queues.len() as u64,
+			0x14 => self.status as u64,
+			0x15 => 0, // Configuration generation
+			0x16 => self.queue_select as u64,
+			0x18 => queue.map_or(0, |queue| queue.size as u64),
+			0x1C => queue.map_or(0, |queue| queue.ready as u64),
+			0x1E => self.queue_select as u64, // Notify offset
+			0x20 => queue.map_or(0, |queue| queue.desc),
+			0x28 => queue.map_or(0, |queue| queue.driver),
+			0x30 => queue.map_or(0, |queue| queue.device),
+			_ => 0,
+		}
+	}
+
+	fn write_common(&mut self, offset: u64, size: usize, value: u64) {
+		let queue = self.queues.get_mut(self.queue_select as usize);
+		match offset {
+			0x00 => self.device_feature_select = value as u32,
+			0x08 => self.driver_feature_select = value as u32,
+			0x0C => if self.driver_feature_select < 2 {
+				let shift = 32 * self.driver_feature_select;
+				let value = (value & 0xFFFF_FFFF) << shift & self.device_features();
+				self.driver_features = (self.driver_features & !(0xFFFF_FFFF << shift)) | value;
+			},
+			0x14 => if value == 0 { self.reset() } else { self.status = value as u8 },
+			0x16 => self.queue_select = value as u16,
+			0x18 => if let Some(queue) = queue { queue.size = (value as u16).min(QUEUE_SIZE) },
+			0x1C => if let Some(queue) = queue { queue.ready = value & 1 != 0 },
+			0x20..=0x37 => if let Some(queue) = queue {
+				let field = match (offset - 0x20) / 8 { 0 => &mut queue.desc, 1 => &mut queue.driver, _ => &mut queue.device };
+				let (shift, mask) = (8 * (offset % 8), !0u64 >> (64 - 8*size));
+				*field = (*field & !(mask << shift)) | (value & mask) << shift;
+			},
+			_ => {}
+		}
+	}
+}
+
+// 64bit registers are accessed as two 32bit halves
+fn field_offset(offset: u64) -> (u64, u64) {
+	match offset {
+		0x20..=0x37 => (offset & !7, offset % 8),
+		_ => (offset, 0),
+	}
+}
+
+impl<D: VirtioDevice> PciDevice for VirtioPci<D> {
+	fn config(&mut self) -> &mut PciConfig { &mut self.config }
+
+	fn read_bar(&mut self, _bar: usize, offset: u64, size: usize) -> u64 {
+		let mask = !0u64 >> (64 - 8*size);
+		match offset & !0xFFF {
+			COMMON_CFG => {
+				let (field, shift) = field_offset(offset);
+				(self.read_common(field) >> (8 * shift)) & mask
+			}
+			ISR_CFG => {
+				let isr = self.isr;
+				self.isr = 0;
+				self.config.set_interrupt(false);
+				isr as u64
+			}
+			DEVICE_CFG => self.device.read_config(offset - DEVICE_CFG, size) & mask,
+			_ => 0,
+		}
+	}
+
+	fn write_bar(&mut self, _bar: usize, offset: u64, size: usize, value: u64) {
+		match offset & !0xFFF {
+			COMMON_CFG => self.write_common(offset, size, value),
+			DEVICE_CFG => self.device.write_config(offset - DEVICE_CFG, size, value),
+			NOTIFY_CFG => {
+				let queue = (offset - NOTIFY_CFG) / NOTIFY_OFF_MULTIPLIER as u64;
+				if (queue as usize) < self.queues.len() { self.notified |= 1 << queue; }
+			}
+			_ => {}
+		}
+	}
+
+	fn update(&mut self, memory: &mut Memory) {
+		if self.status & STATUS_DRIVER_OK == 0 || self.status & STATUS_DEVICE_NEEDS_RESET != 0 || !self.config.bus_master() { return; }
+		let mut used = self.device.poll(&mut self.queues, memory);
+		for (index, queue) in self.queues.iter_mut().enumerate() {
+			if self.notified & (1 << index) != 0 { used |= self.device.process(index, queue, memory); }
+		}
+		self.notified = 0;
+		if used {
+			self.isr |= 1;
+			self.config.set_interrupt(true);
+		}
+		// Reported through the configuration change interrupt
+		if self.queues.iter().any(|queue| queue.broken) {
+			self.status |= STATUS_DEVICE_NEEDS_RESET;
+			self.isr |= 2;
+			self.config.set_interrupt(true);
+		}
+	}
+}
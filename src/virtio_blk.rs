@@ -0,0 +1,106 @@
+use std::io::{Read, Write, Seek, SeekFrom};
+use crate::{memory::Memory, virtio::{VirtioDevice, Virtqueue}};
+
+pub const SECTOR_SIZE: u64 = 512;
+
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+// Backing store: a host file or an in-memory image (std::io::Cursor<Vec<u8>>)
+pub trait Storage: Read + Write + Seek {}
+impl<T: Read + Write + Seek> Storage for T {}
+
+pub struct VirtioBlock {
+	storage: Box<dyn Storage>,
+	capacity: u64, // Sectors
+	read_only: bool,
+	id: [u8; 20],
+}
+
+impl VirtioBlock {
+	pub fn new(mut storage: Box<dyn Storage>, read_only: bool) -> Self {
+		let capacity = storage.seek(SeekFrom::End(0)).unwrap() / SECTOR_SIZE;
+		let mut id = [0; 20];
+		id[..11].copy_from_slice(b"x86emu-disk");
+		Self{storage, capacity, read_only, id}
+	}
+
+	pub fn open(path: impl AsRef<std::path::Path>, read_only: bool) -> std::io::Result<Self> {
+		let file = std::fs::OpenOptions::new().read(true).write(!read_only).open(path)?;
+		Ok(Self::new(Box::new(file), read_only))
+	}
+
+	pub fn from_vec(image: Vec<u8>, read_only: bool) -> Self { Self::new(Box::new(std::io::Cursor::new(image)), read_only) }
+
+	pub fn set_id(&mut self, id: &[u8]) {
+		self.id = [0; 20];
+		self.id[..id.len().min(20)].copy_from_slice(&id[..id.len().min(20)]);
+	}
+
+	fn in_range(&self, sector: u64, length: usize) -> bool {
+		sector.checked_mul(SECTOR_SIZE).and_then(|start| start.checked_add(length as u64)).map_or(false, |end| end <= self.capacity * SECTOR_SIZE)
+	}
+
+	// Returns the data for the driver and the status
+	fn request(&mut self, kind: u32, sector: u64, data: &[u8], length: usize) -> (Vec<u8>, u8) {
+		match kind {
+			VIRTIO_BLK_T_IN => {
+				if !self.in_range(sector, length) { return (Vec::new(), VIRTIO_BLK_S_IOERR); }
+				let mut buffer = vec![0; length];
+				match self.storage.seek(SeekFrom::Start(sector * SECTOR_SIZE)).and_then(|_| self.storage.read_exact(&mut buffer)) {
+					Ok(()) => (buffer, VIRTIO_BLK_S_OK),
+					Err(_) => (Vec::new(), VIRTIO_BLK_S_IOERR),
+				}
+			}
+			VIRTIO_BLK_T_OUT => {
+				if self.read_only || !self.in_range(sector, data.len()) { return (Vec::new(), VIRTIO_BLK_S_IOERR); }
+				match self.storage.seek(SeekFrom::Start(sector * SECTOR_SIZE)).and_then(|_| self.storage.write_all(data)) {
+					Ok(()) => (Vec::new(), VIRTIO_BLK_S_OK),
+					Err(_) => (Vec::new(), VIRTIO_BLK_S_IOERR),
+				}
+			}
+			VIRTIO_BLK_T_FLUSH => (Vec::new(), if self.storage.flush().is_ok() { VIRTIO_BLK_S_OK } else { VIRTIO_BLK_S_IOERR }),
+			VIRTIO_BLK_T_GET_ID => (self.id[..length.min(20)].to_vec(), VIRTIO_BLK_S_OK),
+			_ => (Vec::new(), VIRTIO_BLK_S_UNSUPP),
+		}
+	}
+}
+
+impl VirtioDevice for VirtioBlock {
+	fn device_type(&self) -> u16 { 2 }
+	fn features(&self) -> u64 { VIRTIO_BLK_F_FLUSH | if self.read_only { VIRTIO_BLK_F_RO } else { 0 } }
+	fn queue_count(&self) -> usize { 1 }
+
+	// capacity is the only configuration field
+	fn read_config(&mut self, offset: u64, _size: usize) -> u64 {
+		if offset < 8 { self.capacity >> (8 * offset) } else { 0 }
+	}
+
+	// Requests: header (type, reserved, sector), data, status
+	fn process(&mut self, _queue_index: usize, queue: &mut Virtqueue, memory: &mut Memory) -> bool {
+		let mut used = false;
+		while let Some(chain) = queue.pop(memory) {
+			let readable = chain.read(memory);
+			let (kind, sector) = if readable.len() >= 16 {
+				(u32::from_le_bytes([readable[0], readable[1], readable[2], readable[3]]), u64::from_le_bytes([readable[8], readable[9], readable[10], readable[11], readable[12], readable[13], readable[14], readable[15]]))
+			} else { (!0, 0) };
+			let length = chain.writable_length().saturating_sub(1); // Last writable byte is the status
+			let (mut response, status) = self.request(kind, sector, readable.get(16..).unwrap_or(&[]), length);
+			response.resize(length, 0);
+			response.push(status);
+			let written = chain.write(memory, &response);
+			queue.push(memory, &chain, written);
+			used = true;
+		}
+		used
+	}
+}
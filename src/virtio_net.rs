@@ -0,0 +1,161 @@
+use std::{io::{self, Read, Write}, collections::VecDeque, sync::mpsc::{channel, Sender, Receiver}};
+use crate::{memory::Memory, virtio::{VirtioDevice, Virtqueue}};
+
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+const HEADER_SIZE: usize = 12; // virtio_net_hdr with num_buffers
+
+const RECEIVE_QUEUE: usize = 0;
+const TRANSMIT_QUEUE: usize = 1;
+
+// Host side of the link. Frames are Ethernet frames without the virtio header
+pub trait NetBackend {
+	fn send(&mut self, frame: &[u8]);
+	fn receive(&mut self) -> Option<Vec<u8>>;
+}
+
+// One end of an in-process link
+pub struct Loopback {
+	sender: Sender<Vec<u8>>,
+	receiver: Receiver<Vec<u8>>,
+}
+
+// Frames sent on one end are received on the other. Either end may be held by a test harness or another thread
+pub fn loopback_pair() -> (Loopback, Loopback) {
+	let ((a_sender, a_receiver), (b_sender, b_receiver)) = (channel(), channel());
+	(Loopback{sender: a_sender, receiver: b_receiver}, Loopback{sender: b_sender, receiver: a_receiver})
+}
+
+impl NetBackend for Loopback {
+	fn send(&mut self, frame: &[u8]) { let _ = self.sender.send(frame.to_vec()); } // Dropped if the other end is gone
+	fn receive(&mut self) -> Option<Vec<u8>> { self.receiver.try_recv().ok() }
+}
+
+const PCAP_MAGIC: u32 = 0xA1B2_C3D4;
+const LINKTYPE_ETHERNET: u32 = 1;
+
+// Transmitted frames are written to a pcap capture, received frames are replayed from another capture
+// Timestamps are zero so that captures are reproducible
+pub struct Pcap {
+	output: Option<Box<dyn Write>>,
+	input: VecDeque<Vec<u8>>,
+}
+
+impl Pcap {
+	pub fn new(mut output: Option<Box<dyn Write>>, input: Option<Box<dyn Read>>) -> io::Result<Self> {
+		if let Some(output) = &mut output {
+			let header: Vec<u8> = [PCAP_MAGIC.to_le_bytes(), (2u16 as u32 | 4 << 16).to_le_bytes(), 0u32.to_le_bytes(), 0u32.to_le_bytes(), 65535u32.to_le_bytes(), LINKTYPE_ETHERNET.to_le_bytes()].concat();
+			output.write_all(&header)?;
+		}
+		let input = match input {
+			Some(mut input) => {
+				let mut capture = Vec::new();
+				input.read_to_end(&mut capture)?;
+				Self::parse(&capture)?
+			}
+			None => VecDeque::new(),
+		};
+		Ok(Self{output, input})
+	}
+
+	fn parse(capture: &[u8]) -> io::Result<VecDeque<Vec<u8>>> {
+		let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, format!("pcap: {}", message));
+		let u32_at = |offset: usize, big_endian: bool| {
+			let bytes = [capture[offset], capture[offset+1], capture[offset+2], capture[offset+3]];
+			if big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) }
+		};
+		if capture.len() < 24 { return Err(invalid("truncated header".into())); }
+		let big_endian = match u32_at(0, false) {
+			0xA1B2_C3D4 | 0xA1B2_3C4D => false,
+			0xD4C3_B2A1 | 0x4D3C_B2A1 => true,
+			magic => return Err(invalid(format!("bad magic {:x}", magic))),
+		};
+		if u32_at(20, big_endian) != LINKTYPE_ETHERNET { return Err(invalid("only Ethernet captures are supported".into())); }
+		let mut frames = VecDeque::new();
+		let mut offset = 24;
+		while offset + 16 <= capture.len() {
+			let length = u32_at(offset + 8, big_endian) as usize; // Captured length
+			let start = offset + 16;
+			if length > capture.len() - start { return Err(invalid("truncated record".into())); }
+			frames.push_back(capture[start..start+length].to_vec());
+			offset = start + length;
+		}
+		Ok(frames)
+	}
+}
+
+impl NetBackend for Pcap {
+	fn send(&mut self, frame: &[u8]) {
+		if let Some(output) = &mut self.output {
+			let length = (frame.len() as u32).to_le_bytes();
+			output.write_all(&[[0; 4], [0; 4], length, length].concat()).unwrap();
+			output.write_all(frame).unwrap();
+			output.flush().unwrap();
+		}
+	}
+	fn receive(&mut self) -> Option<Vec<u8>> { self.input.pop_front() }
+}
+
+pub struct VirtioNet {
+	backend: Box<dyn NetBackend>,
+	mac: [u8; 6],
+	pending: Option<Vec<u8>>, // Received frame waiting for a receive buffer
+}
+
+impl VirtioNet {
+	pub fn new(backend: Box<dyn NetBackend>, mac: [u8; 6]) -> Self { Self{backend, mac, pending: None} }
+
+	fn receive(&mut self, queue: &mut Virtqueue, memory: &mut Memory) -> bool {
+		let mut used = false;
+		loop {
+			let frame = match self.pending.take().or_else(|| self.backend.receive()) { Some(frame) => frame, None => break };
+			let chain = match queue.pop(memory) {
+				Some(chain) => chain,
+				None => { self.pending = Some(frame); break; }
+			};
+			let mut packet = vec![0; HEADER_SIZE];
+			packet[10] = 1; // num_buffers
+			packet.extend_from_slice(&frame);
+			let written = chain.write(memory, &packet);
+			queue.push(memory, &chain, written);
+			used = true;
+		}
+		used
+	}
+
+	fn transmit(&mut self, queue: &mut Virtqueue, memory: &mut Memory) -> bool {
+		let mut used = false;
+		while let Some(chain) = queue.pop(memory) {
+			let packet = chain.read(memory);
+			if packet.len() > HEADER_SIZE { self.backend.send(&packet[HEADER_SIZE..]); }
+			queue.push(memory, &chain, 0);
+			used = true;
+		}
+		used
+	}
+}
+
+impl VirtioDevice for VirtioNet {
+	fn device_type(&self) -> u16 { 1 }
+	fn features(&self) -> u64 { VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS }
+	fn queue_count(&self) -> usize { 2 }
+
+	// mac, status
+	fn read_config(&mut self, offset: u64, size: usize) -> u64 {
+		let mut config = [0; 8];
+		config[..6].copy_from_slice(&self.mac);
+		config[6..8].copy_from_slice(&VIRTIO_NET_S_LINK_UP.to_le_bytes());
+		(offset as usize..(offset as usize + size).min(8)).rev().fold(0, |value, index| value << 8 | config[index] as u64)
+	}
+
+	fn process(&mut self, queue_index: usize, queue: &mut Virtqueue, memory: &mut Memory) -> bool {
+		match queue_index {
+			RECEIVE_QUEUE => self.receive(queue, memory),
+			TRANSMIT_QUEUE => self.transmit(queue, memory),
+			_ => false,
+		}
+	}
+
+	fn poll(&mut self, queues: &mut [Virtqueue], memory: &mut Memory) -> bool { self.receive(&mut queues[RECEIVE_QUEUE], memory) }
+}